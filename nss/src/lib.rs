@@ -0,0 +1,406 @@
+//! glibc NSS module, so `getaddrinfo ("laptop")` finds LookAround peers in
+//! every program, not just ours. Install it as `libnss_lookaround.so.2`
+//! and add `lookaround` to the `hosts:` line of `/etc/nsswitch.conf`, e.g.
+//! `hosts: files lookaround dns`.
+//!
+//! The lookups themselves are `lookaround::nss::NssResolver`. Everything
+//! here is just copying its answers into glibc's structs.
+
+use std::{
+	ffi::CStr,
+	mem::{
+		align_of,
+		size_of,
+	},
+	net::IpAddr,
+	os::raw::{
+		c_char,
+		c_int,
+	},
+	panic::catch_unwind,
+	ptr,
+	sync::OnceLock,
+	time::Duration,
+};
+
+use lookaround::nss::NssResolver;
+
+// `enum nss_status` from <nss.h>
+
+const NSS_STATUS_TRYAGAIN: c_int = -2;
+const NSS_STATUS_UNAVAIL: c_int = -1;
+const NSS_STATUS_NOTFOUND: c_int = 0;
+const NSS_STATUS_SUCCESS: c_int = 1;
+
+// `h_errno` values from <netdb.h>
+
+const NETDB_INTERNAL: c_int = -1;
+const HOST_NOT_FOUND: c_int = 1;
+const NO_RECOVERY: c_int = 3;
+const NO_DATA: c_int = 4;
+
+/// `struct gaih_addrtuple` from <nss.h>, one address in the linked list
+/// that `getaddrinfo` asks for
+#[repr (C)]
+pub struct GaihAddrtuple {
+	next: *mut GaihAddrtuple,
+	name: *mut c_char,
+	family: c_int,
+	addr: [u32; 4],
+	scopeid: u32,
+}
+
+// Why a lookup didn't succeed, in the three ways NSS wants to hear it
+
+#[derive (Debug, PartialEq)]
+struct Failure {
+	status: c_int,
+	errno: c_int,
+	h_errno: c_int,
+}
+
+impl Failure {
+	fn not_found () -> Self {
+		Self {
+			status: NSS_STATUS_NOTFOUND,
+			errno: libc::ENOENT,
+			h_errno: HOST_NOT_FOUND,
+		}
+	}
+	
+	fn unavailable () -> Self {
+		Self {
+			status: NSS_STATUS_UNAVAIL,
+			errno: libc::ENOENT,
+			h_errno: NO_RECOVERY,
+		}
+	}
+	
+	// glibc retries with a bigger buffer when it sees this
+	
+	fn buffer_too_small () -> Self {
+		Self {
+			status: NSS_STATUS_TRYAGAIN,
+			errno: libc::ERANGE,
+			h_errno: NETDB_INTERNAL,
+		}
+	}
+}
+
+// One resolver per process, so the cache lasts as long as the program does
+
+fn resolver () -> &'static NssResolver {
+	static RESOLVER: OnceLock <NssResolver> = OnceLock::new ();
+	RESOLVER.get_or_init (NssResolver::from_config)
+}
+
+// Nothing may unwind into C, so panics count as "unavailable", and glibc
+// moves on to the next source in nsswitch.conf
+
+unsafe fn lookup (name: *const c_char) -> Result <(Vec <IpAddr>, Duration), Failure> {
+	let name = CStr::from_ptr (name).to_str ().map_err (|_| Failure::not_found ())?;
+	
+	let (addrs, ttl) = match catch_unwind (|| resolver ().lookup (name)) {
+		Ok (Ok (x)) => x,
+		_ => return Err (Failure::unavailable ()),
+	};
+	
+	if addrs.is_empty () {
+		return Err (Failure::not_found ());
+	}
+	
+	Ok ((addrs, ttl))
+}
+
+unsafe fn finish (result: Result <(), Failure>, errnop: *mut c_int, h_errnop: *mut c_int) -> c_int {
+	match result {
+		Ok (()) => NSS_STATUS_SUCCESS,
+		Err (e) => {
+			*errnop = e.errno;
+			*h_errnop = e.h_errno;
+			e.status
+		},
+	}
+}
+
+// Hands out aligned pieces of the caller's buffer, since everything we
+// return has to live in it
+
+struct Buffer {
+	ptr: *mut u8,
+	left: usize,
+}
+
+impl Buffer {
+	fn new (ptr: *mut c_char, len: usize) -> Self {
+		Self {
+			ptr: ptr as *mut u8,
+			left: len,
+		}
+	}
+	
+	unsafe fn alloc <T> (&mut self, count: usize) -> Result <*mut T, Failure> {
+		let pad = self.ptr.align_offset (align_of::<T> ());
+		let size = pad + size_of::<T> () * count;
+		if size > self.left {
+			return Err (Failure::buffer_too_small ());
+		}
+		
+		let p = self.ptr.add (pad) as *mut T;
+		self.ptr = self.ptr.add (size);
+		self.left -= size;
+		Ok (p)
+	}
+	
+	unsafe fn copy_str (&mut self, s: &CStr) -> Result <*mut c_char, Failure> {
+		let bytes = s.to_bytes_with_nul ();
+		let p = self.alloc::<u8> (bytes.len ())?;
+		ptr::copy_nonoverlapping (bytes.as_ptr (), p, bytes.len ());
+		Ok (p as *mut c_char)
+	}
+}
+
+fn family (addr: &IpAddr) -> c_int {
+	match addr {
+		IpAddr::V4 (_) => libc::AF_INET,
+		IpAddr::V6 (_) => libc::AF_INET6,
+	}
+}
+
+fn octets (addr: &IpAddr) -> Vec <u8> {
+	match addr {
+		IpAddr::V4 (x) => x.octets ().to_vec (),
+		IpAddr::V6 (x) => x.octets ().to_vec (),
+	}
+}
+
+// Builds the linked list for `gethostbyname4_r`, in the order we got
+// the addresses
+
+unsafe fn write_addrtuples (name: &CStr, addrs: &[IpAddr], buf: &mut Buffer)
+-> Result <*mut GaihAddrtuple, Failure>
+{
+	let name = buf.copy_str (name)?;
+	let mut next = ptr::null_mut ();
+	
+	for addr in addrs.iter ().rev () {
+		let mut words = [0u8; 16];
+		let octets = octets (addr);
+		words [..octets.len ()].copy_from_slice (&octets);
+		
+		let tuple = buf.alloc::<GaihAddrtuple> (1)?;
+		tuple.write (GaihAddrtuple {
+			next,
+			name,
+			family: family (addr),
+			addr: [0, 4, 8, 12].map (|i| u32::from_ne_bytes ([words [i], words [i + 1], words [i + 2], words [i + 3]])),
+			scopeid: 0,
+		});
+		next = tuple;
+	}
+	
+	Ok (next)
+}
+
+// Fills in `result` for the older `gethostbyname*_r` calls, which only
+// take one address family at a time
+
+unsafe fn write_hostent (
+	name: &CStr,
+	af: c_int,
+	addrs: &[IpAddr],
+	result: *mut libc::hostent,
+	buf: &mut Buffer,
+) -> Result <(), Failure>
+{
+	let addrs: Vec <_> = addrs.iter ().filter (|x| family (x) == af).map (octets).collect ();
+	if addrs.is_empty () {
+		return Err (Failure {
+			h_errno: NO_DATA,
+			..Failure::not_found ()
+		});
+	}
+	let addr_len = addrs [0].len ();
+	
+	let h_name = buf.copy_str (name)?;
+	let aliases = buf.alloc::<*mut c_char> (1)?;
+	*aliases = ptr::null_mut ();
+	
+	let data = buf.alloc::<u8> (addr_len * addrs.len ())?;
+	let addr_list = buf.alloc::<*mut c_char> (addrs.len () + 1)?;
+	for (i, addr) in addrs.iter ().enumerate () {
+		let p = data.add (i * addr_len);
+		ptr::copy_nonoverlapping (addr.as_ptr (), p, addr_len);
+		*addr_list.add (i) = p as *mut c_char;
+	}
+	*addr_list.add (addrs.len ()) = ptr::null_mut ();
+	
+	*result = libc::hostent {
+		h_name,
+		h_aliases: aliases,
+		h_addrtype: af,
+		h_length: addr_len as c_int,
+		h_addr_list: addr_list,
+	};
+	Ok (())
+}
+
+/// Called by `getaddrinfo`, for both address families at once
+///
+/// # Safety
+///
+/// glibc's NSS interface. All pointers must be valid, and `buffer` must
+/// have room for `buflen` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn _nss_lookaround_gethostbyname4_r (
+	name: *const c_char,
+	pat: *mut *mut GaihAddrtuple,
+	buffer: *mut c_char,
+	buflen: usize,
+	errnop: *mut c_int,
+	h_errnop: *mut c_int,
+	ttlp: *mut i32,
+) -> c_int
+{
+	let result = lookup (name).and_then (|(addrs, ttl)| {
+		let mut buf = Buffer::new (buffer, buflen);
+		*pat = write_addrtuples (CStr::from_ptr (name), &addrs, &mut buf)?;
+		if ! ttlp.is_null () {
+			*ttlp = ttl.as_secs () as i32;
+		}
+		Ok (())
+	});
+	
+	finish (result, errnop, h_errnop)
+}
+
+/// # Safety
+///
+/// glibc's NSS interface. All pointers except `ttlp` and `canonp` must be
+/// valid, and `buffer` must have room for `buflen` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn _nss_lookaround_gethostbyname3_r (
+	name: *const c_char,
+	af: c_int,
+	result: *mut libc::hostent,
+	buffer: *mut c_char,
+	buflen: usize,
+	errnop: *mut c_int,
+	h_errnop: *mut c_int,
+	ttlp: *mut i32,
+	canonp: *mut *mut c_char,
+) -> c_int
+{
+	if af != libc::AF_INET && af != libc::AF_INET6 {
+		*errnop = libc::EAFNOSUPPORT;
+		*h_errnop = NO_RECOVERY;
+		return NSS_STATUS_UNAVAIL;
+	}
+	
+	let result = lookup (name).and_then (|(addrs, ttl)| {
+		let mut buf = Buffer::new (buffer, buflen);
+		write_hostent (CStr::from_ptr (name), af, &addrs, result, &mut buf)?;
+		if ! ttlp.is_null () {
+			*ttlp = ttl.as_secs () as i32;
+		}
+		if ! canonp.is_null () {
+			*canonp = (*result).h_name;
+		}
+		Ok (())
+	});
+	
+	finish (result, errnop, h_errnop)
+}
+
+/// # Safety
+///
+/// Same as `_nss_lookaround_gethostbyname3_r`
+#[no_mangle]
+pub unsafe extern "C" fn _nss_lookaround_gethostbyname2_r (
+	name: *const c_char,
+	af: c_int,
+	result: *mut libc::hostent,
+	buffer: *mut c_char,
+	buflen: usize,
+	errnop: *mut c_int,
+	h_errnop: *mut c_int,
+) -> c_int
+{
+	_nss_lookaround_gethostbyname3_r (name, af, result, buffer, buflen, errnop, h_errnop, ptr::null_mut (), ptr::null_mut ())
+}
+
+/// # Safety
+///
+/// Same as `_nss_lookaround_gethostbyname3_r`
+#[no_mangle]
+pub unsafe extern "C" fn _nss_lookaround_gethostbyname_r (
+	name: *const c_char,
+	result: *mut libc::hostent,
+	buffer: *mut c_char,
+	buflen: usize,
+	errnop: *mut c_int,
+	h_errnop: *mut c_int,
+) -> c_int
+{
+	_nss_lookaround_gethostbyname3_r (name, libc::AF_INET, result, buffer, buflen, errnop, h_errnop, ptr::null_mut (), ptr::null_mut ())
+}
+
+#[cfg (test)]
+mod test {
+	use super::*;
+	
+	fn addrs () -> Vec <IpAddr> {
+		vec! [
+			"192.168.1.101".parse ().unwrap (),
+			"fd00::1".parse ().unwrap (),
+		]
+	}
+	
+	#[test]
+	fn test_write_addrtuples () {
+		let name = c"laptop";
+		let mut storage = vec! [0 as c_char; 256];
+		
+		unsafe {
+			let first = write_addrtuples (name, &addrs (), &mut Buffer::new (storage.as_mut_ptr (), storage.len ())).unwrap ();
+			let first = &*first;
+			assert_eq! (CStr::from_ptr (first.name), name);
+			assert_eq! (first.family, libc::AF_INET);
+			assert_eq! (first.addr [0].to_ne_bytes (), [192, 168, 1, 101]);
+			
+			let second = &*first.next;
+			assert_eq! (second.family, libc::AF_INET6);
+			assert_eq! (second.addr [3].to_ne_bytes (), [0, 0, 0, 1]);
+			assert! (second.next.is_null ());
+			
+			// glibc grows the buffer and tries again if we say so
+			assert_eq! (
+				write_addrtuples (name, &addrs (), &mut Buffer::new (storage.as_mut_ptr (), 16)).err (),
+				Some (Failure::buffer_too_small ())
+			);
+		}
+	}
+	
+	#[test]
+	fn test_write_hostent () {
+		let name = c"laptop";
+		let mut storage = vec! [0 as c_char; 256];
+		let mut result: libc::hostent = unsafe { std::mem::zeroed () };
+		
+		unsafe {
+			write_hostent (name, libc::AF_INET6, &addrs (), &mut result, &mut Buffer::new (storage.as_mut_ptr (), storage.len ())).unwrap ();
+			assert_eq! (CStr::from_ptr (result.h_name), name);
+			assert! ((*result.h_aliases).is_null ());
+			assert_eq! (result.h_length, 16);
+			
+			let addr = std::slice::from_raw_parts (*result.h_addr_list as *const u8, 16);
+			assert_eq! (addr, "fd00::1".parse::<std::net::Ipv6Addr> ().unwrap ().octets ());
+			assert! ((*result.h_addr_list.add (1)).is_null ());
+			
+			// An IPv6-only peer has no IPv4 data, but it does exist
+			let v6_only = &addrs () [1..];
+			let e = write_hostent (name, libc::AF_INET, v6_only, &mut result, &mut Buffer::new (storage.as_mut_ptr (), storage.len ())).unwrap_err ();
+			assert_eq! ((e.status, e.h_errno), (NSS_STATUS_NOTFOUND, NO_DATA));
+		}
+	}
+}
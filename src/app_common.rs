@@ -15,6 +15,10 @@ pub enum AppError {
 	#[error ("Operation timed out")]
 	Elapsed (#[from] tokio::time::error::Elapsed),
 	#[error (transparent)]
+	Hosts (#[from] crate::hosts::HostsError),
+	#[error (transparent)]
+	Identity (#[from] crate::identity::IdentityError),
+	#[error (transparent)]
 	Io (#[from] std::io::Error),
 	#[error (transparent)]
 	Ip (#[from] crate::ip::IpError),
@@ -22,38 +26,121 @@ pub enum AppError {
 	Join (#[from] tokio::task::JoinError),
 	#[error (transparent)]
 	MacAddr (#[from] mac_address::MacAddressError),
+	#[error ("Couldn't find MAC `{0}`")]
+	MacNotFound (String),
+	#[error (transparent)]
+	MacParse (#[from] mac_address::MacParseError),
 	#[error (transparent)]
 	Message (#[from] crate::message::MessageError),
+	#[error ("Couldn't find nickname `{0}`")]
+	NickNotFound (String),
+	#[error ("No LookAround server answered at {0}")]
+	NoAnswer (String),
+	#[error ("Couldn't bind to any network interface")]
+	NoInterfaces,
 	#[error (transparent)]
 	ParseInt (#[from] std::num::ParseIntError),
+	#[error ("Answers would be {0} bytes, too big for one packet. Advertise fewer services, or pick a shorter nickname.")]
+	ResponseTooBig (usize),
+	#[error (transparent)]
+	Seal (#[from] crate::sealed::SealError),
+	#[error ("Couldn't find service `{0}`")]
+	ServiceNotFound (String),
 	#[error (transparent)]
 	Tlv (#[from] crate::tlv::TlvError),
+	#[error (transparent)]
+	Transfer (#[from] crate::transfer::TransferError),
 }
 
 #[derive (Debug, thiserror::Error)]
 pub enum CliArgError {
+	#[error ("Unknown output format or template field `{0}`, try json, jsonl, tsv, csv, or a template like `{{ip}} {{nick}}`")]
+	BadFormat (String),
 	#[error ("Missing value for argument `{0}`")]
 	MissingArgumentValue (String),
 	#[error ("Missing required argument <{0}>")]
 	MissingRequiredArg (String),
 	#[error ("First argument should be a subcommand")]
 	MissingSubcommand,
+	#[error ("`{0}` isn't a multicast address")]
+	NotMulticast (String),
+	#[error ("No `[fleet.{0}]` section in client.ini")]
+	UnknownFleet (String),
 	#[error ("Unknown subcommand `{0}`")]
 	UnknownSubcommand (String),
 	#[error ("Unrecognized argument `{0}`")]
 	UnrecognizedArgument (String),
 }
 
-pub async fn recv_msg_from (socket: &UdpSocket) -> Result <(Vec <Message>, SocketAddr), AppError> 
+pub async fn recv_packet_from (socket: &UdpSocket) -> Result <(Packet, SocketAddr), AppError> 
 {
 	let mut buf = vec! [0u8; PACKET_SIZE];
 	let (bytes_recved, remote_addr) = socket.recv_from (&mut buf).await?;
 	buf.truncate (bytes_recved);
-	let msgs = Message::from_slice2 (&buf)?;
+	let packet = Message::decode (&buf)?;
+	
+	Ok ((packet, remote_addr))
+}
+
+// Binds an IPv6-only UDP socket, so it doesn't fight the IPv4 socket
+// over the same port on dual-stack hosts
+
+pub fn bind_udp_v6 (port: u16) -> Result <UdpSocket, AppError> {
+	use socket2::{
+		Domain,
+		Protocol,
+		Socket,
+		Type,
+	};
 	
-	Ok ((msgs, remote_addr))
+	let socket = Socket::new (Domain::IPV6, Type::DGRAM, Some (Protocol::UDP))?;
+	socket.set_only_v6 (true)?;
+	socket.set_nonblocking (true)?;
+	socket.bind (&SocketAddrV6::new (Ipv6Addr::UNSPECIFIED, port, 0, 0).into ())?;
+	
+	Ok (UdpSocket::from_std (socket.into ())?)
+}
+
+// Formats a peer's IP for humans and scripts. Link-local IPv6 addresses
+// are useless without their scope ID, so it's appended like `fe80::1%2`
+
+pub fn format_ip (addr: &SocketAddr) -> String {
+	match addr {
+		SocketAddr::V4 (x) => x.ip ().to_string (),
+		SocketAddr::V6 (x) if x.scope_id () != 0 => format! ("{}%{}", x.ip (), x.scope_id ()),
+		SocketAddr::V6 (x) => x.ip ().to_string (),
+	}
 }
 
+// Formats a time as UTC, like `2021-12-07 04:05:06Z`. Good enough for
+// logs without pulling in a whole date crate.
+
+pub fn format_timestamp (t: SystemTime) -> String {
+	let secs = t.duration_since (std::time::UNIX_EPOCH).map (|x| x.as_secs ()).unwrap_or_default ();
+	let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+	
+	// Howard Hinnant's `civil_from_days`
+	let z = days as i64 + 719_468;
+	let era = z.div_euclid (146_097);
+	let doe = z - era * 146_097;
+	let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let d = doy - (153 * mp + 2) / 5 + 1;
+	let m = if mp < 10 { mp + 3 } else { mp - 9 };
+	let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+	
+	format! (
+		"{:04}-{:02}-{:02} {:02}:{:02}:{:02}Z",
+		y, m, d,
+		secs_of_day / 3_600, secs_of_day / 60 % 60, secs_of_day % 60,
+	)
+}
+
+// Anything shorter could be guessed offline from one captured packet
+
+const MIN_PSK_LEN: usize = 16;
+
 #[derive (Clone)]
 pub struct Params {
 	// Servers bind on this port, clients must send to the port
@@ -61,6 +148,17 @@ pub struct Params {
 	
 	// Clients and servers will all join the same multicast addr
 	pub multicast_addr: Ipv4Addr,
+	
+	// Same idea for IPv6, but link-local scope, so it must be joined and
+	// sent to once per interface
+	pub multicast_addr_v6: Ipv6Addr,
+	
+	// If set, servers and clients ignore anything not tagged with it
+	pub psk: Option <Psk>,
+	
+	// If set, clients only accept encrypted answers, and servers only
+	// give them, see `sealed`
+	pub encrypt: bool,
 }
 
 impl Default for Params {
@@ -68,6 +166,178 @@ impl Default for Params {
 		Self {
 			server_port: 9040,
 			multicast_addr: Ipv4Addr::new (225, 100, 99, 98),
+			multicast_addr_v6: Ipv6Addr::new (0xff02, 0, 0, 0, 0, 0, 0xe164, 0x6362),
+			psk: None,
+			encrypt: false,
+		}
+	}
+}
+
+impl Params {
+	/// Applies `section` of server.ini or client.ini, like `[network]` or
+	/// a `[fleet.<name>]` section. Servers and clients only find each
+	/// other if these match, so separate fleets on one LAN can each pick
+	/// their own. Anything missing or bad is left alone, and the bad
+	/// settings come back as warnings for the caller to print.
+	pub fn load_ini (&mut self, ini: &Ini, section: &str) -> Vec <String> {
+		let mut warnings = vec! [];
+		
+		if let Some (x) = ini.get (section, "port") {
+			match u16::from_str (&x) {
+				Ok (x) => self.server_port = x,
+				Err (e) => warnings.push (format! ("Ignoring bad `port` setting: {}", e)),
+			}
+		}
+		
+		if let Some (x) = ini.get (section, "group") {
+			match parse_group (&x) {
+				Ok (x) => self.multicast_addr = x,
+				Err (e) => warnings.push (format! ("Ignoring bad `group` setting: {}", e)),
+			}
+		}
+		
+		if let Some (x) = ini.get (section, "group_v6") {
+			match parse_group_v6 (&x) {
+				Ok (x) => self.multicast_addr_v6 = x,
+				Err (e) => warnings.push (format! ("Ignoring bad `group_v6` setting: {}", e)),
+			}
+		}
+		
+		if let Some (x) = ini.get (section, "psk") {
+			// Empty, so a fleet can leave the top-level key behind
+			if x.is_empty () {
+				self.psk = None;
+			}
+			else {
+				if x.len () < MIN_PSK_LEN {
+					warnings.push (format! ("Warning: `psk` is shorter than {} characters, so it's easy to guess", MIN_PSK_LEN));
+				}
+				self.psk = Some (Psk::new (x.as_bytes ()));
+			}
+		}
+		
+		match ini.getbool (section, "encrypt") {
+			Ok (Some (x)) => self.encrypt = x,
+			Ok (None) => (),
+			Err (e) => warnings.push (format! ("Ignoring bad `encrypt` setting: {}", e)),
+		}
+		
+		warnings
+	}
+}
+
+/// Every `[fleet.<name>]` section in `ini`, as (name, section) pairs
+/// sorted by name. `[fleet.<name>.services]` sections aren't fleets.
+pub fn fleet_sections (ini: &Ini) -> Vec <(String, String)> {
+	let mut fleets: Vec <_> = ini.sections ().into_iter ()
+	.filter_map (|section| {
+		let name = section.strip_prefix ("fleet.")?;
+		if name.is_empty () || name.ends_with (".services") {
+			return None;
+		}
+		Some ((name.to_string (), section))
+	})
+	.collect ();
+	fleets.sort ();
+	fleets
+}
+
+pub fn parse_group (s: &str) -> Result <Ipv4Addr, AppError> {
+	let addr = Ipv4Addr::from_str (s.trim ())?;
+	if ! addr.is_multicast () {
+		return Err (CliArgError::NotMulticast (s.to_string ()).into ());
+	}
+	Ok (addr)
+}
+
+pub fn parse_group_v6 (s: &str) -> Result <Ipv6Addr, AppError> {
+	let addr = Ipv6Addr::from_str (s.trim ())?;
+	if ! addr.is_multicast () {
+		return Err (CliArgError::NotMulticast (s.to_string ()).into ());
+	}
+	Ok (addr)
+}
+
+#[cfg (test)]
+mod test {
+	use super::*;
+	
+	#[test]
+	fn test_load_ini () {
+		let mut ini = Ini::new_cs ();
+		ini.read ([
+			"[network]",
+			"port = 9041",
+			"group = 225.100.99.99",
+			"group_v6 = 192.168.1.1",
+			"psk = correct horse battery staple",
+			"[fleet.lab]",
+			"port = 9042",
+			"encrypt = true",
+			"psk =",
+			"[fleet.lab.services]",
+			"ssh = tcp/22",
+			"[fleet.build]",
+			"group = 225.100.99.97",
+		].join ("\n")).unwrap ();
+		
+		let mut params = Params::default ();
+		let warnings = params.load_ini (&ini, "network");
+		assert_eq! (params.server_port, 9041);
+		assert_eq! (params.multicast_addr, Ipv4Addr::new (225, 100, 99, 99));
+		
+		// Bad settings are skipped, with a warning
+		assert_eq! (params.multicast_addr_v6, Params::default ().multicast_addr_v6);
+		assert_eq! (warnings.len (), 1);
+		
+		for bad in ["192.168.1.1", "laptop", "ff02::1"] {
+			assert! (parse_group (bad).is_err (), "{}", bad);
+		}
+		for bad in ["fe80::1", "225.100.99.99"] {
+			assert! (parse_group_v6 (bad).is_err (), "{}", bad);
+		}
+		
+		assert_eq! (fleet_sections (&ini), vec! [
+			("build".to_string (), "fleet.build".to_string ()),
+			("lab".to_string (), "fleet.lab".to_string ()),
+		]);
+		
+		// A fleet only overrides what it sets
+		let mut fleet = params.clone ();
+		fleet.load_ini (&ini, "fleet.build");
+		assert_eq! (fleet.server_port, 9041);
+		assert_eq! (fleet.multicast_addr, Ipv4Addr::new (225, 100, 99, 97));
+		assert! (! fleet.encrypt);
+		assert! (fleet.psk.is_some ());
+		
+		// An empty key means no key
+		let mut fleet = params.clone ();
+		fleet.load_ini (&ini, "fleet.lab");
+		assert! (fleet.encrypt);
+		assert! (fleet.psk.is_none ());
+	}
+	
+	#[test]
+	fn test_format_ip () {
+		for (input, expected) in [
+			("192.168.1.101:9040", "192.168.1.101"),
+			("[fe80::1%2]:9040", "fe80::1%2"),
+			("[2001:db8::1]:9040", "2001:db8::1"),
+		] {
+			let input: SocketAddr = input.parse ().unwrap ();
+			assert_eq! (format_ip (&input), expected);
+		}
+	}
+	
+	#[test]
+	fn test_format_timestamp () {
+		for (input, expected) in [
+			(0, "1970-01-01 00:00:00Z"),
+			(951_782_400, "2000-02-29 00:00:00Z"),
+			(1_700_000_000, "2023-11-14 22:13:20Z"),
+		] {
+			let t = std::time::UNIX_EPOCH + Duration::from_secs (input);
+			assert_eq! (format_timestamp (t), expected);
 		}
 	}
 }
@@ -0,0 +1,821 @@
+// The command-line side of LookAround. Everything here just parses
+// arguments, calls into the library, and prints.
+
+use std::{
+	collections::BTreeMap,
+	io::{
+		Read,
+		Write,
+	},
+	net::{
+		IpAddr,
+		Ipv4Addr,
+		Ipv6Addr,
+		SocketAddr,
+	},
+	path::{
+		Path,
+		PathBuf,
+	},
+	str::FromStr,
+	time::{
+		Duration,
+		SystemTime,
+	},
+};
+
+use mac_address::{
+	MacAddress,
+	get_mac_address,
+};
+use tokio::{
+	io::{
+		AsyncReadExt,
+		AsyncWriteExt,
+	},
+	net::{
+		TcpListener,
+		TcpStream,
+	},
+};
+use tokio_stream::StreamExt;
+
+use lookaround::{
+	AppError,
+	DiscoverOptions,
+	Peer,
+	PeerEvent,
+	Protocol,
+	Responder,
+	ResolverOptions,
+	Service,
+	hosts,
+	app_common::{
+		CliArgError,
+		find_project_dirs,
+		format_ip,
+		format_timestamp,
+		parse_group,
+		parse_group_v6,
+	},
+	connect::{
+		self,
+		LastAddrs,
+	},
+	ip,
+	server,
+	transfer::{
+		self,
+		TransferError,
+	},
+};
+
+use crate::output::{
+	self,
+	Format,
+	Record,
+};
+
+fn print_our_mac () {
+	match get_mac_address() {
+		Ok(Some(ma)) => {
+			println!("Our MAC addr = {}", ma);
+		}
+		Ok(None) => println!("No MAC address found."),
+		Err(e) => println!("{:?}", e),
+	}
+}
+
+pub async fn client <I: Iterator <Item=String>> (args: I) -> Result <(), AppError> {
+	let (options, format) = configure_client (args)?;
+	
+	if format.is_none () {
+		print_our_mac ();
+	}
+	
+	let peers = lookaround::discover (options).await?;
+	let mut peers: Vec <Peer> = peers.collect ().await;
+	
+	// IPv4 first, so each peer's IPv6 addresses land right after it
+	peers.sort_by_key (|x| (x.mac, x.addr.is_ipv6 ()));
+	
+	if let Some (format) = format {
+		let interfaces = ip::list_interfaces ();
+		let records: Vec <_> = peers.iter ().map (|x| Record::from_peer (x, &interfaces)).collect ();
+		output::print (&format, &records);
+		return Ok (());
+	}
+	
+	println! ("Found {} peers:", peers.len ());
+	for peer in peers.into_iter () {
+		print_peer (&peer);
+		for service in &peer.services {
+			println! ("\t{}", service);
+		}
+	}
+	
+	let unmatched = lookaround::client::unmatched_responses ();
+	if unmatched > 0 {
+		eprintln! ("Dropped {} responses that didn't match our request", unmatched);
+	}
+	
+	Ok (())
+}
+
+fn print_peer (peer: &Peer) {
+	let rtt = peer.rtt.map (|x| format! (" in {:.1} ms", x.as_secs_f64 () * 1000.0)).unwrap_or_default ();
+	match peer.mac {
+		None => println! ("<Unknown> = {}{}", peer.addr, rtt),
+		Some (_) => println! ("{}{}", describe_peer (peer), rtt),
+	}
+}
+
+pub async fn find_nick <I: Iterator <Item=String>> (args: I) -> Result <(), AppError> 
+{
+	let (needle_nick, options, format) = parse_find_args (args, "nickname")?;
+	
+	let peer = lookaround::find_nick_with (options, &needle_nick).await?
+	.ok_or (AppError::NickNotFound (needle_nick))?;
+	
+	print_found (&peer, format);
+	Ok (())
+}
+
+pub async fn find_mac <I: Iterator <Item=String>> (args: I) -> Result <(), AppError> 
+{
+	let (needle, options, format) = parse_find_args (args, "MAC")?;
+	let mac = MacAddress::from_str (&needle)?;
+	
+	let peer = lookaround::find_mac_with (options, mac.bytes ()).await?
+	.ok_or (AppError::MacNotFound (needle))?;
+	
+	print_found (&peer, format);
+	Ok (())
+}
+
+// `whois 192.168.1.5` asks that IP directly, and prints its MAC and nickname
+
+pub async fn whois <I: Iterator <Item=String>> (args: I) -> Result <(), AppError> 
+{
+	let (needle, options, format) = parse_find_args (args, "IP")?;
+	
+	// Accept `fe80::1%2` for link-local IPv6
+	let port = options.common.server_port;
+	let addr = match needle.parse::<IpAddr> () {
+		Ok (ip) => SocketAddr::new (ip, port),
+		Err (_) => SocketAddr::from_str (&format! ("[{}]:{}", needle, port))?,
+	};
+	
+	let peer = lookaround::whois_with (options, addr).await?
+	.ok_or (AppError::NoAnswer (needle))?;
+	
+	match format {
+		None => print_peer (&peer),
+		Some (format) => output::print (&format, &[Record::from_peer (&peer, &ip::list_interfaces ())]),
+	}
+	Ok (())
+}
+
+// The `find-*` subcommands all take one thing to look for, plus options
+
+fn parse_find_args <I: Iterator <Item=String>> (mut args: I, what: &str) 
+-> Result <(String, DiscoverOptions, Option <Format>), AppError>
+{
+	let mut needle = None;
+	let mut options = DiscoverOptions::from_config ();
+	let mut format = None;
+	
+	while let Some (arg) = args.next () {
+		if parse_discover_arg (&mut options, &arg, &mut args)? {
+			continue;
+		}
+		match arg.as_str () {
+			"--format" => format = Some (parse_format (&arg, &mut args)?),
+			_ => needle = Some (arg),
+		}
+	}
+	
+	let needle = needle.ok_or_else (|| CliArgError::MissingRequiredArg (what.to_string ()))?;
+	Ok ((needle, options, format))
+}
+
+fn print_found (peer: &Peer, format: Option <Format>) {
+	match format {
+		None => println! ("{}", format_ip (&peer.addr)),
+		Some (format) => output::print (&format, &[Record::from_peer (peer, &ip::list_interfaces ())]),
+	}
+}
+
+// `find-service ssh@laptop` prints `192.168.1.101:22`, for scripts
+
+pub async fn find_service <I: Iterator <Item=String>> (mut args: I) -> Result <(), AppError> 
+{
+	let mut needle = None;
+	let mut options = DiscoverOptions::from_config ();
+	
+	while let Some (arg) = args.next () {
+		if parse_discover_arg (&mut options, &arg, &mut args)? {
+			continue;
+		}
+		needle = Some (arg);
+	}
+	
+	let needle = needle.ok_or_else (|| CliArgError::MissingRequiredArg ("service@nickname".to_string ()))?;
+	let (service, nick) = needle.split_once ('@')
+	.ok_or_else (|| CliArgError::MissingRequiredArg ("service@nickname".to_string ()))?;
+	
+	let addr = lookaround::find_service_with (options, nick, service).await?
+	.ok_or_else (|| AppError::ServiceNotFound (needle.clone ()))?;
+	
+	match addr {
+		SocketAddr::V4 (_) => println! ("{}", addr),
+		SocketAddr::V6 (_) => println! ("[{}]:{}", format_ip (&addr), addr.port ()),
+	}
+	
+	Ok (())
+}
+
+fn configure_client <I: Iterator <Item=String>> (mut args: I) 
+-> Result <(DiscoverOptions, Option <Format>), AppError>
+{
+	let mut options = DiscoverOptions::from_config ();
+	let mut format = None;
+	
+	while let Some (arg) = args.next () {
+		if parse_discover_arg (&mut options, &arg, &mut args)? {
+			continue;
+		}
+		match arg.as_str () {
+			"--format" => format = Some (parse_format (&arg, &mut args)?),
+			_ => return Err (CliArgError::UnrecognizedArgument (arg).into ()),
+		}
+	}
+	
+	Ok ((options, format))
+}
+
+// Handles the arguments that every discovery subcommand takes. Returns
+// false if `arg` isn't one of them.
+
+fn parse_discover_arg <I: Iterator <Item=String>> (
+	options: &mut DiscoverOptions,
+	arg: &str,
+	args: &mut I,
+) -> Result <bool, AppError>
+{
+	match arg {
+		"--bind-addr" => {
+			options.bind_addrs.push (match args.next () {
+				None => return Err (CliArgError::MissingArgumentValue (arg.to_string ()).into ()),
+				Some (x) => Ipv4Addr::from_str (&x)?,
+			});
+		},
+		"--encrypt" => options.common.encrypt = true,
+		"--fleet" => options.use_fleet (&parse_value (arg, args)?)?,
+		"--group" => options.common.multicast_addr = parse_group (&parse_value (arg, args)?)?,
+		"--group-v6" => options.common.multicast_addr_v6 = parse_group_v6 (&parse_value (arg, args)?)?,
+		"--port" => options.common.server_port = u16::from_str (&parse_value (arg, args)?)?,
+		"--timeout-ms" => {
+			options.timeout = parse_millis (arg, args)?;
+		},
+		_ => return Ok (false),
+	}
+	
+	Ok (true)
+}
+
+fn parse_value <I: Iterator <Item=String>> (arg: &str, args: &mut I) -> Result <String, AppError> {
+	args.next ().ok_or_else (|| CliArgError::MissingArgumentValue (arg.to_string ()).into ())
+}
+
+pub fn parse_format <I: Iterator <Item=String>> (arg: &str, args: &mut I) -> Result <Format, AppError> {
+	match args.next () {
+		None => Err (CliArgError::MissingArgumentValue (arg.to_string ()).into ()),
+		Some (x) => Ok (Format::from_str (&x)?),
+	}
+}
+
+fn parse_millis <I: Iterator <Item=String>> (arg: &str, args: &mut I) -> Result <Duration, AppError> {
+	match args.next () {
+		None => Err (CliArgError::MissingArgumentValue (arg.to_string ()).into ()),
+		Some (x) => Ok (Duration::from_millis (u64::from_str (&x)?)),
+	}
+}
+
+// `watch` runs until killed, printing a line whenever a peer joins,
+// leaves, or changes IPs
+
+pub async fn watch <I: Iterator <Item=String>> (mut args: I) -> Result <(), AppError> {
+	let mut options = DiscoverOptions::from_config ();
+	let mut interval = Duration::from_secs (5);
+	
+	while let Some (arg) = args.next () {
+		if parse_discover_arg (&mut options, &arg, &mut args)? {
+			continue;
+		}
+		match arg.as_str () {
+			"--interval-ms" => interval = parse_millis (&arg, &mut args)?,
+			_ => return Err (CliArgError::UnrecognizedArgument (arg).into ()),
+		}
+	}
+	
+	let mut events = Box::pin (lookaround::watch (options, interval).await?);
+	
+	while let Some (event) = events.next ().await {
+		print_event (&event);
+	}
+	
+	Ok (())
+}
+
+// `dns` serves `<nick>.lookaround` to anything that can use a DNS server
+
+pub async fn dns <I: Iterator <Item=String>> (mut args: I) -> Result <(), AppError> {
+	let mut options = ResolverOptions {
+		discover: DiscoverOptions::from_config (),
+		..Default::default ()
+	};
+	
+	while let Some (arg) = args.next () {
+		if parse_discover_arg (&mut options.discover, &arg, &mut args)? {
+			continue;
+		}
+		
+		let value = args.next ().ok_or_else (|| CliArgError::MissingArgumentValue (arg.clone ()))?;
+		match arg.as_str () {
+			"--listen" => options.listen = SocketAddr::from_str (&value)?,
+			"--suffix" => options.suffix = value.trim_matches ('.').to_string (),
+			"--ttl-secs" => options.ttl = Duration::from_secs (u64::from_str (&value)?),
+			"--upstream" => options.upstream = Some (SocketAddr::from_str (&value)?),
+			_ => return Err (CliArgError::UnrecognizedArgument (arg).into ()),
+		}
+	}
+	
+	println! ("Serving `*.{}` on {}", options.suffix, options.listen);
+	match options.upstream {
+		None => println! ("Other names get NXDOMAIN"),
+		Some (x) => println! ("Forwarding other names to {}", x),
+	}
+	
+	lookaround::serve_dns (options).await
+}
+
+// `hosts` prints a hosts file block for every peer, or with `--write`,
+// keeps one up to date in a real hosts file
+
+pub async fn hosts <I: Iterator <Item=String>> (mut args: I) -> Result <(), AppError> {
+	let mut options = DiscoverOptions::from_config ();
+	let mut path = None;
+	let mut dry_run = false;
+	let mut watch_interval = None;
+	
+	while let Some (arg) = args.next () {
+		if parse_discover_arg (&mut options, &arg, &mut args)? {
+			continue;
+		}
+		match arg.as_str () {
+			"--dry-run" => dry_run = true,
+			"--interval-ms" => watch_interval = Some (parse_millis (&arg, &mut args)?),
+			"--watch" => watch_interval = watch_interval.or (Some (Duration::from_secs (5))),
+			"--write" => path = Some (PathBuf::from (args.next ().ok_or_else (|| CliArgError::MissingArgumentValue (arg.clone ()))?)),
+			_ => return Err (CliArgError::UnrecognizedArgument (arg).into ()),
+		}
+	}
+	
+	let nicknames = options.nicknames.clone ();
+	let update = |peers: &[&Peer]| -> Result <(), AppError> {
+		let block = hosts::render_block (&hosts::entries (peers.iter ().copied (), &nicknames));
+		update_hosts (path.as_deref (), &block, dry_run)
+	};
+	
+	let interval = match watch_interval {
+		None => {
+			let peers: Vec <Peer> = lookaround::discover (options).await?.collect ().await;
+			return update (&peers.iter ().collect::<Vec <_>> ());
+		},
+		Some (x) => x,
+	};
+	
+	// Keyed like the watch table, so a peer that moves replaces itself
+	let mut current: BTreeMap <(Option <[u8; 6]>, Option <String>), Peer> = Default::default ();
+	let mut events = Box::pin (lookaround::watch (options, interval).await?);
+	
+	while let Some (event) = events.next ().await {
+		print_event (&event);
+		match event {
+			PeerEvent::Joined (peer) | PeerEvent::AddressChanged { peer, .. } => {
+				current.insert ((peer.mac, peer.nickname.clone ()), peer);
+			},
+			PeerEvent::Left (peer) => {
+				current.remove (&(peer.mac, peer.nickname.clone ()));
+			},
+		}
+		update (&current.values ().collect::<Vec <_>> ())?;
+	}
+	
+	Ok (())
+}
+
+// Without a path, just prints the block
+
+fn update_hosts (path: Option <&Path>, block: &str, dry_run: bool) -> Result <(), AppError> {
+	let path = match path {
+		None => {
+			print! ("{}", block);
+			return Ok (());
+		},
+		Some (x) => x,
+	};
+	
+	let old = match std::fs::read_to_string (path) {
+		Ok (x) => x,
+		Err (e) if e.kind () == std::io::ErrorKind::NotFound => String::new (),
+		Err (e) => return Err (e.into ()),
+	};
+	let new = hosts::replace_block (&old, block)?;
+	
+	if new == old {
+		return Ok (());
+	}
+	
+	if dry_run {
+		println! ("Would change {:?}:", path);
+		print! ("{}", hosts::diff (&old, &new));
+		return Ok (());
+	}
+	
+	hosts::write_atomic (path, &new)?;
+	println! ("Updated {:?}", path);
+	Ok (())
+}
+
+// `listen` is like `watch`, but it only hears peers that announce
+// themselves, and never sends a query
+
+pub async fn listen <I: Iterator <Item=String>> (mut args: I) -> Result <(), AppError> {
+	let mut options = DiscoverOptions::from_config ();
+	
+	while let Some (arg) = args.next () {
+		if parse_discover_arg (&mut options, &arg, &mut args)? {
+			continue;
+		}
+		return Err (CliArgError::UnrecognizedArgument (arg).into ());
+	}
+	
+	let mut events = Box::pin (lookaround::listen (options).await?);
+	
+	while let Some (event) = events.next ().await {
+		print_event (&event);
+	}
+	
+	Ok (())
+}
+
+fn print_event (event: &PeerEvent) {
+	let now = format_timestamp (SystemTime::now ());
+	
+	match event {
+		PeerEvent::Joined (peer) => println! ("{} joined  {}", now, describe_peer (peer)),
+		PeerEvent::Left (peer) => println! ("{} left    {}", now, describe_peer (peer)),
+		PeerEvent::AddressChanged { peer, old } => println! ("{} moved   {} (was {})", now, describe_peer (peer), format_ip (old)),
+	}
+}
+
+fn describe_peer (peer: &Peer) -> String {
+	let mac = peer.mac.map (|x| MacAddress::new (x).to_string ()).unwrap_or_else (|| "<Unknown>".to_string ());
+	
+	match &peer.nickname {
+		None => format! ("{} = {}", mac, format_ip (&peer.addr)),
+		Some (nick) => format! ("{} = {} `{}`", mac, format_ip (&peer.addr), nick),
+	}
+}
+
+// `connect` pipes stdin and stdout to a peer's TCP port, for SSH's
+// `ProxyCommand`. Nothing but the peer's bytes may go to stdout.
+
+pub async fn connect <I: Iterator <Item=String>> (mut args: I) -> Result <(), AppError> {
+	let mut options = DiscoverOptions::from_config ();
+	let mut positional = vec! [];
+	
+	while let Some (arg) = args.next () {
+		if parse_discover_arg (&mut options, &arg, &mut args)? {
+			continue;
+		}
+		match arg.as_str () {
+			x if x.starts_with ("--") => return Err (CliArgError::UnrecognizedArgument (arg).into ()),
+			_ => positional.push (arg),
+		}
+	}
+	
+	let mut positional = positional.into_iter ();
+	let nick = positional.next ().ok_or_else (|| CliArgError::MissingRequiredArg ("nickname".to_string ()))?;
+	let port = u16::from_str (&positional.next ().ok_or_else (|| CliArgError::MissingRequiredArg ("port".to_string ()))?)?;
+	if let Some (x) = positional.next () {
+		return Err (CliArgError::UnrecognizedArgument (x).into ());
+	}
+	
+	let mut last_addrs = find_project_dirs ().map (|x| LastAddrs::load (x.cache_dir ().join ("last_addrs.ini")));
+	let last_known = last_addrs.as_ref ().and_then (|x| x.get (&nick));
+	
+	let stream = connect::connect_nick (options, &nick, port, last_known).await?;
+	
+	if let (Some (last_addrs), Ok (addr)) = (&mut last_addrs, stream.peer_addr ()) {
+		if last_known != Some (addr) {
+			if let Err (e) = last_addrs.set (&nick, addr) {
+				eprintln! ("Couldn't save last address for `{}`: {:?}", nick, e);
+			}
+		}
+	}
+	
+	pipe_stdio (stream).await
+}
+
+async fn pipe_stdio (stream: TcpStream) -> Result <(), AppError> {
+	let (mut rd, mut wr) = stream.into_split ();
+	
+	// Stdin gets a plain thread, since a blocking read in Tokio's pool
+	// would keep the runtime from shutting down after the peer hangs up
+	let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec <u8>> (4);
+	std::thread::spawn (move || {
+		let mut stdin = std::io::stdin ();
+		let mut buf = vec! [0u8; 16 * 1024];
+		loop {
+			match stdin.read (&mut buf) {
+				Ok (0) | Err (_) => break,
+				Ok (n) => if tx.blocking_send (buf [..n].to_vec ()).is_err () {
+					break;
+				},
+			}
+		}
+	});
+	
+	tokio::spawn (async move {
+		while let Some (chunk) = rx.recv ().await {
+			wr.write_all (&chunk).await?;
+		}
+		wr.shutdown ().await
+	});
+	
+	// We're the only task that matters now, so blocking on stdout is fine
+	let mut stdout = std::io::stdout ();
+	let mut buf = vec! [0u8; 16 * 1024];
+	loop {
+		let n = rd.read (&mut buf).await?;
+		if n == 0 {
+			return Ok (());
+		}
+		stdout.write_all (&buf [..n])?;
+		stdout.flush ()?;
+	}
+}
+
+const SEND_ATTEMPTS: u32 = 5;
+
+// `send` finds the receiver by nickname, and if the connection drops,
+// finds it again and resumes
+
+pub async fn send <I: Iterator <Item=String>> (mut args: I) -> Result <(), AppError> {
+	let mut options = DiscoverOptions::from_config ();
+	let mut positional = vec! [];
+	
+	while let Some (arg) = args.next () {
+		if parse_discover_arg (&mut options, &arg, &mut args)? {
+			continue;
+		}
+		match arg.as_str () {
+			x if x.starts_with ("--") => return Err (CliArgError::UnrecognizedArgument (arg).into ()),
+			_ => positional.push (arg),
+		}
+	}
+	
+	let mut positional = positional.into_iter ();
+	let nick = positional.next ().ok_or_else (|| CliArgError::MissingRequiredArg ("nickname".to_string ()))?;
+	let path = PathBuf::from (positional.next ().ok_or_else (|| CliArgError::MissingRequiredArg ("file".to_string ()))?);
+	if let Some (x) = positional.next () {
+		return Err (CliArgError::UnrecognizedArgument (x).into ());
+	}
+	
+	let header = transfer::Header::for_file (&path).await?;
+	
+	for attempt in 1..=SEND_ATTEMPTS {
+		let addr = lookaround::find_service_with (options.clone (), &nick, transfer::SERVICE_NAME).await?
+		.ok_or_else (|| AppError::ServiceNotFound (format! ("{}@{}", transfer::SERVICE_NAME, nick)))?;
+		
+		println! ("Sending {:?} ({} bytes) to `{}` at {}", header.name, header.size, nick, addr);
+		match transfer::send_file (addr, &path, &header).await {
+			Ok (0) => (),
+			Ok (skipped) => println! ("Resumed, {} bytes were already there", skipped),
+			Err (TransferError::Io (e)) if attempt < SEND_ATTEMPTS => {
+				println! ("Connection lost ({}), resuming in 1 second", e);
+				tokio::time::sleep (Duration::from_secs (1)).await;
+				continue;
+			},
+			Err (e) => return Err (e.into ()),
+		}
+		
+		println! ("Sent");
+		break;
+	}
+	
+	Ok (())
+}
+
+// `receive` runs a responder that advertises where to send files, and
+// saves whatever arrives, until killed or until the first file with `--once`
+
+pub async fn receive <I: Iterator <Item=String>> (mut args: I) -> Result <(), AppError> {
+	let mut builder = Responder::builder ().load_config ();
+	let mut dir = PathBuf::from (".");
+	let mut tcp_port = 0;
+	let mut once = false;
+	let mut max_size = u64::MAX;
+	
+	while let Some (arg) = args.next () {
+		match arg.as_str () {
+			"--once" => once = true,
+			_ => {
+				let value = args.next ().ok_or_else (|| CliArgError::MissingArgumentValue (arg.clone ()))?;
+				match arg.as_str () {
+					"--dir" => dir = PathBuf::from (value),
+					"--group" => builder = builder.group (parse_group (&value)?),
+					"--group-v6" => builder = builder.group_v6 (parse_group_v6 (&value)?),
+					"--max-size" => max_size = u64::from_str (&value)?,
+					"--nickname" => builder = builder.nickname (value),
+					"--port" => builder = builder.port (u16::from_str (&value)?),
+					"--tcp-port" => tcp_port = u16::from_str (&value)?,
+					_ => return Err (CliArgError::UnrecognizedArgument (arg).into ()),
+				}
+			},
+		}
+	}
+	
+	// Dual-stack if we can get it
+	let listener = match TcpListener::bind ((Ipv6Addr::UNSPECIFIED, tcp_port)).await {
+		Ok (x) => x,
+		Err (_) => TcpListener::bind ((Ipv4Addr::UNSPECIFIED, tcp_port)).await?,
+	};
+	let port = listener.local_addr ()?.port ();
+	println! ("Saving files to {:?}, listening on TCP port {}", dir, port);
+	
+	let responder = builder.service (Service {
+		name: transfer::SERVICE_NAME.to_string (),
+		protocol: Protocol::Tcp,
+		port,
+	}).build ()?;
+	
+	responder.run_until (async {
+		tokio::select! {
+			_ = shutdown_signal () => (),
+			_ = accept_transfers (listener, dir, max_size, once) => (),
+		}
+	}).await
+}
+
+// Returns after the first file is saved if `once` is set, otherwise never
+
+async fn accept_transfers (listener: TcpListener, dir: PathBuf, max_size: u64, once: bool) {
+	let (saved_tx, mut saved_rx) = tokio::sync::mpsc::unbounded_channel ();
+	
+	loop {
+		let (mut stream, remote_addr) = tokio::select! {
+			x = listener.accept () => match x {
+				Ok (x) => x,
+				Err (e) => {
+					println! ("Error accepting connection: {:?}", e);
+					continue;
+				},
+			},
+			_ = saved_rx.recv () => return,
+		};
+		
+		let dir = dir.clone ();
+		let saved_tx = saved_tx.clone ();
+		tokio::spawn (async move {
+			println! ("Receiving from {}", remote_addr);
+			match transfer::receive_file (&mut stream, &dir, max_size).await {
+				Ok (path) => {
+					println! ("Saved {:?}", path);
+					if once {
+						saved_tx.send (()).ok ();
+					}
+				},
+				Err (e) => println! ("Error receiving from {}: {}", remote_addr, e),
+			}
+		});
+	}
+}
+
+pub async fn server <I: Iterator <Item=String>> (mut args: I) -> Result <(), AppError> 
+{
+	print_our_mac ();
+	
+	let mut builder = Responder::builder ().load_config ();
+	
+	// Flags only apply to the top-level settings, not to fleets
+	
+	let fleets = builder.fleets ();
+	let mut top_level = fleets.is_empty () || server::has_top_level_config ();
+	
+	while let Some (arg) = args.next () {
+		// Any flag sets something for the top level, so serve it
+		top_level = true;
+		
+		match arg.as_str () {
+			"--bind-addr" => {
+				builder = builder.bind_addr (match args.next () {
+					None => return Err (CliArgError::MissingArgumentValue (arg).into ()),
+					Some (x) => Ipv4Addr::from_str (&x)?,
+				});
+			},
+			"--group" => builder = builder.group (parse_group (&parse_value (&arg, &mut args)?)?),
+			"--group-v6" => builder = builder.group_v6 (parse_group_v6 (&parse_value (&arg, &mut args)?)?),
+			"--encrypt" => builder = builder.encrypt (true),
+			"--mdns" => builder = builder.mdns (true),
+			"--port" => builder = builder.port (u16::from_str (&parse_value (&arg, &mut args)?)?),
+			"--nickname" => {
+				builder = builder.nickname (match args.next () {
+					None => return Err (CliArgError::MissingArgumentValue (arg).into ()),
+					Some (x) => x
+				});
+			},
+			_ => return Err (CliArgError::UnrecognizedArgument (arg).into ()),
+		}
+	}
+	
+	if ! top_level {
+		eprintln! ("server.ini only has fleets, so not serving the default port and groups");
+	}
+	
+	// mDNS answers for the whole machine, and two sockets answering it
+	// would answer twice, so only the first responder asking gets it
+	
+	let mut mdns_taken = top_level && builder.has_mdns ();
+	let fleets: Vec <_> = fleets.into_iter ().map (|(name, fleet)| {
+		if ! fleet.has_mdns () {
+			(name, fleet)
+		}
+		else if mdns_taken {
+			eprintln! ("Fleet `{}` won't answer mDNS, since this server already does for something else", name);
+			(name, fleet.mdns (false))
+		}
+		else {
+			mdns_taken = true;
+			(name, fleet)
+		}
+	}).collect ();
+	
+	// Every fleet shares one shutdown signal, so they all say goodbye
+	
+	let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel (());
+	let mut tasks = vec! [];
+	for (name, fleet) in fleets {
+		let responder = fleet.build ()?;
+		let mut shutdown_rx = shutdown_rx.clone ();
+		tasks.push (tokio::spawn (async move {
+			let shutdown = async move {
+				shutdown_rx.changed ().await.ok ();
+			};
+			if let Err (e) = responder.run_until (shutdown).await {
+				eprintln! ("Stopped serving fleet `{}`: {}", name, e);
+			}
+		}));
+	}
+	
+	let result = if top_level {
+		builder.build ()?.run_until (shutdown_signal ()).await
+	}
+	else {
+		shutdown_signal ().await;
+		Ok (())
+	};
+	shutdown_tx.send (()).ok ();
+	for task in tasks {
+		task.await?;
+	}
+	result
+}
+
+// Ctrl+C, or a service manager stopping us, so the server can say goodbye
+
+async fn shutdown_signal () {
+	#[cfg(unix)]
+	{
+		use tokio::signal::unix::{
+			SignalKind,
+			signal,
+		};
+		
+		match signal (SignalKind::terminate ()) {
+			Ok (mut sigterm) => {
+				tokio::select! {
+					_ = tokio::signal::ctrl_c () => (),
+					_ = sigterm.recv () => (),
+				}
+				return;
+			},
+			Err (e) => eprintln! ("Can't listen for SIGTERM: {:?}", e),
+		}
+	}
+	
+	tokio::signal::ctrl_c ().await.ok ();
+}
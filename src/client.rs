@@ -1,172 +1,343 @@
+use socket2::{
+	Domain,
+	Socket,
+	Type,
+};
+
+use std::sync::atomic::{
+	AtomicU64,
+	Ordering,
+};
+
 use crate::prelude::*;
 
-struct ServerResponse {
-	mac: Option <[u8; 6]>,
-	nickname: Option <String>,
+/// A LookAround server that answered one of our requests. Peers with
+/// several addresses show up once per address.
+#[derive (Clone, Debug, PartialEq)]
+pub struct Peer {
+	pub addr: SocketAddr,
+	pub mac: Option <[u8; 6]>,
+	
+	/// From the server's own config if it has one, otherwise from our
+	/// client.ini
+	pub nickname: Option <String>,
+	pub nickname_source: Option <NicknameSource>,
+	
+	/// TCP and UDP services the server advertises
+	pub services: Vec <Service>,
+	
+	/// The key the server signed its response with, if it signed
+	pub public_key: Option <PublicKey>,
+	
+	/// From sending our request to hearing this answer. `None` for
+	/// announcements, which nobody asked for.
+	pub rtt: Option <Duration>,
 }
 
-struct ConfigFile {
-	nicknames: HashMap <String, String>,
+/// Where a peer's nickname came from
+#[derive (Clone, Copy, Debug, PartialEq)]
+pub enum NicknameSource {
+	/// The peer's own server.ini
+	Server,
+	/// Our client.ini
+	ClientConfig,
 }
 
-struct ClientParams {
-	common: app_common::Params,
-	bind_addrs: Vec <Ipv4Addr>,
-	nicknames: HashMap <String, String>,
-	timeout_ms: u64,
+impl NicknameSource {
+	pub fn as_str (&self) -> &'static str {
+		match self {
+			Self::Server => "server",
+			Self::ClientConfig => "client.ini",
+		}
+	}
 }
 
-pub async fn client <I: Iterator <Item=String>> (args: I) -> Result <(), AppError> {
-	match get_mac_address() {
-		Ok(Some(ma)) => {
-			println!("Our MAC addr = {}", ma);
-		}
-		Ok(None) => println!("No MAC address found."),
-		Err(e) => println!("{:?}", e),
+impl Peer {
+	pub fn service (&self, name: &str) -> Option <&Service> {
+		self.services.iter ().find (|x| x.name == name)
 	}
 	
-	let params = configure_client (args)?;
-	let socket = make_socket (&params.common, params.bind_addrs).await?;
-	let msg = Message::new_request1 ().to_vec ()?;
-	tokio::spawn (send_requests (Arc::clone (&socket), params.common, msg));
+	/// Where to connect to reach one of the peer's advertised services
+	pub fn service_addr (&self, name: &str) -> Option <SocketAddr> {
+		let mut addr = self.addr;
+		addr.set_port (self.service (name)?.port);
+		Some (addr)
+	}
+}
+
+/// How to look for peers. `Default` gives the standard port and groups,
+/// auto-detected interfaces, and no local nicknames.
+#[derive (Clone)]
+pub struct DiscoverOptions {
+	pub common: app_common::Params,
 	
-	let mut peers = HashMap::with_capacity (10);
+	/// IPv4 addresses of the interfaces to query on. Empty means all of them.
+	pub bind_addrs: Vec <Ipv4Addr>,
 	
-	timeout (Duration::from_millis (params.timeout_ms), listen_for_responses (&*socket, params.nicknames, &mut peers)).await.ok ();
+	/// Indexes of the interfaces to query on with IPv6. Empty means all of them.
+	pub v6_ifaces: Vec <u32>,
 	
-	let mut peers: Vec <_> = peers.into_iter ().collect ();
-	peers.sort_by_key (|(_, v)| v.mac);
+	/// MAC-nickname pairs, like the `[nicknames]` section of client.ini
+	pub nicknames: HashMap <String, String>,
 	
-	println! ("Found {} peers:", peers.len ());
-	for (ip, resp) in peers.into_iter () {
-		let mac = match resp.mac {
-			None => {
-				println! ("<Unknown> = {}", ip);
-				continue;
-			},
-			Some (x) => x,
-		};
-		
-		let nickname = match resp.nickname {
-			None => {
-				println! ("{} = {}", MacAddress::new (mac), ip.ip ());
-				continue;
-			},
-			Some (x) => x,
-		};
-		
-		println! ("{} = {} `{}`", MacAddress::new (mac), ip.ip (), nickname);
-	}
+	/// How long to listen for responses before ending the stream
+	pub timeout: Duration,
 	
-	Ok (())
+	/// Where to pin peers' keys. If this is `None`, signatures are still
+	/// checked, but any key is accepted.
+	pub known_peers: Option <PathBuf>,
+	
+	/// Network settings for each `[fleet.<name>]` section of client.ini,
+	/// for `use_fleet`
+	pub fleets: HashMap <String, app_common::Params>,
+	
+	/// We're running inside somebody else's program, like the NSS module
+	/// does. Nothing is printed, and keys are checked against `known_peers`,
+	/// but new ones aren't pinned, so nothing is written either.
+	pub guest: bool,
 }
 
-pub async fn find_nick <I: Iterator <Item=String>> (mut args: I) -> Result <(), AppError> 
-{
-	let mut nick = None;
-	let mut timeout_ms = 500;
-	let ConfigFile {
-		nicknames,
-	} = load_config_file ();
-	
-	while let Some (arg) = args.next () {
-		match arg.as_str () {
-			"--timeout-ms" => {
-				timeout_ms = match args.next () {
-					None => return Err (CliArgError::MissingArgumentValue (arg).into ()),
-					Some (x) => u64::from_str (&x)?,
-				};
-			},
-			_ => nick = Some (arg),
+impl Default for DiscoverOptions {
+	fn default () -> Self {
+		Self {
+			common: Default::default (),
+			bind_addrs: vec! [],
+			v6_ifaces: vec! [],
+			nicknames: Default::default (),
+			timeout: Duration::from_millis (500),
+			known_peers: None,
+			fleets: Default::default (),
+			guest: false,
 		}
 	}
+}
+
+impl DiscoverOptions {
+	pub(crate) async fn make_sockets (&self) -> Result <ClientSockets, AppError> {
+		let (bind_addrs, v6_ifaces) = self.ifaces ()?;
+		make_sockets (&self.common, bind_addrs, v6_ifaces, self.guest).await
+	}
 	
-	let needle_nick = nick.ok_or_else (|| CliArgError::MissingRequiredArg ("nickname".to_string ()))?;
-	let needle_nick = Some (needle_nick);
-	
-	let common_params = Default::default ();
-	
-	let socket = make_socket (&common_params, get_ips ()?).await?;
-	let msg = Message::new_request1 ().to_vec ()?;
-	tokio::spawn (send_requests (Arc::clone (&socket), common_params, msg));
+	/// Sockets on the server port and in the multicast groups, to overhear
+	/// announcements instead of asking
+	pub(crate) fn make_group_sockets (&self) -> Result <ClientSockets, AppError> {
+		let (bind_addrs, v6_ifaces) = self.ifaces ()?;
+		make_group_sockets (&self.common, bind_addrs, v6_ifaces, self.guest)
+	}
 	
-	timeout (Duration::from_millis (timeout_ms), async move { loop {
-		let (msgs, remote_addr) = match recv_msg_from (&socket).await {
-			Err (_) => continue,
-			Ok (x) => x,
+	fn ifaces (&self) -> Result <(Vec <Ipv4Addr>, Vec <u32>), AppError> {
+		let bind_addrs = if self.bind_addrs.is_empty () {
+			get_ips ()?
+		}
+		else {
+			self.bind_addrs.clone ()
 		};
 		
-		let mut resp = ServerResponse {
-			mac: None,
-			nickname: None,
+		let v6_ifaces = if self.v6_ifaces.is_empty () {
+			detect_v6_ifaces (self.guest)
+		}
+		else {
+			self.v6_ifaces.clone ()
 		};
 		
-		for msg in msgs.into_iter () {
-			match msg {
-				Message::Response1 (x) => resp.mac = x,
-				Message::Response2 (x) => resp.nickname = Some (x.nickname),
-				_ => (),
-			}
+		Ok ((bind_addrs, v6_ifaces))
+	}
+	
+	/// Default options plus anything set in client.ini
+	pub fn from_config () -> Self {
+		let ConfigFile {
+			common,
+			nicknames,
+			fleets,
+			warnings,
+		} = load_config_file ();
+		
+		for x in warnings {
+			eprintln! ("{}", x);
 		}
 		
-		resp.nickname = get_peer_nickname (&nicknames, resp.mac, resp.nickname);
+		Self {
+			common,
+			nicknames,
+			known_peers: known_peers_path (),
+			fleets,
+			..Default::default ()
+		}
+	}
+	
+	/// Like `from_config`, but as a `guest`, and without printing any
+	/// warnings about client.ini
+	pub fn from_config_as_guest () -> Self {
+		let ConfigFile {
+			common,
+			nicknames,
+			fleets,
+			warnings: _,
+		} = load_config_file ();
 		
-		if resp.nickname == needle_nick {
-			println! ("{}", remote_addr.ip ());
-			return;
+		Self {
+			common,
+			nicknames,
+			known_peers: known_peers_path (),
+			fleets,
+			guest: true,
+			..Default::default ()
 		}
-	}}).await?;
+	}
 	
-	Ok (())
+	pub(crate) fn load_known_peers (&self) -> Option <KnownPeers> {
+		self.known_peers.clone ().map (|x| KnownPeers::load (x, self.guest))
+	}
+	
+	/// Switches to the port and groups of the fleet called `name`
+	pub fn use_fleet (&mut self, name: &str) -> Result <(), AppError> {
+		self.common = self.fleets.get (name)
+		.ok_or_else (|| app_common::CliArgError::UnknownFleet (name.to_string ()))?
+		.clone ();
+		Ok (())
+	}
+}
+
+struct ServerResponse {
+	mac: Option <[u8; 6]>,
+	nickname: Option <String>,
+	services: Vec <Service>,
+}
+
+struct ConfigFile {
+	common: app_common::Params,
+	nicknames: HashMap <String, String>,
+	fleets: HashMap <String, app_common::Params>,
+	warnings: Vec <String>,
 }
 
-fn configure_client <I: Iterator <Item=String>> (mut args: I) 
--> Result <ClientParams, AppError>
+/// Sends requests to every interface and yields each peer as it answers.
+/// The stream ends after `options.timeout`.
+pub async fn discover (options: DiscoverOptions) 
+-> Result <impl Stream <Item = Peer>, AppError>
 {
-	let mut bind_addrs = vec! [];
-	let mut timeout_ms = 500;
+	query (options, Message::new_request1 (), None).await
+}
+
+// Sends `request` to the multicast groups, or only to `dest` if given
+
+async fn query (options: DiscoverOptions, request: Message, dest: Option <SocketAddr>) 
+-> Result <impl Stream <Item = Peer>, AppError>
+{
+	let sockets = options.make_sockets ().await?;
+	let mut known_peers = options.load_known_peers ();
+	let (tx, rx) = mpsc::unbounded_channel ();
 	
-	let ConfigFile {
-		nicknames,
-	} = load_config_file ();
-	
-	while let Some (arg) = args.next () {
-		match arg.as_str () {
-			"--bind-addr" => {
-				bind_addrs.push (match args.next () {
-					None => return Err (CliArgError::MissingArgumentValue (arg).into ()),
-					Some (x) => Ipv4Addr::from_str (&x)?,
-				});
-			},
-			"--timeout-ms" => {
-				timeout_ms = match args.next () {
-					None => return Err (CliArgError::MissingArgumentValue (arg).into ()),
-					Some (x) => u64::from_str (&x)?,
-				};
-			},
-			_ => return Err (CliArgError::UnrecognizedArgument (arg).into ()),
-		}
-	}
+	let outstanding = Outstanding::new (request, &options);
+	match dest {
+		None => tokio::spawn (send_requests (sockets.clone (), options.common.clone (), Arc::clone (&outstanding))),
+		Some (dest) => tokio::spawn (send_unicast_requests (sockets.clone (), dest, Arc::clone (&outstanding))),
+	};
+	
+	tokio::spawn (async move {
+		let listen = listen_for_responses (&sockets, &outstanding, &options, &mut known_peers, |peer| tx.send (peer).is_ok ());
+		timeout (options.timeout, listen).await.ok ();
+	});
+	
+	Ok (UnboundedReceiverStream::new (rx))
+}
+
+/// Looks for a peer by nickname, using only the nicknames that servers
+/// report for themselves. Returns `None` if nobody answers before `timeout`.
+pub async fn find_nick (nick: &str, timeout: Duration) 
+-> Result <Option <Peer>, AppError>
+{
+	find_nick_with (DiscoverOptions {
+		timeout,
+		..Default::default ()
+	}, nick).await
+}
+
+/// Like `find_nick`, but with custom options, e.g. `DiscoverOptions::from_config`
+/// to also match nicknames from client.ini
+pub async fn find_nick_with (options: DiscoverOptions, nick: &str) 
+-> Result <Option <Peer>, AppError>
+{
+	let peers = discover (options).await?;
+	Ok (first_match (peers, |peer| peer.nickname.as_deref () == Some (nick)).await)
+}
+
+/// Looks for the address of `service` on the peer called `nick`. Peers
+/// that have the nickname but not the service are skipped, since a
+/// machine can run more than one responder.
+pub async fn find_service_with (options: DiscoverOptions, nick: &str, service: &str) 
+-> Result <Option <SocketAddr>, AppError>
+{
+	let peers = discover (options).await?;
+	let peer = first_match (peers, |peer| peer.nickname.as_deref () == Some (nick) && peer.service (service).is_some ()).await;
+	Ok (peer.and_then (|x| x.service_addr (service)))
+}
+
+/// Looks for a peer by MAC. Only the server with that MAC is asked to answer,
+/// so this is quieter than `discover`. Older servers ignore MAC-specific
+/// requests, so they'll never be found this way.
+pub async fn find_mac_with (options: DiscoverOptions, mac: [u8; 6]) 
+-> Result <Option <Peer>, AppError>
+{
+	let peers = query (options, Message::new_request1_for (Some (mac)), None).await?;
+	Ok (first_match (peers, |peer| peer.mac == Some (mac)).await)
+}
+
+/// Asks whoever is at `addr` who it is, without multicasting. `addr` should
+/// include the server port, and a scope ID for link-local IPv6.
+pub async fn whois_with (options: DiscoverOptions, addr: SocketAddr) 
+-> Result <Option <Peer>, AppError>
+{
+	let peers = query (options, Message::new_request1 (), Some (addr)).await?;
+	Ok (first_match (peers, |peer| peer.addr.ip () == addr.ip ()).await)
+}
+
+// Scripts mostly want IPv4, so an IPv6 match is only a fallback in case
+// no IPv4 match shows up before the timeout
+
+async fn first_match <S, F> (peers: S, mut pred: F) -> Option <Peer>
+where
+	S: Stream <Item = Peer>,
+	F: FnMut (&Peer) -> bool,
+{
+	let mut peers = Box::pin (peers);
+	let mut fallback_v6 = None;
 	
-	if bind_addrs.is_empty () {
-		bind_addrs = get_ips ()?;
+	while let Some (peer) = peers.next ().await {
+		if ! pred (&peer) {
+			continue;
+		}
+		
+		if peer.addr.is_ipv4 () {
+			return Some (peer);
+		}
+		
+		if fallback_v6.is_none () {
+			fallback_v6 = Some (peer);
+		}
 	}
 	
-	Ok (ClientParams {
-		common: Default::default (),
-		bind_addrs,
-		nicknames,
-		timeout_ms,
-	})
+	fallback_v6
 }
 
 fn load_config_file () -> ConfigFile {
+	let mut common = app_common::Params::default ();
 	let mut nicknames: HashMap <String, String> = Default::default ();
+	let mut fleets = HashMap::default ();
+	let mut warnings = vec! [];
 	
 	if let Some (proj_dirs) = find_project_dirs () {
 		let mut ini = Ini::new_cs ();
 		let path = proj_dirs.config_local_dir ().join ("client.ini");
 		if ini.load (&path).is_ok () {
+			warnings.extend (common.load_ini (&ini, "network"));
+			
+			for (name, section) in app_common::fleet_sections (&ini) {
+				let mut x = common.clone ();
+				warnings.extend (x.load_ini (&ini, &section));
+				fleets.insert (name, x);
+			}
+			
 			let map_ref = ini.get_map_ref ();
 			if let Some (x) = map_ref.get ("nicknames") {
 				for (k, v) in x {
@@ -180,70 +351,481 @@ fn load_config_file () -> ConfigFile {
 	}
 	
 	ConfigFile {
+		common,
 		nicknames,
+		fleets,
+		warnings,
+	}
+}
+
+fn known_peers_path () -> Option <PathBuf> {
+	find_project_dirs ().map (|x| x.config_local_dir ().join ("known_peers.ini"))
+}
+
+fn detect_v6_ifaces (guest: bool) -> Vec <u32> {
+	get_ipv6_ifaces ().unwrap_or_else (|e| {
+		if ! guest {
+			eprintln! ("Can't detect IPv6 interfaces: {:?}", e);
+		}
+		vec! []
+	})
+}
+
+// One socket per address family. IPv6 is optional because plenty of
+// hosts have it disabled.
+
+#[derive (Clone)]
+pub(crate) struct ClientSockets {
+	v4: Arc <UdpSocket>,
+	v6: Option <Arc <UdpSocket>>,
+	v6_ifaces: Vec <u32>,
+}
+
+impl ClientSockets {
+	pub(crate) async fn recv_packet_from (&self) -> Result <(Packet, SocketAddr), AppError> {
+		match &self.v6 {
+			None => recv_packet_from (&self.v4).await,
+			Some (v6) => tokio::select! {
+				x = recv_packet_from (&self.v4) => x,
+				x = recv_packet_from (v6) => x,
+			},
+		}
 	}
 }
 
-async fn make_socket (
+pub(crate) async fn make_sockets (
 	common_params: &app_common::Params,
 	bind_addrs: Vec <Ipv4Addr>,
-) -> Result <Arc <UdpSocket>, AppError> {
+	v6_ifaces: Vec <u32>,
+	guest: bool,
+) -> Result <ClientSockets, AppError> {
 	let socket = UdpSocket::bind (SocketAddrV4::new (Ipv4Addr::UNSPECIFIED, 0)).await?;
 	
 	for bind_addr in &bind_addrs {
 		if let Err (e) = socket.join_multicast_v4 (common_params.multicast_addr, *bind_addr) {
-			println! ("Error joining multicast group with iface {}: {:?}", bind_addr, e);
+			if ! guest {
+				eprintln! ("Error joining multicast group with iface {}: {:?}", bind_addr, e);
+			}
 		}
 	}
 	
-	Ok (Arc::new (socket))
+	let v6 = if v6_ifaces.is_empty () {
+		None
+	}
+	else {
+		match bind_udp_v6 (0) {
+			Ok (x) => Some (Arc::new (x)),
+			Err (e) => {
+				if ! guest {
+					eprintln! ("Can't bind IPv6 socket, querying IPv4 only: {:?}", e);
+				}
+				None
+			},
+		}
+	};
+	
+	Ok (ClientSockets {
+		v4: Arc::new (socket),
+		v6,
+		v6_ifaces,
+	})
 }
 
-async fn send_requests (
-	socket: Arc <UdpSocket>, 
+// Servers on this machine already have the port, so share it with them
+
+fn make_group_sockets (
+	common_params: &app_common::Params,
+	bind_addrs: Vec <Ipv4Addr>,
+	v6_ifaces: Vec <u32>,
+	guest: bool,
+) -> Result <ClientSockets, AppError> {
+	let socket = Socket::new (Domain::IPV4, Type::DGRAM, Some (socket2::Protocol::UDP))?;
+	socket.set_reuse_address (true)?;
+	socket.bind (&SocketAddrV4::new (Ipv4Addr::UNSPECIFIED, common_params.server_port).into ())?;
+	for bind_addr in &bind_addrs {
+		if let Err (e) = socket.join_multicast_v4 (&common_params.multicast_addr, bind_addr) {
+			if ! guest {
+				eprintln! ("Error joining multicast group with iface {}: {:?}", bind_addr, e);
+			}
+		}
+	}
+	socket.set_nonblocking (true)?;
+	let v4 = UdpSocket::from_std (socket.into ())?;
+	
+	let v6 = if v6_ifaces.is_empty () {
+		None
+	}
+	else {
+		let socket = Socket::new (Domain::IPV6, Type::DGRAM, Some (socket2::Protocol::UDP))?;
+		socket.set_only_v6 (true)?;
+		socket.set_reuse_address (true)?;
+		socket.bind (&SocketAddrV6::new (Ipv6Addr::UNSPECIFIED, common_params.server_port, 0, 0).into ())?;
+		for iface in &v6_ifaces {
+			if let Err (e) = socket.join_multicast_v6 (&common_params.multicast_addr_v6, *iface) {
+				if ! guest {
+					eprintln! ("Error joining IPv6 multicast group on iface {}: {:?}", iface, e);
+				}
+			}
+		}
+		socket.set_nonblocking (true)?;
+		Some (Arc::new (UdpSocket::from_std (socket.into ())?))
+	};
+	
+	Ok (ClientSockets {
+		v4: Arc::new (v4),
+		v6,
+		v6_ifaces,
+	})
+}
+
+// A request we're still listening for answers to, shared between the
+// task sending it and the one listening
+
+pub(crate) struct Outstanding {
+	request: Message,
+	idem_id: [u8; 8],
+	psk: Option <Psk>,
+	
+	// New for each request, so answers to one can't be read with the key
+	// from another
+	response_key: Option <ResponseKey>,
+	guest: bool,
+	state: std::sync::Mutex <OutstandingState>,
+}
+
+#[derive (Default)]
+struct OutstandingState {
+	// When the first copy went out, for round-trip times
+	first_sent: Option <Instant>,
+	
+	// See `Message::KnownAnswers`
+	known_answers: Vec <[u8; 8]>,
+}
+
+// Plenty for one LAN, and small enough to fit in a packet
+
+const MAX_KNOWN_ANSWERS: usize = 100;
+
+// Answers that didn't match the request we were listening for, since
+// the process started
+
+static UNMATCHED_RESPONSES: AtomicU64 = AtomicU64::new (0);
+
+/// How many responses we've dropped because they didn't answer any request
+/// we were waiting on. They're usually late answers to an earlier request,
+/// but they could be forged.
+pub fn unmatched_responses () -> u64 {
+	UNMATCHED_RESPONSES.load (Ordering::Relaxed)
+}
+
+impl Outstanding {
+	pub(crate) fn new (request: Message, options: &DiscoverOptions) -> Arc <Self> {
+		Arc::new (Self {
+			idem_id: request.idem_id ().unwrap_or_default (),
+			request,
+			psk: options.common.psk.clone (),
+			response_key: options.common.encrypt.then (ResponseKey::default),
+			guest: options.guest,
+			state: Default::default (),
+		})
+	}
+	
+	fn add_known_answer (&self, id: [u8; 8]) {
+		let ids = &mut self.state.lock ().unwrap ().known_answers;
+		if ids.len () < MAX_KNOWN_ANSWERS && ! ids.contains (&id) {
+			ids.push (id);
+		}
+	}
+	
+	// The request in every protocol version, so servers too old for the
+	// newest one can still answer. Only v2 can carry our known answers,
+	// a response key, or a PSK tag, so private or encrypted requests only
+	// go out in v2.
+	
+	fn packets (&self) -> Result <Vec <Vec <u8>>, MessageError> {
+		let mut state = self.state.lock ().unwrap ();
+		state.first_sent.get_or_insert_with (Instant::now);
+		let ids = &state.known_answers;
+		
+		message::VERSIONS.iter ()
+		.filter (|v| (self.psk.is_none () && self.response_key.is_none ()) || **v >= message::VERSION_2)
+		.map (|v| {
+			let mut msgs = vec! [self.request.clone ()];
+			if *v >= message::VERSION_2 && ! ids.is_empty () {
+				msgs.push (Message::KnownAnswers (ids.clone ()));
+			}
+			if let Some (key) = &self.response_key {
+				msgs.push (key.message ());
+			}
+			if let Some (psk) = &self.psk {
+				msgs.push (psk::timestamp (SystemTime::now ()));
+				msgs.push (psk.tag (self.idem_id, &msgs, *v)?);
+			}
+			Message::encode (&msgs, *v)
+		}).collect ()
+	}
+	
+	// Since the first copy, since servers answer that one unless it's lost
+	
+	fn rtt (&self, now: Instant) -> Option <Duration> {
+		self.state.lock ().unwrap ().first_sent.map (|x| now.saturating_duration_since (x))
+	}
+}
+
+// Sends `request` a few times, in case of packet loss. Servers re-answer
+// the copies that come long enough after their first answer, unless the
+// copy says we already heard them.
+
+pub(crate) async fn send_requests (
+	sockets: ClientSockets,
 	params: app_common::Params,
-	msg: Vec <u8>,
+	outstanding: Arc <Outstanding>,
 ) 
 -> Result <(), AppError> 
 {
 	for _ in 0..10 {
-		socket.send_to (&msg, (params.multicast_addr, params.server_port)).await?;
+		for msg in &outstanding.packets ()? {
+			// Don't let one family's send errors (e.g. no IPv4 route) stop
+			// the other family
+			if let Err (e) = sockets.v4.send_to (msg, (params.multicast_addr, params.server_port)).await {
+				if ! outstanding.guest {
+					eprintln! ("Error sending IPv4 request: {:?}", e);
+				}
+			}
+			
+			if let Some (v6) = &sockets.v6 {
+				for iface in &sockets.v6_ifaces {
+					let addr = SocketAddrV6::new (params.multicast_addr_v6, params.server_port, 0, *iface);
+					if let Err (e) = v6.send_to (msg, addr).await {
+						if ! outstanding.guest {
+							eprintln! ("Error sending IPv6 request on iface {}: {:?}", iface, e);
+						}
+					}
+				}
+			}
+		}
+		
 		sleep (Duration::from_millis (100)).await;
 	}
 	
 	Ok::<_, AppError> (())
 }
 
-async fn listen_for_responses (
-	socket: &UdpSocket,
-	nicknames: HashMap <String, String>,
-	peers: &mut HashMap <SocketAddr, ServerResponse>
+// Listens for responses to `outstanding`, and passes each verified peer
+// to `on_peer`, until `on_peer` returns false
+
+pub(crate) async fn listen_for_responses <F: FnMut (Peer) -> bool> (
+	sockets: &ClientSockets,
+	outstanding: &Outstanding,
+	options: &DiscoverOptions,
+	known_peers: &mut Option <KnownPeers>,
+	mut on_peer: F,
 ) {
+	let mut seen = HashSet::new ();
+	
 	loop {
-		let (msgs, remote_addr) = match recv_msg_from (socket).await {
+		let (packet, remote_addr) = match sockets.recv_packet_from ().await {
 			Err (_) => continue,
 			Ok (x) => x,
 		};
+		let rtt = outstanding.rtt (Instant::now ());
 		
-		let mut resp = ServerResponse {
-			mac: None,
-			nickname: None,
+		let answer_id = packet.msgs.iter ().find_map (|x| match x {
+			Message::AnswerId (x) => Some (*x),
+			_ => None,
+		});
+		
+		let mut peer = match parse_peer (packet, remote_addr, outstanding.idem_id, outstanding.response_key.as_ref (), options, known_peers) {
+			None => continue,
+			Some (x) => x,
 		};
+		peer.rtt = rtt;
 		
-		for msg in msgs.into_iter () {
-			match msg {
-				Message::Response1 (x) => resp.mac = x,
-				Message::Response2 (x) => resp.nickname = Some (x.nickname),
-				_ => (),
-			}
+		if let Some (x) = answer_id {
+			outstanding.add_known_answer (x);
 		}
 		
-		resp.nickname = get_peer_nickname (&nicknames, resp.mac, resp.nickname);
+		// Callers only need to hear about each address once
+		if ! seen.insert (remote_addr) {
+			continue;
+		}
 		
-		peers.insert (remote_addr, resp);
+		if ! on_peer (peer) {
+			return;
+		}
 	}
 }
 
+pub(crate) async fn send_unicast_requests (
+	sockets: ClientSockets,
+	dest: SocketAddr,
+	outstanding: Arc <Outstanding>,
+) 
+-> Result <(), AppError> 
+{
+	let socket = match (dest, &sockets.v6) {
+		(SocketAddr::V4 (_), _) => &sockets.v4,
+		(SocketAddr::V6 (_), Some (v6)) => v6,
+		(SocketAddr::V6 (_), None) => {
+			if ! outstanding.guest {
+				eprintln! ("Can't query {} without an IPv6 socket", dest);
+			}
+			return Ok (());
+		},
+	};
+	
+	for _ in 0..10 {
+		for msg in &outstanding.packets ()? {
+			if let Err (e) = socket.send_to (msg, dest).await {
+				if ! outstanding.guest {
+					eprintln! ("Error sending request to {}: {:?}", dest, e);
+				}
+			}
+		}
+		
+		sleep (Duration::from_millis (100)).await;
+	}
+	
+	Ok (())
+}
+
+// Turns a response (or announcement) into a peer, after checking its
+// signature against `idem_id` and its key against our pins. `options`
+// has the fleet's key and our nicknames.
+
+pub(crate) fn parse_peer (
+	packet: Packet,
+	remote_addr: SocketAddr,
+	idem_id: [u8; 8],
+	response_key: Option <&ResponseKey>,
+	options: &DiscoverOptions,
+	known_peers: &mut Option <KnownPeers>,
+) -> Option <Peer>
+{
+	let guest = options.guest;
+	
+	// In a private fleet, anything without the key's tag is from outside
+	// it, so it isn't even worth counting
+	if options.common.psk.as_ref ().is_some_and (|x| ! x.verify (idem_id, &packet)) {
+		return None;
+	}
+	
+	// If we asked for a sealed answer, a plain one is from a server that
+	// didn't understand, and it's already been overheard. Ignoring it at
+	// least tells the user something's wrong.
+	let packet = match response_key {
+		None => packet,
+		Some (key) => match key.open (idem_id, &packet) {
+			Some (x) => x,
+			None => {
+				if ! guest {
+					eprintln! ("Dropping response from {} that isn't sealed to us", remote_addr);
+				}
+				return None;
+			},
+		},
+	};
+	
+	// Anything that doesn't answer `idem_id` is stale, meant for some
+	// other request, or forged
+	if ! packet.msgs.iter ().any (|x| matches! (x, Message::Response2 (x) if x.idem_id == idem_id)) {
+		UNMATCHED_RESPONSES.fetch_add (1, Ordering::Relaxed);
+		return None;
+	}
+	
+	let public_key = match check_signature (idem_id, &packet) {
+		Ok (x) => x,
+		Err (()) => {
+			if ! guest {
+				eprintln! ("Dropping response from {} with a bad signature", remote_addr);
+			}
+			return None;
+		},
+	};
+	
+	let version = packet.version;
+	let mut resp = ServerResponse {
+		mac: None,
+		nickname: None,
+		services: vec! [],
+	};
+	
+	for msg in packet.msgs.into_iter () {
+		match msg {
+			Message::Response1 (x) => resp.mac = x,
+			Message::Response2 (x) => resp.nickname = Some (x.nickname),
+			Message::Services (x) => resp.services = x,
+			_ => (),
+		}
+	}
+	
+	let from_server = resp.nickname.as_deref ().is_some_and (|x| ! x.is_empty ());
+	let nickname = get_peer_nickname (&options.nicknames, resp.mac, resp.nickname);
+	let nickname_source = nickname.as_ref ().map (|_| if from_server {
+		NicknameSource::Server
+	}
+	else {
+		NicknameSource::ClientConfig
+	});
+	
+	if let (Some (known_peers), Some (nickname)) = (known_peers.as_mut (), &nickname) {
+		match (known_peers.check (nickname, public_key.as_ref ()), public_key) {
+			// Guests only check pins, so they never write anything
+			(Trust::New, Some (_)) if guest => (),
+			(Trust::New, Some (key)) => {
+				eprintln! ("Pinning new key {} for `{}`", identity::to_hex (&key), nickname);
+				if let Err (e) = known_peers.pin (nickname, key) {
+					eprintln! ("Couldn't save known peers: {:?}", e);
+				}
+			},
+			// v1 can't carry a signature, so a v1 answer from a pinned
+			// peer proves nothing either way. It's normally a copy of an
+			// answer that also came in v2.
+			(Trust::Mismatch, None) if version < message::VERSION_2 => return None,
+			(Trust::Mismatch, _) if guest => return None,
+			(Trust::Mismatch, _) => {
+				eprintln! ("WARNING: {} claims to be `{}`, but its key doesn't match the one pinned in known_peers.ini!", remote_addr, nickname);
+				eprintln! ("WARNING: It could be an impostor. Ignoring it. If `{}` really did get a new key, remove its old key from known_peers.ini", nickname);
+				return None;
+			},
+			_ => (),
+		}
+	}
+	
+	Some (Peer {
+		addr: remote_addr,
+		mac: resp.mac,
+		nickname,
+		nickname_source,
+		services: resp.services,
+		public_key,
+		rtt: None,
+	})
+}
+
+// Returns the signer's key if the response is validly signed, `None` if
+// it isn't signed at all, and `Err` if it's signed but the signature is bad
+
+// The signature only covers what's before it. The fleet's tag is the only
+// thing allowed after it, since anything else could be tacked on by anybody.
+
+fn check_signature (idem_id: [u8; 8], packet: &Packet) -> Result <Option <PublicKey>, ()> {
+	let msgs = &packet.msgs;
+	for (i, msg) in msgs.iter ().enumerate () {
+		if let Message::Signature { public_key, signature } = msg {
+			if ! matches! (&msgs [i + 1..], [] | [Message::Hmac (_)]) {
+				return Err (());
+			}
+			if identity::verify (idem_id, &msgs [..i], packet.version, public_key, signature) {
+				return Ok (Some (*public_key));
+			}
+			return Err (());
+		}
+	}
+	
+	Ok (None)
+}
+
 fn get_peer_nickname (
 	nicknames: &HashMap <String, String>,
 	mac: Option <[u8; 6]>,
@@ -297,4 +879,122 @@ mod test {
 			assert_eq! (actual.as_ref ().map (String::as_str), expected, "{}", num);
 		}
 	}
+	
+	#[test]
+	fn test_parse_peer () {
+		let remote_addr: SocketAddr = "192.168.1.101:9040".parse ().unwrap ();
+		let plain = DiscoverOptions::default ();
+		let private = |psk: &Psk| DiscoverOptions {
+			common: app_common::Params {
+				psk: Some (psk.clone ()),
+				..Default::default ()
+			},
+			..Default::default ()
+		};
+		let packet = |idem_id| Packet {
+			version: message::VERSION_2,
+			msgs: vec! [
+				Message::Response1 (Some ([1, 2, 3, 4, 5, 6])),
+				Message::Response2 (message::Response2 {
+					idem_id,
+					nickname: "laptop".to_string (),
+				}),
+			],
+		};
+		
+		let peer = parse_peer (packet ([1; 8]), remote_addr, [1; 8], None, &plain, &mut None).unwrap ();
+		assert_eq! (peer.nickname.as_deref (), Some ("laptop"));
+		
+		// A late answer to some other request
+		let before = unmatched_responses ();
+		assert_eq! (parse_peer (packet ([2; 8]), remote_addr, [1; 8], None, &plain, &mut None), None);
+		assert! (unmatched_responses () > before);
+		
+		// And one that doesn't say what it's answering at all
+		let mut untagged = packet ([1; 8]);
+		untagged.msgs.pop ();
+		assert_eq! (parse_peer (untagged, remote_addr, [1; 8], None, &plain, &mut None), None);
+		
+		// In a private fleet, only tagged answers count
+		let psk = Psk::new (b"correct horse battery staple");
+		let mut tagged = packet ([1; 8]);
+		tagged.msgs.push (psk.tag ([1; 8], &tagged.msgs, message::VERSION_2).unwrap ());
+		assert! (parse_peer (tagged.clone (), remote_addr, [1; 8], None, &private (&psk), &mut None).is_some ());
+		assert_eq! (parse_peer (packet ([1; 8]), remote_addr, [1; 8], None, &private (&psk), &mut None), None);
+		assert_eq! (parse_peer (tagged, remote_addr, [1; 8], None, &private (&Psk::new (b"hunter2")), &mut None), None);
+		
+		// If we asked for a sealed answer, only a sealed one counts
+		let key = ResponseKey::default ();
+		let recipient = match key.message () {
+			Message::ResponseKey (x) => x,
+			_ => unreachable! (),
+		};
+		let sealed = Packet {
+			version: message::VERSION_2,
+			msgs: vec! [
+				crate::sealed::seal (&recipient, [1; 8], &packet ([1; 8]).msgs, message::VERSION_2).unwrap (),
+			],
+		};
+		let peer = parse_peer (sealed, remote_addr, [1; 8], Some (&key), &plain, &mut None).unwrap ();
+		assert_eq! (peer.nickname.as_deref (), Some ("laptop"));
+		assert_eq! (parse_peer (packet ([1; 8]), remote_addr, [1; 8], Some (&key), &plain, &mut None), None);
+		
+		// Signed, with or without the fleet's tag after
+		let identity = crate::identity::Identity::from_seed ([1; 32]);
+		let mut signed = packet ([1; 8]);
+		signed.msgs.push (identity.sign ([1; 8], &signed.msgs, message::VERSION_2).unwrap ());
+		assert! (parse_peer (signed.clone (), remote_addr, [1; 8], None, &plain, &mut None).is_some ());
+		let mut tagged = signed.clone ();
+		tagged.msgs.push (psk.tag ([1; 8], &tagged.msgs, message::VERSION_2).unwrap ());
+		assert! (parse_peer (tagged, remote_addr, [1; 8], None, &private (&psk), &mut None).is_some ());
+		
+		// But nothing else can ride along after a good signature
+		for extra in [
+			Message::Response2 (message::Response2 {
+				idem_id: [1; 8],
+				nickname: "impostor".to_string (),
+			}),
+			Message::Services (vec! [Service::parse ("ssh", "tcp/2222").unwrap ()]),
+		] {
+			let mut appended = signed.clone ();
+			appended.msgs.push (extra);
+			assert_eq! (parse_peer (appended, remote_addr, [1; 8], None, &plain, &mut None), None);
+		}
+	}
+	
+	#[test]
+	fn test_guest () {
+		let remote_addr: SocketAddr = "192.168.1.101:9040".parse ().unwrap ();
+		let mut signed = Packet {
+			version: message::VERSION_2,
+			msgs: vec! [
+				Message::Response1 (Some ([1, 2, 3, 4, 5, 6])),
+				Message::Response2 (message::Response2 {
+					idem_id: [1; 8],
+					nickname: "laptop".to_string (),
+				}),
+			],
+		};
+		signed.msgs.push (crate::identity::Identity::from_seed ([1; 32]).sign ([1; 8], &signed.msgs, message::VERSION_2).unwrap ());
+		
+		let dir = std::env::temp_dir ().join (format! ("lookaround-test-{}", rand::thread_rng ().next_u64 ()));
+		let guest = DiscoverOptions {
+			known_peers: Some (dir.join ("known_peers.ini")),
+			guest: true,
+			..Default::default ()
+		};
+		
+		// A guest takes new keys on trust, but doesn't pin them
+		let mut known_peers = guest.load_known_peers ();
+		assert! (parse_peer (signed.clone (), remote_addr, [1; 8], None, &guest, &mut known_peers).is_some ());
+		assert! (! dir.exists ());
+		
+		// And still turns away impostors
+		std::fs::create_dir_all (&dir).unwrap ();
+		std::fs::write (dir.join ("known_peers.ini"), format! ("[keys]\nlaptop = {}\n", "22".repeat (32))).unwrap ();
+		let mut known_peers = guest.load_known_peers ();
+		assert_eq! (parse_peer (signed, remote_addr, [1; 8], None, &guest, &mut known_peers), None);
+		
+		std::fs::remove_dir_all (&dir).unwrap ();
+	}
 }
@@ -0,0 +1,187 @@
+// Connecting to a peer's TCP port by nickname, for `connect`. Peers move
+// between addresses, like from WiFi to Ethernet or to a new DHCP lease, so
+// the last address that worked is only a first guess. If it doesn't
+// answer, we ask the LAN again instead of failing.
+
+use tokio::net::TcpStream;
+
+use crate::{
+	client::discover,
+	prelude::*,
+};
+
+/// How long to wait for each address. Dead addresses on a LAN usually
+/// fail fast, but not always.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs (2);
+
+/// The last address that worked for each nickname, stored in
+/// last_addrs.ini, so most connections can skip discovery
+pub struct LastAddrs {
+	path: PathBuf,
+	addrs: HashMap <String, SocketAddr>,
+}
+
+impl LastAddrs {
+	pub fn load (path: PathBuf) -> Self {
+		let mut addrs = HashMap::default ();
+		
+		let mut ini = Ini::new_cs ();
+		if ini.load (&path).is_ok () {
+			if let Some (x) = ini.get_map_ref ().get ("addrs") {
+				for (k, v) in x {
+					match v.as_deref ().map (SocketAddr::from_str) {
+						Some (Ok (v)) => {
+							addrs.insert (k.to_string (), v);
+						},
+						_ => eprintln! ("Ignoring bad address for `{}` in {:?}", k, path),
+					}
+				}
+			}
+		}
+		
+		Self {
+			path,
+			addrs,
+		}
+	}
+	
+	pub fn get (&self, nick: &str) -> Option <SocketAddr> {
+		self.addrs.get (nick).copied ()
+	}
+	
+	/// Remembers `addr` and saves the file right away
+	pub fn set (&mut self, nick: &str, addr: SocketAddr) -> Result <(), std::io::Error> {
+		self.addrs.insert (nick.to_string (), addr);
+		
+		let mut ini = Ini::new_cs ();
+		for (k, v) in &self.addrs {
+			ini.set ("addrs", k, Some (v.to_string ()));
+		}
+		
+		if let Some (dir) = self.path.parent () {
+			std::fs::create_dir_all (dir)?;
+		}
+		ini.write (&self.path)
+	}
+}
+
+/// Connects to `port` on the peer called `nick`. Tries `last_known`
+/// first, since asking the LAN takes a while, then every address the
+/// peer answers from, IPv4 first.
+pub async fn connect_nick (
+	options: DiscoverOptions,
+	nick: &str,
+	port: u16,
+	last_known: Option <SocketAddr>,
+) -> Result <TcpStream, AppError>
+{
+	let last_known = last_known.map (|mut x| {
+		x.set_port (port);
+		x
+	});
+	
+	if let Some (addr) = last_known {
+		match try_connect (addr).await {
+			Ok (x) => return Ok (x),
+			Err (e) => eprintln! ("Can't reach `{}` at {} anymore ({}), looking for it again", nick, addr, e),
+		}
+	}
+	
+	let mut candidates: Vec <SocketAddr> = discover (options).await?
+	.filter (|x| x.nickname.as_deref () == Some (nick))
+	.map (|x| x.addr)
+	.collect ().await;
+	
+	candidates.sort_by_key (|x| x.is_ipv6 ());
+	candidates.dedup ();
+	
+	let mut last_err = None;
+	for mut addr in candidates {
+		addr.set_port (port);
+		if Some (addr) == last_known {
+			continue;
+		}
+		
+		match try_connect (addr).await {
+			Ok (x) => return Ok (x),
+			Err (e) => last_err = Some (e),
+		}
+	}
+	
+	Err (match last_err {
+		Some (e) => e.into (),
+		None => AppError::NickNotFound (nick.to_string ()),
+	})
+}
+
+async fn try_connect (addr: SocketAddr) -> Result <TcpStream, std::io::Error> {
+	timeout (CONNECT_TIMEOUT, TcpStream::connect (addr)).await
+	.unwrap_or_else (|_| Err (std::io::ErrorKind::TimedOut.into ()))
+}
+
+#[cfg (test)]
+mod test {
+	use tokio::net::TcpListener;
+	
+	use super::*;
+	
+	// Nobody else is on this port and group, so discovery finds nothing
+	
+	fn lonely_options () -> DiscoverOptions {
+		DiscoverOptions {
+			common: app_common::Params {
+				server_port: 19041,
+				multicast_addr: Ipv4Addr::new (225, 100, 99, 96),
+				multicast_addr_v6: Ipv6Addr::new (0xff02, 0, 0, 0, 0, 0, 0xe164, 0x6360),
+				psk: None,
+				encrypt: false,
+			},
+			timeout: Duration::from_millis (100),
+			..Default::default ()
+		}
+	}
+	
+	#[tokio::test]
+	async fn test_connect_nick () -> Result <(), AppError> {
+		let listener = TcpListener::bind ((Ipv4Addr::LOCALHOST, 0)).await?;
+		let port = listener.local_addr ()?.port ();
+		
+		// The last known address works, so no discovery needed. Only
+		// its IP matters, the port is always the one asked for.
+		let last_known = Some ((Ipv4Addr::LOCALHOST, 1).into ());
+		let stream = connect_nick (lonely_options (), "laptop", port, last_known).await?;
+		assert_eq! (stream.peer_addr ()?.port (), port);
+		
+		// Once it stops working, we look again, and give up if the peer
+		// is gone
+		drop (stream);
+		drop (listener);
+		assert! (matches! (
+			connect_nick (lonely_options (), "laptop", port, last_known).await,
+			Err (AppError::NickNotFound (_))
+		));
+		
+		Ok (())
+	}
+	
+	#[test]
+	fn test_last_addrs () -> Result <(), std::io::Error> {
+		let path = env::temp_dir ().join (format! ("lookaround-test-{}", rand::thread_rng ().next_u64 ())).join ("last_addrs.ini");
+		
+		let mut last = LastAddrs::load (path.clone ());
+		assert_eq! (last.get ("laptop"), None);
+		
+		for (nick, addr) in [
+			("laptop", "192.168.1.101:22"),
+			("Desktop", "[fe80::1%2]:22"),
+		] {
+			last.set (nick, addr.parse ().unwrap ())?;
+		}
+		
+		let last = LastAddrs::load (path.clone ());
+		assert_eq! (last.get ("laptop"), Some ("192.168.1.101:22".parse ().unwrap ()));
+		assert_eq! (last.get ("Desktop"), Some ("[fe80::1%2]:22".parse ().unwrap ()));
+		
+		std::fs::remove_dir_all (path.parent ().unwrap ())
+	}
+}
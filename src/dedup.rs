@@ -0,0 +1,217 @@
+// Remembers which requests and announcements we've already handled, by
+// idem_id and source. Clients and servers send everything a few times in
+// case of packet loss, so each copy after the first should be ignored.
+//
+// Entries expire after a fixed window instead of after some number of
+// newer entries, so a busy LAN can't push a request out before its
+// retransmits are done, and a quiet one doesn't remember it forever.
+// We also remember when each one was last handled, since a server answers
+// a retransmit again if enough time has passed, in case its first answer
+// was lost.
+
+use std::collections::VecDeque;
+
+use crate::prelude::*;
+
+/// Longer than any sender keeps retransmitting
+pub const DEDUP_WINDOW: Duration = Duration::from_secs (5);
+
+// Enough for a few hundred clients each asking every few seconds. Past
+// this, the oldest entries go first, even if they haven't expired.
+
+const MAX_ENTRIES: usize = 4096;
+
+type Key = ([u8; 8], SocketAddr);
+
+pub struct IdemCache {
+	window: Duration,
+	max_entries: usize,
+	
+	// When each key was last handled, and the sequence number of its
+	// newest entry in `order`
+	seen: HashMap <Key, (Instant, u64)>,
+	
+	// Oldest first, so expiring only ever looks at the front. A key
+	// handled again gets a second entry, and only its newest one
+	// expires it.
+	order: VecDeque <(Key, Instant, u64)>,
+	next_seq: u64,
+}
+
+impl Default for IdemCache {
+	fn default () -> Self {
+		Self::new (DEDUP_WINDOW, MAX_ENTRIES)
+	}
+}
+
+impl IdemCache {
+	pub fn new (window: Duration, max_entries: usize) -> Self {
+		Self {
+			window,
+			max_entries,
+			seen: Default::default (),
+			order: Default::default (),
+			next_seq: 0,
+		}
+	}
+	
+	/// How long ago we last handled `idem_id` from `source`, if it was
+	/// within the window
+	pub fn since (&mut self, idem_id: [u8; 8], source: SocketAddr, now: Instant) -> Option <Duration> {
+		self.expire (now);
+		self.seen.get (&(idem_id, source)).map (|(t, _)| now.saturating_duration_since (*t))
+	}
+	
+	/// Remembers handling `idem_id` from `source` at `now`. Returns false
+	/// if we'd already handled it within the window.
+	pub fn insert (&mut self, idem_id: [u8; 8], source: SocketAddr, now: Instant) -> bool {
+		self.expire (now);
+		
+		let key = (idem_id, source);
+		let seq = self.next_seq;
+		self.next_seq += 1;
+		let is_new = self.seen.insert (key, (now, seq)).is_none ();
+		
+		self.order.push_back ((key, now, seq));
+		while self.order.len () > self.max_entries {
+			self.pop_front ();
+		}
+		
+		is_new
+	}
+	
+	fn expire (&mut self, now: Instant) {
+		while let Some ((_, t, _)) = self.order.front () {
+			if now.saturating_duration_since (*t) < self.window {
+				break;
+			}
+			self.pop_front ();
+		}
+	}
+	
+	fn pop_front (&mut self) {
+		if let Some ((key, _, seq)) = self.order.pop_front () {
+			if self.seen.get (&key).is_some_and (|x| x.1 == seq) {
+				self.seen.remove (&key);
+			}
+		}
+	}
+}
+
+/// Which source each idem_id first came from. In a private fleet, the
+/// same request from anywhere else is a replay.
+pub struct FirstSources {
+	window: Duration,
+	max_entries: usize,
+	sources: HashMap <[u8; 8], SocketAddr>,
+	
+	// Oldest first. Each idem_id is only in here once, since it's only
+	// ever inserted once.
+	order: VecDeque <([u8; 8], Instant)>,
+}
+
+impl Default for FirstSources {
+	fn default () -> Self {
+		Self::new (psk::MAX_SKEW * 2, MAX_ENTRIES)
+	}
+}
+
+impl FirstSources {
+	pub fn new (window: Duration, max_entries: usize) -> Self {
+		Self {
+			window,
+			max_entries,
+			sources: Default::default (),
+			order: Default::default (),
+		}
+	}
+	
+	/// Remembers `source` as the first to send `idem_id`, unless somebody
+	/// else sent it within the window. Returns false if they did.
+	pub fn claim (&mut self, idem_id: [u8; 8], source: SocketAddr, now: Instant) -> bool {
+		while let Some ((id, t)) = self.order.front () {
+			if now.saturating_duration_since (*t) < self.window && self.order.len () < self.max_entries {
+				break;
+			}
+			self.sources.remove (id);
+			self.order.pop_front ();
+		}
+		
+		match self.sources.get (&idem_id) {
+			Some (x) => *x == source,
+			None => {
+				self.sources.insert (idem_id, source);
+				self.order.push_back ((idem_id, now));
+				true
+			},
+		}
+	}
+}
+
+#[cfg (test)]
+mod test {
+	use super::*;
+	
+	#[test]
+	fn test_idem_cache () {
+		let a: SocketAddr = "192.168.1.101:9040".parse ().unwrap ();
+		let b: SocketAddr = "192.168.1.102:9040".parse ().unwrap ();
+		let start = Instant::now ();
+		let at = |ms| start + Duration::from_millis (ms);
+		let mut cache = IdemCache::new (Duration::from_secs (5), 3);
+		
+		for (ms, id, source, expected) in [
+			(0, 1, a, true),
+			// Retransmits are caught
+			(100, 1, a, false),
+			(4900, 1, b, true),
+			// Each time resets the window
+			(5000, 1, a, false),
+			(9900, 1, a, false),
+			// The same ID from somebody else is a different request
+			(9900, 2, b, true),
+			// After the window, it counts as new again
+			(14900, 1, b, true),
+			(14900, 2, a, true),
+			// Too many, so the oldest goes early
+			(14900, 3, a, true),
+			(14900, 1, b, false),
+			(14900, 4, a, true),
+			(14900, 2, a, true),
+		] {
+			let idem_id = [id; 8];
+			let since = cache.since (idem_id, source, at (ms));
+			assert_eq! (since.is_none (), expected, "{} {}", ms, id);
+			assert_eq! (cache.insert (idem_id, source, at (ms)), expected, "{} {}", ms, id);
+		}
+		
+		assert_eq! (cache.since ([4; 8], a, at (15000)), Some (Duration::from_millis (100)));
+	}	
+	#[test]
+	fn test_first_sources () {
+		let a: SocketAddr = "192.168.1.101:9040".parse ().unwrap ();
+		let b: SocketAddr = "192.168.1.102:9040".parse ().unwrap ();
+		let start = Instant::now ();
+		let at = |s| start + Duration::from_secs (s);
+		let mut sources = FirstSources::new (Duration::from_secs (60), 3);
+		
+		for (s, id, source, expected) in [
+			(0, 1, a, true),
+			// Retransmits from the same place are fine
+			(1, 1, a, true),
+			// The same request from somewhere else is a replay
+			(2, 1, b, false),
+			(59, 1, b, false),
+			(59, 2, b, true),
+			// After the window, anybody can have it
+			(60, 1, b, true),
+			(61, 1, a, false),
+			// Too many, so the oldest goes early
+			(61, 3, a, true),
+			(61, 4, a, true),
+			(61, 2, a, true),
+		] {
+			assert_eq! (sources.claim ([id; 8], source, at (s)), expected, "{} {}", s, id);
+		}
+	}
+}
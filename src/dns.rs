@@ -0,0 +1,249 @@
+// The bits of the DNS wire format (RFC 1035) that the mDNS responder and
+// the stub resolver share. Only A and AAAA, since that's all we answer.
+
+use crate::prelude::*;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_ANY: u16 = 255;
+pub const CLASS_IN: u16 = 1;
+
+/// In questions, mDNS uses the top bit of the class to ask for a unicast
+/// response. In answers, it means "flush your cache".
+pub const CLASS_TOP_BIT: u16 = 0x8000;
+
+// Header flags
+
+pub const FLAG_RESPONSE: u16 = 0x8000;
+pub const FLAG_AUTHORITATIVE: u16 = 0x0400;
+pub const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+pub const FLAG_RECURSION_AVAILABLE: u16 = 0x0080;
+
+pub const RCODE_SERVFAIL: u16 = 2;
+pub const RCODE_NXDOMAIN: u16 = 3;
+
+#[derive (Debug, thiserror::Error)]
+pub enum DnsError {
+	#[error ("Packet ended early")]
+	Truncated,
+	#[error ("Name compression pointer loops")]
+	PointerLoop,
+}
+
+#[derive (Clone, Debug, PartialEq)]
+pub struct Question {
+	pub name: String,
+	pub qtype: u16,
+	pub unicast_response: bool,
+}
+
+#[derive (Debug, PartialEq)]
+pub struct Query {
+	pub id: u16,
+	pub flags: u16,
+	pub questions: Vec <Question>,
+}
+
+#[derive (Debug, PartialEq)]
+pub struct Answer {
+	pub name: String,
+	pub rtype: u16,
+	pub class: u16,
+	pub ttl: u32,
+	pub rdata: Vec <u8>,
+}
+
+impl Answer {
+	/// An A or AAAA record, depending on `addr`
+	pub fn addr (name: &str, addr: IpAddr, class: u16, ttl: u32) -> Self {
+		let (rtype, rdata) = match addr {
+			IpAddr::V4 (x) => (TYPE_A, x.octets ().to_vec ()),
+			IpAddr::V6 (x) => (TYPE_AAAA, x.octets ().to_vec ()),
+		};
+		
+		Self {
+			name: name.to_string (),
+			rtype,
+			class,
+			ttl,
+			rdata,
+		}
+	}
+}
+
+impl Question {
+	/// True if an answer of type `rtype` answers this question
+	pub fn wants (&self, rtype: u16) -> bool {
+		self.qtype == rtype || self.qtype == TYPE_ANY
+	}
+}
+
+/// Parses a query. Returns `None` for responses and anything else that
+/// isn't a standard query, since those aren't for us.
+pub fn parse_query (buf: &[u8]) -> Result <Option <Query>, DnsError> {
+	let id = read_u16 (buf, 0)?;
+	let flags = read_u16 (buf, 2)?;
+	let qdcount = read_u16 (buf, 4)?;
+	
+	// QR bit set means it's a response, and nonzero opcodes aren't queries
+	if flags & 0xf800 != 0 {
+		return Ok (None);
+	}
+	
+	let mut pos = 12;
+	let mut questions = vec! [];
+	for _ in 0..qdcount {
+		let (name, next) = read_name (buf, pos)?;
+		let qtype = read_u16 (buf, next)?;
+		let qclass = read_u16 (buf, next + 2)?;
+		pos = next + 4;
+		
+		if qclass & !CLASS_TOP_BIT != CLASS_IN {
+			continue;
+		}
+		
+		questions.push (Question {
+			name,
+			qtype,
+			unicast_response: qclass & CLASS_TOP_BIT != 0,
+		});
+	}
+	
+	Ok (Some (Query {
+		id,
+		flags,
+		questions,
+	}))
+}
+
+/// `flags` should include `FLAG_RESPONSE` and the rcode
+pub fn encode_response (id: u16, flags: u16, questions: &[&Question], answers: &[Answer]) -> Vec <u8> {
+	let mut buf = vec! [];
+	buf.extend_from_slice (&id.to_be_bytes ());
+	buf.extend_from_slice (&flags.to_be_bytes ());
+	buf.extend_from_slice (&(questions.len () as u16).to_be_bytes ());
+	buf.extend_from_slice (&(answers.len () as u16).to_be_bytes ());
+	buf.extend_from_slice (&[0, 0, 0, 0]);
+	
+	for q in questions {
+		write_name (&mut buf, &q.name);
+		buf.extend_from_slice (&q.qtype.to_be_bytes ());
+		buf.extend_from_slice (&CLASS_IN.to_be_bytes ());
+	}
+	
+	for a in answers {
+		write_name (&mut buf, &a.name);
+		buf.extend_from_slice (&a.rtype.to_be_bytes ());
+		buf.extend_from_slice (&a.class.to_be_bytes ());
+		buf.extend_from_slice (&a.ttl.to_be_bytes ());
+		buf.extend_from_slice (&(a.rdata.len () as u16).to_be_bytes ());
+		buf.extend_from_slice (&a.rdata);
+	}
+	
+	buf
+}
+
+pub(crate) fn read_u16 (buf: &[u8], pos: usize) -> Result <u16, DnsError> {
+	let b = buf.get (pos..pos + 2).ok_or (DnsError::Truncated)?;
+	Ok (u16::from_be_bytes ([b [0], b [1]]))
+}
+
+// Returns the name, and the position just after it in the original packet
+
+fn read_name (buf: &[u8], mut pos: usize) -> Result <(String, usize), DnsError> {
+	let mut labels: Vec <String> = vec! [];
+	let mut end = None;
+	
+	// Every pointer has to go backwards, so this many jumps means a loop
+	for _ in 0..buf.len () {
+		let len = *buf.get (pos).ok_or (DnsError::Truncated)? as usize;
+		
+		if len == 0 {
+			return Ok ((labels.join ("."), end.unwrap_or (pos + 1)));
+		}
+		
+		if len & 0xc0 == 0xc0 {
+			let target = (read_u16 (buf, pos)? & 0x3fff) as usize;
+			if target >= pos {
+				return Err (DnsError::PointerLoop);
+			}
+			end.get_or_insert (pos + 2);
+			pos = target;
+			continue;
+		}
+		
+		let label = buf.get (pos + 1..pos + 1 + len).ok_or (DnsError::Truncated)?;
+		labels.push (String::from_utf8_lossy (label).into_owned ());
+		pos += 1 + len;
+	}
+	
+	Err (DnsError::PointerLoop)
+}
+
+pub(crate) fn write_name (buf: &mut Vec <u8>, name: &str) {
+	for label in name.split ('.').filter (|x| ! x.is_empty ()) {
+		let label = &label.as_bytes () [..label.len ().min (63)];
+		buf.push (label.len () as u8);
+		buf.extend_from_slice (label);
+	}
+	buf.push (0);
+}
+
+#[cfg (test)]
+pub(crate) mod test {
+	use super::*;
+	
+	/// A query for one A record, like `avahi-resolve -4 -n laptop.local` sends
+	pub(crate) fn example_query (name: &str) -> Vec <u8> {
+		let mut buf = vec! [
+			0, 0, 0, 0,
+			0, 1, 0, 0,
+			0, 0, 0, 0,
+		];
+		write_name (&mut buf, name);
+		buf.extend_from_slice (&[0, 1, 0, 1]);
+		buf
+	}
+	
+	#[test]
+	fn test_parse_query () -> Result <(), DnsError> {
+		assert_eq! (parse_query (&example_query ("laptop.local"))?, Some (Query {
+			id: 0,
+			flags: 0,
+			questions: vec! [
+				Question {
+					name: "laptop.local".to_string (),
+					qtype: TYPE_A,
+					unicast_response: false,
+				},
+			],
+		}));
+		
+		// Second question compressed to point at the first one's name,
+		// asking for AAAA with a unicast response
+		let mut buf = example_query ("laptop.local");
+		buf [5] = 2;
+		buf.extend_from_slice (&[0xc0, 12, 0, 28, 0x80, 1]);
+		let query = parse_query (&buf)?.unwrap ();
+		assert_eq! (query.questions [1], Question {
+			name: "laptop.local".to_string (),
+			qtype: TYPE_AAAA,
+			unicast_response: true,
+		});
+		
+		// Responses are ignored
+		let mut buf = example_query ("laptop.local");
+		buf [2] = 0x84;
+		assert_eq! (parse_query (&buf)?, None);
+		
+		// Bad packets are errors, not panics
+		for buf in [
+			&example_query ("laptop.local") [..14],
+			&[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xc0, 12, 0, 1, 0, 1][..],
+		] {
+			assert! (parse_query (buf).is_err ());
+		}
+		
+		Ok (())
+	}
+}
@@ -0,0 +1,272 @@
+// Keeps a block of peers in a hosts file, for tools that only know how to
+// read /etc/hosts. Everything outside our markers is left alone.
+
+use std::path::Path;
+
+use crate::prelude::*;
+
+pub const BEGIN_MARKER: &str = "# BEGIN lookaround";
+pub const END_MARKER: &str = "# END lookaround";
+
+/// Every name goes under this, like the DNS resolver's names
+pub const SUFFIX: &str = "lookaround";
+
+#[derive (Debug, thiserror::Error)]
+pub enum HostsError {
+	#[error ("Found `# BEGIN lookaround` without `# END lookaround` after it, fix the hosts file by hand")]
+	UnterminatedBlock,
+	#[error (transparent)]
+	Io (#[from] std::io::Error),
+}
+
+#[derive (Clone, Debug, PartialEq)]
+pub struct HostsEntry {
+	pub ip: IpAddr,
+	pub names: Vec <String>,
+}
+
+/// One entry per usable address, with names like `laptop.lookaround`. The
+/// peer's own nickname comes first, and if client.ini calls its MAC
+/// something else, that's added as an alias.
+pub fn entries <'a, I: IntoIterator <Item = &'a Peer>> (
+	peers: I,
+	nicknames: &HashMap <String, String>,
+) -> Vec <HostsEntry>
+{
+	let mut entries: Vec <HostsEntry> = vec! [];
+	
+	for peer in peers {
+		// A hosts file has nowhere to put a scope ID
+		if let SocketAddr::V6 (x) = peer.addr {
+			if x.ip ().segments () [0] & 0xffc0 == 0xfe80 {
+				continue;
+			}
+		}
+		
+		let alias = peer.mac.and_then (|x| nicknames.get (&MacAddress::new (x).to_string ()));
+		let names: Vec <String> = peer.nickname.iter ().chain (alias)
+		.filter (|x| is_valid_name (x))
+		.map (|x| format! ("{}.{}", x, SUFFIX))
+		.fold (vec! [], |mut v, x| {
+			if ! v.contains (&x) {
+				v.push (x);
+			}
+			v
+		});
+		
+		if names.is_empty () {
+			continue;
+		}
+		
+		let ip = peer.addr.ip ();
+		match entries.iter_mut ().find (|x| x.ip == ip) {
+			Some (entry) => for name in names {
+				if ! entry.names.contains (&name) {
+					entry.names.push (name);
+				}
+			},
+			None => entries.push (HostsEntry {
+				ip,
+				names,
+			}),
+		}
+	}
+	
+	entries.sort_by (|a, b| (&a.names [0], a.ip.is_ipv6 (), a.ip).cmp (&(&b.names [0], b.ip.is_ipv6 (), b.ip)));
+	entries
+}
+
+// Any peer on the LAN can claim any nickname, and the hosts file is for
+// the whole machine, so only a plain DNS label gets in. Otherwise a peer
+// calling itself `github.com` could take over that name. The suffix keeps
+// even `localhost` harmless, but it would only confuse people.
+
+fn is_valid_name (name: &str) -> bool {
+	(1..=63).contains (&name.len ()) &&
+	name.bytes ().all (|c| c.is_ascii_alphanumeric () || c == b'-') &&
+	! name.starts_with ('-') &&
+	! name.ends_with ('-') &&
+	! name.eq_ignore_ascii_case ("localhost")
+}
+
+/// The whole block, markers included
+pub fn render_block (entries: &[HostsEntry]) -> String {
+	let mut s = String::new ();
+	s.push_str (BEGIN_MARKER);
+	s.push ('\n');
+	for entry in entries {
+		s.push_str (&format! ("{}\t{}\n", entry.ip, entry.names.join (" ")));
+	}
+	s.push_str (END_MARKER);
+	s.push ('\n');
+	s
+}
+
+/// Swaps our block in `existing` for `block`, or appends `block` if
+/// there isn't one yet
+pub fn replace_block (existing: &str, block: &str) -> Result <String, HostsError> {
+	let lines: Vec <&str> = existing.split_inclusive ('\n').collect ();
+	let begin = lines.iter ().position (|x| x.trim_end () == BEGIN_MARKER);
+	
+	let (before, after) = match begin {
+		None => (lines.as_slice (), &[][..]),
+		Some (begin) => {
+			let end = lines [begin..].iter ().position (|x| x.trim_end () == END_MARKER)
+			.ok_or (HostsError::UnterminatedBlock)? + begin;
+			(&lines [..begin], &lines [end + 1..])
+		},
+	};
+	
+	let mut s: String = before.concat ();
+	if ! s.is_empty () && ! s.ends_with ('\n') {
+		s.push ('\n');
+	}
+	s.push_str (block);
+	s.push_str (&after.concat ());
+	Ok (s)
+}
+
+/// Lines only in `old` get `-`, lines only in `new` get `+`. Good enough
+/// for a block that only ever changes in one place.
+pub fn diff (old: &str, new: &str) -> String {
+	let old: Vec <&str> = old.lines ().collect ();
+	let new: Vec <&str> = new.lines ().collect ();
+	let mut s = String::new ();
+	
+	for line in &old {
+		if ! new.contains (line) {
+			s.push_str (&format! ("-{}\n", line));
+		}
+	}
+	for line in &new {
+		if ! old.contains (line) {
+			s.push_str (&format! ("+{}\n", line));
+		}
+	}
+	
+	s
+}
+
+/// Writes a temp file next to `path` and renames it over `path`, so
+/// nothing ever sees a half-written hosts file
+pub fn write_atomic (path: &Path, contents: &str) -> Result <(), HostsError> {
+	let mut tmp_name = path.file_name ().unwrap_or_default ().to_os_string ();
+	tmp_name.push (".lookaround-tmp");
+	let tmp_path = path.with_file_name (tmp_name);
+	
+	{
+		let mut f = std::fs::File::create (&tmp_path)?;
+		f.write_all (contents.as_bytes ())?;
+		f.sync_all ()?;
+	}
+	
+	// Keep the original's permissions, /etc/hosts has to stay world-readable
+	if let Ok (metadata) = std::fs::metadata (path) {
+		std::fs::set_permissions (&tmp_path, metadata.permissions ())?;
+	}
+	
+	std::fs::rename (&tmp_path, path)?;
+	Ok (())
+}
+
+#[cfg (test)]
+mod test {
+	use super::*;
+	
+	fn peer (mac: u8, addr: &str, nick: Option <&str>) -> Peer {
+		Peer {
+			addr: addr.parse ().unwrap (),
+			mac: Some ([mac; 6]),
+			nickname: nick.map (str::to_string),
+			nickname_source: None,
+			services: vec! [],
+			public_key: None,
+			rtt: None,
+		}
+	}
+	
+	#[test]
+	fn test_entries () {
+		let mut nicknames = HashMap::new ();
+		nicknames.insert ("02:02:02:02:02:02".to_string (), "nas".to_string ());
+		nicknames.insert ("03:03:03:03:03:03".to_string (), "printer".to_string ());
+		
+		let peers = [
+			peer (1, "192.168.1.101:9040", Some ("laptop")),
+			peer (1, "[fe80::1%2]:9040", Some ("laptop")),
+			peer (1, "[fd00::1]:9040", Some ("laptop")),
+			peer (2, "192.168.1.102:9040", Some ("storage")),
+			peer (3, "192.168.1.103:9040", Some ("printer")),
+			peer (4, "192.168.1.104:9040", Some ("bad name")),
+			peer (5, "192.168.1.105:9040", None),
+			peer (6, "192.168.1.106:9040", Some ("github.com")),
+			peer (7, "192.168.1.107:9040", Some ("localhost")),
+		];
+		let ip = |x: &str| x.parse ().unwrap ();
+		let names = |x: &[&str]| x.iter ().map (|x| x.to_string ()).collect ();
+		
+		assert_eq! (entries (&peers, &nicknames), vec! [
+			HostsEntry { ip: ip ("192.168.1.101"), names: names (&["laptop.lookaround"]) },
+			HostsEntry { ip: ip ("fd00::1"), names: names (&["laptop.lookaround"]) },
+			HostsEntry { ip: ip ("192.168.1.103"), names: names (&["printer.lookaround"]) },
+			HostsEntry { ip: ip ("192.168.1.102"), names: names (&["storage.lookaround", "nas.lookaround"]) },
+		]);
+	}
+	
+	#[test]
+	fn test_is_valid_name () {
+		for (input, expected) in [
+			("laptop", true),
+			("build-01", true),
+			("NAS", true),
+			("", false),
+			("bad name", false),
+			("a#b", false),
+			// Other people's names
+			("github.com", false),
+			("pypi.org", false),
+			("laptop.lookaround", false),
+			("localhost", false),
+			("LocalHost", false),
+			("-laptop", false),
+			("laptop-", false),
+			("caf\u{e9}", false),
+		] {
+			assert_eq! (is_valid_name (input), expected, "{:?}", input);
+		}
+		assert! (! is_valid_name (&"a".repeat (64)));
+	}
+	
+	#[test]
+	fn test_replace_block () -> Result <(), HostsError> {
+		let block = "# BEGIN lookaround\n192.168.1.101\tlaptop\n# END lookaround\n";
+		
+		for (input, expected) in [
+			// Appended if there's no block yet, even without a final newline
+			("", block.to_string ()),
+			("127.0.0.1\tlocalhost", format! ("127.0.0.1\tlocalhost\n{}", block)),
+			// Replaced in place, with everything around it kept
+			(
+				"127.0.0.1\tlocalhost\n# BEGIN lookaround\n10.0.0.1\told\n# END lookaround\n::1\tlocalhost\n",
+				format! ("127.0.0.1\tlocalhost\n{}::1\tlocalhost\n", block),
+			),
+		] {
+			assert_eq! (replace_block (input, block)?, expected, "{:?}", input);
+		}
+		
+		assert! (matches! (
+			replace_block ("# BEGIN lookaround\n10.0.0.1\told\n", block),
+			Err (HostsError::UnterminatedBlock)
+		));
+		
+		Ok (())
+	}
+	
+	#[test]
+	fn test_diff () {
+		let old = "# BEGIN lookaround\n10.0.0.1\tlaptop\n10.0.0.2\tnas\n# END lookaround\n";
+		let new = "# BEGIN lookaround\n10.0.0.5\tlaptop\n10.0.0.2\tnas\n# END lookaround\n";
+		assert_eq! (diff (old, new), "-10.0.0.1\tlaptop\n+10.0.0.5\tlaptop\n");
+		assert_eq! (diff (old, old), "");
+	}
+}
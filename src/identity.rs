@@ -0,0 +1,274 @@
+// Trust-on-first-use identities. Each server keeps an Ed25519 key in its
+// config dir and signs its responses. Clients pin the first key they see
+// for each nickname in known_peers.ini, and refuse any other key after that.
+
+use std::path::{
+	Path,
+	PathBuf,
+};
+
+use ed25519_dalek::{
+	Signer,
+	SigningKey,
+	Verifier,
+	VerifyingKey,
+};
+
+use crate::prelude::*;
+
+pub type PublicKey = [u8; 32];
+
+// Keeps a signature from being valid in any other context
+
+const SIGNATURE_CONTEXT: &[u8] = b"lookaround response v1";
+
+#[derive (Debug, thiserror::Error)]
+pub enum IdentityError {
+	#[error ("Key file {0:?} is corrupt, delete it to make a new key")]
+	BadKeyFile (PathBuf),
+	#[error (transparent)]
+	Io (#[from] std::io::Error),
+}
+
+pub struct Identity {
+	key: SigningKey,
+}
+
+impl Identity {
+	pub fn from_seed (seed: [u8; 32]) -> Self {
+		Self {
+			key: SigningKey::from_bytes (&seed),
+		}
+	}
+	
+	/// Loads the key at `path`, or makes a new one there if it doesn't exist
+	pub fn load_or_create (path: &Path) -> Result <Self, IdentityError> {
+		match std::fs::read_to_string (path) {
+			Ok (s) => {
+				let seed = from_hex (s.trim ())
+				.ok_or_else (|| IdentityError::BadKeyFile (path.to_path_buf ()))?;
+				Ok (Self::from_seed (seed))
+			},
+			Err (e) if e.kind () == std::io::ErrorKind::NotFound => {
+				let mut seed = [0u8; 32];
+				rand::thread_rng ().fill_bytes (&mut seed);
+				
+				if let Some (dir) = path.parent () {
+					std::fs::create_dir_all (dir)?;
+				}
+				
+				let mut options = std::fs::OpenOptions::new ();
+				options.write (true).create_new (true);
+				#[cfg(unix)]
+				std::os::unix::fs::OpenOptionsExt::mode (&mut options, 0o600);
+				
+				let mut f = options.open (path)?;
+				writeln! (f, "{}", to_hex (&seed))?;
+				
+				Ok (Self::from_seed (seed))
+			},
+			Err (e) => Err (e.into ()),
+		}
+	}
+	
+	pub fn public_key (&self) -> PublicKey {
+		self.key.verifying_key ().to_bytes ()
+	}
+	
+	/// Signs `msgs`, as they'll be encoded in `version`, along with the
+	/// client's `idem_id`, so the signature can't be replayed to answer a
+	/// different request
+	pub fn sign (&self, idem_id: [u8; 8], msgs: &[Message], version: u8) -> Result <Message, MessageError> {
+		let signature = self.key.sign (&signed_bytes (idem_id, msgs, version)?);
+		
+		Ok (Message::Signature {
+			public_key: self.public_key (),
+			signature: signature.to_bytes (),
+		})
+	}
+}
+
+/// Checks a signature made by `Identity::sign`
+pub fn verify (
+	idem_id: [u8; 8],
+	msgs: &[Message],
+	version: u8,
+	public_key: &PublicKey,
+	signature: &[u8; 64],
+) -> bool
+{
+	let key = match VerifyingKey::from_bytes (public_key) {
+		Ok (x) => x,
+		Err (_) => return false,
+	};
+	let bytes = match signed_bytes (idem_id, msgs, version) {
+		Ok (x) => x,
+		Err (_) => return false,
+	};
+	
+	key.verify (&bytes, &ed25519_dalek::Signature::from_bytes (signature)).is_ok ()
+}
+
+fn signed_bytes (idem_id: [u8; 8], msgs: &[Message], version: u8) -> Result <Vec <u8>, MessageError> {
+	let mut v = SIGNATURE_CONTEXT.to_vec ();
+	v.extend_from_slice (&idem_id);
+	v.extend_from_slice (&Message::encode (msgs, version)?);
+	Ok (v)
+}
+
+#[derive (Debug, PartialEq)]
+pub enum Trust {
+	/// We've never seen this nickname with a key before
+	New,
+	/// The key matches the one we pinned
+	Known,
+	/// The peer didn't sign, and we have nothing pinned for it either
+	Unsigned,
+	/// The peer's key doesn't match, or it didn't sign at all even though
+	/// we pinned a key for it. Could be an impostor.
+	Mismatch,
+}
+
+/// Nickname-to-key pins, stored in known_peers.ini
+pub struct KnownPeers {
+	path: PathBuf,
+	keys: HashMap <String, PublicKey>,
+}
+
+impl KnownPeers {
+	/// Bad keys in the file are skipped, with a warning unless `quiet`
+	pub fn load (path: PathBuf, quiet: bool) -> Self {
+		let mut keys = HashMap::default ();
+		
+		let mut ini = Ini::new_cs ();
+		if ini.load (&path).is_ok () {
+			if let Some (x) = ini.get_map_ref ().get ("keys") {
+				for (k, v) in x {
+					match v.as_deref ().and_then (from_hex) {
+						Some (v) => {
+							keys.insert (k.to_string (), v);
+						},
+						None => if ! quiet {
+							eprintln! ("Ignoring bad key for `{}` in {:?}", k, path);
+						},
+					}
+				}
+			}
+		}
+		
+		Self {
+			path,
+			keys,
+		}
+	}
+	
+	pub fn check (&self, nickname: &str, key: Option <&PublicKey>) -> Trust {
+		match (self.keys.get (nickname), key) {
+			(None, None) => Trust::Unsigned,
+			(None, Some (_)) => Trust::New,
+			(Some (pinned), Some (key)) if pinned == key => Trust::Known,
+			(Some (_), _) => Trust::Mismatch,
+		}
+	}
+	
+	/// Pins a key and saves the file right away
+	pub fn pin (&mut self, nickname: &str, key: PublicKey) -> Result <(), std::io::Error> {
+		self.keys.insert (nickname.to_string (), key);
+		
+		let mut ini = Ini::new_cs ();
+		for (k, v) in &self.keys {
+			ini.set ("keys", k, Some (to_hex (v)));
+		}
+		
+		if let Some (dir) = self.path.parent () {
+			std::fs::create_dir_all (dir)?;
+		}
+		ini.write (&self.path)
+	}
+}
+
+pub fn to_hex (b: &[u8]) -> String {
+	b.iter ().map (|x| format! ("{:02x}", x)).collect ()
+}
+
+fn from_hex <const N: usize> (s: &str) -> Option <[u8; N]> {
+	if s.len () != N * 2 || ! s.is_ascii () {
+		return None;
+	}
+	
+	let mut b = [0u8; N];
+	for (i, x) in b.iter_mut ().enumerate () {
+		*x = u8::from_str_radix (&s [i * 2..i * 2 + 2], 16).ok ()?;
+	}
+	Some (b)
+}
+
+#[cfg (test)]
+mod test {
+	use super::*;
+	
+	fn example_msgs () -> Vec <Message> {
+		vec! [
+			Message::Response1 (Some ([0x11, 0x22, 0x33, 0x44, 0x55, 0x66])),
+			Message::Response2 (message::Response2 {
+				idem_id: [1, 2, 3, 4, 5, 6, 7, 8],
+				nickname: "laptop".to_string (),
+			}),
+		]
+	}
+	
+	#[test]
+	fn test_sign () -> Result <(), MessageError> {
+		let idem_id = [1, 2, 3, 4, 5, 6, 7, 8];
+		let msgs = example_msgs ();
+		let id = Identity::from_seed ([7; 32]);
+		
+		let v = message::VERSION_2;
+		let (public_key, signature) = match id.sign (idem_id, &msgs, v)? {
+			Message::Signature { public_key, signature } => (public_key, signature),
+			_ => panic! ("sign should return a Signature"),
+		};
+		
+		assert! (verify (idem_id, &msgs, v, &public_key, &signature));
+		
+		// Replaying the signature for another request must fail
+		assert! (! verify ([0; 8], &msgs, v, &public_key, &signature));
+		
+		// So must tampering
+		let mut tampered = example_msgs ();
+		tampered [0] = Message::Response1 (None);
+		assert! (! verify (idem_id, &tampered, v, &public_key, &signature));
+		
+		// And another key
+		let other = Identity::from_seed ([8; 32]).public_key ();
+		assert! (! verify (idem_id, &msgs, v, &other, &signature));
+		
+		// And the same messages in another version's framing
+		assert! (! verify (idem_id, &msgs, message::VERSION_1, &public_key, &signature));
+		
+		Ok (())
+	}
+	
+	#[test]
+	fn test_trust () {
+		let mut known = KnownPeers {
+			path: PathBuf::new (),
+			keys: Default::default (),
+		};
+		known.keys.insert ("laptop".to_string (), [1; 32]);
+		
+		assert_eq! (known.check ("laptop", Some (&[1; 32])), Trust::Known);
+		assert_eq! (known.check ("laptop", Some (&[2; 32])), Trust::Mismatch);
+		assert_eq! (known.check ("laptop", None), Trust::Mismatch);
+		assert_eq! (known.check ("desktop", Some (&[2; 32])), Trust::New);
+		assert_eq! (known.check ("desktop", None), Trust::Unsigned);
+	}
+	
+	#[test]
+	fn test_hex () {
+		assert_eq! (to_hex (&[0x00, 0xab, 0x10]), "00ab10");
+		assert_eq! (from_hex::<3> ("00ab10"), Some ([0x00, 0xab, 0x10]));
+		assert_eq! (from_hex::<3> ("00ab1"), None);
+		assert_eq! (from_hex::<3> ("00abzz"), None);
+	}
+}
@@ -1,5 +1,13 @@
 use std::{
-	net::Ipv4Addr,
+	net::{
+		IpAddr,
+		Ipv4Addr,
+		SocketAddr,
+	},
+};
+
+#[cfg(target_os = "windows")]
+use std::{
 	process::Command,
 	str::FromStr,
 };
@@ -14,11 +22,89 @@ pub enum IpError {
 	NotImplementedOnMac,
 }
 
+#[derive (Clone, Debug, PartialEq)]
+pub struct Interface {
+	pub name: String,
+	pub index: u32,
+	pub mac: Option <[u8; 6]>,
+	pub addrs: Vec <InterfaceAddr>,
+	pub is_up: bool,
+	pub is_multicast: bool,
+	pub is_loopback: bool,
+}
+
+#[derive (Clone, Debug, PartialEq)]
+pub struct InterfaceAddr {
+	pub addr: IpAddr,
+	pub prefix_len: u8,
+}
+
+impl std::fmt::Display for InterfaceAddr {
+	fn fmt (&self, f: &mut std::fmt::Formatter <'_>) -> std::fmt::Result {
+		write! (f, "{}/{}", self.addr, self.prefix_len)
+	}
+}
+
+impl InterfaceAddr {
+	/// True if `addr` is on this address's subnet
+	pub fn contains (&self, addr: IpAddr) -> bool {
+		match (self.addr, addr) {
+			(IpAddr::V4 (a), IpAddr::V4 (b)) => {
+				let mask = u32::MAX.checked_shl (32 - u32::from (self.prefix_len).min (32)).unwrap_or (0);
+				u32::from (a) & mask == u32::from (b) & mask
+			},
+			(IpAddr::V6 (a), IpAddr::V6 (b)) => {
+				let mask = u128::MAX.checked_shl (128 - u32::from (self.prefix_len).min (128)).unwrap_or (0);
+				u128::from (a) & mask == u128::from (b) & mask
+			},
+			_ => false,
+		}
+	}
+}
+
+/// Which of our interfaces a peer at `addr` is reachable through.
+/// Link-local IPv6 goes by scope ID, everything else by subnet.
+pub fn iface_for <'a> (interfaces: &'a [Interface], addr: &SocketAddr) -> Option <&'a Interface> {
+	if let SocketAddr::V6 (x) = addr {
+		if x.scope_id () != 0 {
+			return interfaces.iter ().find (|iface| iface.index == x.scope_id ());
+		}
+	}
+	
+	interfaces.iter ()
+	.filter (|iface| ! iface.is_loopback)
+	.find (|iface| iface.addrs.iter ().any (|a| a.contains (addr.ip ())))
+}
+
+#[cfg(target_os = "linux")]
+pub fn get_interfaces () -> Result <Vec <Interface>, IpError> {
+	linux::get_interfaces ()
+}
+
+/// Like `get_interfaces`, but empty on errors and on platforms where
+/// we can't list interfaces yet, for callers that can do without
+#[cfg(target_os = "linux")]
+pub fn list_interfaces () -> Vec <Interface> {
+	get_interfaces ().unwrap_or_default ()
+}
+
+#[cfg(not (target_os = "linux"))]
+pub fn list_interfaces () -> Vec <Interface> {
+	vec! []
+}
+
 #[cfg(target_os = "linux")]
 pub fn get_ips () -> Result <Vec <Ipv4Addr>, IpError> {
-	let output = linux::get_ip_addr_output ()?;
+	let ips = get_interfaces ()?.into_iter ()
+	.flat_map (|iface| iface.addrs.into_iter ())
+	.filter_map (|a| match a.addr {
+		IpAddr::V4 (x) => Some (x),
+		IpAddr::V6 (_) => None,
+	})
+	.filter (|a| ! a.is_loopback ())
+	.collect ();
 	
-	Ok (linux::parse_ip_addr_output (&output))
+	Ok (ips)
 }
 
 #[cfg(target_os = "macos")]
@@ -33,29 +119,202 @@ pub fn get_ips () -> Result <Vec <Ipv4Addr>, IpError> {
 	Ok (windows::parse_ip_config_output (&output))
 }
 
+// IPv6 multicast is link-local, so instead of addresses we need the
+// indexes (scope IDs) of every interface that has a link-local IPv6 address
+
 #[cfg(target_os = "linux")]
-pub mod linux {
+pub fn get_ipv6_ifaces () -> Result <Vec <u32>, IpError> {
+	let ifaces = get_interfaces ()?.into_iter ()
+	.filter (|iface| iface.is_up && iface.is_multicast && ! iface.is_loopback)
+	.filter (|iface| iface.addrs.iter ().any (|a| match a.addr {
+		IpAddr::V6 (x) => (x.segments () [0] & 0xffc0) == 0xfe80,
+		IpAddr::V4 (_) => false,
+	}))
+	.map (|iface| iface.index)
+	.collect ();
+	
+	Ok (ifaces)
+}
+
+#[cfg(target_os = "macos")]
+pub fn get_ipv6_ifaces () -> Result <Vec <u32>, IpError> {
+	Err (IpError::NotImplementedOnMac)
+}
+
+#[cfg(target_os = "windows")]
+pub fn get_ipv6_ifaces () -> Result <Vec <u32>, IpError> {
+	let output = windows::get_ip_config_output ()?;
+	
+	Ok (windows::parse_ip_config_output_v6 (&output))
+}
+
+#[cfg (test)]
+mod test {
 	use super::*;
 	
-	pub fn get_ip_addr_output () -> Result <String, IpError> {
-		let output = Command::new ("ip")
-		.arg ("addr")
-		.output ()?;
-		let output = output.stdout.as_slice ();
-		let output = String::from_utf8 (output.to_vec ())?;
-		Ok (output)
+	#[test]
+	fn test_iface_for () {
+		let iface = |name: &str, index, addrs: &[&str]| Interface {
+			name: name.to_string (),
+			index,
+			mac: None,
+			addrs: addrs.iter ().map (|x| {
+				let (addr, len) = x.split_once ('/').unwrap ();
+				InterfaceAddr {
+					addr: addr.parse ().unwrap (),
+					prefix_len: len.parse ().unwrap (),
+				}
+			}).collect (),
+			is_up: true,
+			is_multicast: true,
+			is_loopback: false,
+		};
+		let ifaces = [
+			iface ("eth0", 2, &["192.168.1.5/24", "fe80::1/64"]),
+			iface ("wlan0", 3, &["10.0.0.7/8", "fe80::2/64"]),
+		];
+		
+		for (input, expected) in [
+			("192.168.1.101:9040", Some ("eth0")),
+			("10.200.3.4:9040", Some ("wlan0")),
+			("172.16.0.1:9040", None),
+			("[fe80::99%3]:9040", Some ("wlan0")),
+			("[fe80::99%9]:9040", None),
+		] {
+			let actual = iface_for (&ifaces, &input.parse ().unwrap ()).map (|x| x.name.as_str ());
+			assert_eq! (actual, expected, "{}", input);
+		}
 	}
+}
 
-	pub fn parse_ip_addr_output (output: &str) -> Vec <Ipv4Addr> {
-		// I wrote this in FP style because I was bored.
+#[cfg(target_os = "linux")]
+pub mod linux {
+	use std::{
+		ffi::CStr,
+		net::Ipv6Addr,
+	};
+	
+	use super::*;
+	
+	// Walks getifaddrs, which gives one entry per (interface, address)
+	// pair. The AF_PACKET entries carry the MAC and index, the AF_INET and
+	// AF_INET6 entries carry the IPs.
+	
+	pub fn get_interfaces () -> Result <Vec <Interface>, IpError> {
+		let mut ifap: *mut libc::ifaddrs = std::ptr::null_mut ();
+		if unsafe { libc::getifaddrs (&mut ifap) } != 0 {
+			return Err (std::io::Error::last_os_error ().into ());
+		}
+		
+		let mut ifaces: Vec <Interface> = vec! [];
+		let mut cur = ifap;
+		
+		while let Some (ifa) = unsafe { cur.as_ref () } {
+			cur = ifa.ifa_next;
+			
+			let name = unsafe { CStr::from_ptr (ifa.ifa_name) }.to_string_lossy ().into_owned ();
+			
+			let iface = match ifaces.iter ().position (|x| x.name == name) {
+				Some (x) => &mut ifaces [x],
+				None => {
+					let flags = ifa.ifa_flags as libc::c_int;
+					ifaces.push (Interface {
+						index: unsafe { libc::if_nametoindex (ifa.ifa_name) },
+						name,
+						mac: None,
+						addrs: vec! [],
+						is_up: flags & libc::IFF_UP != 0,
+						is_multicast: flags & libc::IFF_MULTICAST != 0,
+						is_loopback: flags & libc::IFF_LOOPBACK != 0,
+					});
+					ifaces.last_mut ().unwrap ()
+				},
+			};
+			
+			if ifa.ifa_addr.is_null () {
+				continue;
+			}
+			
+			match i32::from (unsafe { *ifa.ifa_addr }.sa_family) {
+				libc::AF_PACKET => {
+					let ll = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_ll) };
+					if ll.sll_halen == 6 && ll.sll_addr [0..6].iter ().any (|x| *x != 0) {
+						let mut mac = [0u8; 6];
+						mac.copy_from_slice (&ll.sll_addr [0..6]);
+						iface.mac = Some (mac);
+					}
+				},
+				libc::AF_INET => {
+					let sin = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+					let addr = Ipv4Addr::from (u32::from_be (sin.sin_addr.s_addr));
+					let prefix_len = match unsafe { ifa.ifa_netmask.as_ref () } {
+						None => 32,
+						Some (x) => {
+							let mask = unsafe { &*(x as *const libc::sockaddr as *const libc::sockaddr_in) };
+							prefix_len (&mask.sin_addr.s_addr.to_ne_bytes ())
+						},
+					};
+					iface.addrs.push (InterfaceAddr {
+						addr: addr.into (),
+						prefix_len,
+					});
+				},
+				libc::AF_INET6 => {
+					let sin6 = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+					let addr = Ipv6Addr::from (sin6.sin6_addr.s6_addr);
+					let prefix_len = match unsafe { ifa.ifa_netmask.as_ref () } {
+						None => 128,
+						Some (x) => {
+							let mask = unsafe { &*(x as *const libc::sockaddr as *const libc::sockaddr_in6) };
+							prefix_len (&mask.sin6_addr.s6_addr)
+						},
+					};
+					iface.addrs.push (InterfaceAddr {
+						addr: addr.into (),
+						prefix_len,
+					});
+				},
+				_ => (),
+			}
+		}
+		
+		unsafe { libc::freeifaddrs (ifap) };
+		
+		ifaces.sort_by_key (|x| x.index);
+		Ok (ifaces)
+	}
+	
+	fn prefix_len (netmask: &[u8]) -> u8 {
+		netmask.iter ().map (|x| x.count_ones () as u8).sum ()
+	}
+	
+	#[cfg (test)]
+	mod test {
+		use super::*;
 		
-		output.lines () 
-		.map (|l| l.trim_start ())
-		.filter_map (|l| l.strip_prefix ("inet "))
-		.filter_map (|l| l.find ('/').map (|x| &l [0..x]))
-		.filter_map (|l| Ipv4Addr::from_str (l).ok ())
-		.filter (|a| ! a.is_loopback ())
-		.collect ()
+		#[test]
+		fn test_prefix_len () {
+			for (input, expected) in [
+				(&[255, 255, 255, 0][..], 24),
+				(&[255, 255, 240, 0][..], 20),
+				(&[0, 0, 0, 0][..], 0),
+				(&[0xff; 16][..], 128),
+			] {
+				assert_eq! (prefix_len (input), expected);
+			}
+		}
+		
+		#[test]
+		fn test_loopback () -> Result <(), IpError> {
+			let ifaces = get_interfaces ()?;
+			let lo = ifaces.iter ().find (|x| x.is_loopback).expect ("no loopback interface");
+			assert! (lo.is_up);
+			assert! (lo.addrs.contains (&InterfaceAddr {
+				addr: Ipv4Addr::LOCALHOST.into (),
+				prefix_len: 8,
+			}));
+			Ok (())
+		}
 	}
 }
 
@@ -97,6 +356,29 @@ pub mod windows {
 		
 		addrs
 	}
+	
+	pub fn parse_ip_config_output_v6 (output: &str) -> Vec <u32> {
+		let mut ifaces = vec! [];
+		
+		for line in output.lines () {
+			let line = line.trim_start ();
+			
+			// e.g. `Link-local IPv6 Address . . . . . : fe80::1%12`
+			if ! line.starts_with ("Link-local IPv6 Address") {
+				continue;
+			}
+			let iface = match line.rfind ('%').map (|x| u32::from_str (&line [x + 1..])) {
+				Some (Ok (x)) => x,
+				_ => continue,
+			};
+			
+			if ! ifaces.contains (&iface) {
+				ifaces.push (iface);
+			}
+		}
+		
+		ifaces
+	}
 
 	#[cfg (test)]
 	mod test {
@@ -118,5 +400,14 @@ pub mod windows {
 				assert_eq! (actual, expected);
 			}
 		}
+		
+		#[test]
+		fn test_v6 () {
+			let input = r"
+	Link-local IPv6 Address . . . . . : fe80::1c2b:3a4d:5e6f:7081%12
+	IPv4 Address .   .  .. . . . : 192.168.1.1
+	";
+			assert_eq! (parse_ip_config_output_v6 (input), vec! [12]);
+		}
 	}
 }
@@ -0,0 +1,68 @@
+//! LookAround finds your computers' MAC and IP addresses within a LAN,
+//! by multicasting a request and listening for servers to answer.
+//!
+//! ```no_run
+//! # async fn f () -> Result <(), lookaround::AppError> {
+//! use std::time::Duration;
+//!
+//! if let Some (peer) = lookaround::find_nick ("laptop", Duration::from_millis (500)).await? {
+//!     println! ("laptop is at {}", peer.addr.ip ());
+//! }
+//! # Ok (())
+//! # }
+//! ```
+
+pub mod app_common;
+pub mod client;
+pub mod connect;
+pub mod dedup;
+pub mod dns;
+pub mod hosts;
+pub mod identity;
+pub mod ip;
+pub mod mdns;
+pub mod nss;
+pub mod message;
+mod prelude;
+pub mod psk;
+pub mod ratelimit;
+pub mod resolver;
+pub mod sealed;
+pub mod server;
+pub mod tlv;
+pub mod transfer;
+pub mod watch;
+
+pub use app_common::{
+	AppError,
+	Params,
+};
+pub use client::{
+	DiscoverOptions,
+	NicknameSource,
+	Peer,
+	discover,
+	find_mac_with,
+	find_nick,
+	find_nick_with,
+	find_service_with,
+	whois_with,
+};
+pub use message::{
+	Protocol,
+	Service,
+};
+pub use resolver::{
+	ResolverOptions,
+	serve_dns,
+};
+pub use server::{
+	Responder,
+	ResponderBuilder,
+};
+pub use watch::{
+	PeerEvent,
+	PeerTable,
+	listen,
+	watch,
+};
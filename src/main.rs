@@ -1,13 +1,19 @@
-use prelude::*;
+use std::env;
+
+use directories::ProjectDirs;
+
+use lookaround::{
+	AppError,
+	app_common::{
+		CliArgError,
+		LOOKAROUND_VERSION,
+	},
+	ip,
+};
 
-pub mod app_common;
 mod avalanche;
-mod client;
-mod ip;
-pub mod message;
-mod prelude;
-mod server;
-pub mod tlv;
+mod cli;
+mod output;
 
 fn main () -> Result <(), AppError> {
 	let rt = tokio::runtime::Builder::new_current_thread ()
@@ -30,12 +36,22 @@ async fn async_main () -> Result <(), AppError> {
 	match subcommand.as_ref ().map (|x| &x[..]) {
 		None => return Err (CliArgError::MissingSubcommand.into ()),
 		Some ("--version") => println! ("lookaround v{}", LOOKAROUND_VERSION),
-		Some ("client") => client::client (args).await?,
+		Some ("client") => cli::client (args).await?,
 		Some ("config") => config (),
+		Some ("connect") => cli::connect (args).await?,
 		Some ("debug-avalanche") => avalanche::debug (),
-		Some ("find-nick") => client::find_nick (args).await?,
-		Some ("my-ips") => my_ips ()?,
-		Some ("server") => server::server (args).await?,
+		Some ("dns") => cli::dns (args).await?,
+		Some ("find-mac") => cli::find_mac (args).await?,
+		Some ("find-nick") => cli::find_nick (args).await?,
+		Some ("find-service") => cli::find_service (args).await?,
+		Some ("hosts") => cli::hosts (args).await?,
+		Some ("listen") => cli::listen (args).await?,
+		Some ("my-ips") => my_ips (args)?,
+		Some ("receive") => cli::receive (args).await?,
+		Some ("send") => cli::send (args).await?,
+		Some ("server") => cli::server (args).await?,
+		Some ("watch") => cli::watch (args).await?,
+		Some ("whois") => cli::whois (args).await?,
 		Some (x) => return Err (CliArgError::UnknownSubcommand (x.to_string ()).into ()),
 	}
 	
@@ -51,8 +67,67 @@ fn config () {
 	}
 }
 
-fn my_ips () -> Result <(), AppError> {
-	for addr in ip::get_ips ()?
+fn parse_my_ips_args <I: Iterator <Item=String>> (mut args: I) 
+-> Result <Option <output::Format>, AppError>
+{
+	let mut format = None;
+	
+	while let Some (arg) = args.next () {
+		match arg.as_str () {
+			"--format" => format = Some (cli::parse_format (&arg, &mut args)?),
+			_ => return Err (CliArgError::UnrecognizedArgument (arg).into ()),
+		}
+	}
+	
+	Ok (format)
+}
+
+#[cfg(target_os = "linux")]
+fn my_ips <I: Iterator <Item=String>> (args: I) -> Result <(), AppError> {
+	let interfaces = ip::get_interfaces ()?;
+	
+	if let Some (format) = parse_my_ips_args (args)? {
+		output::print (&format, &output::interface_records (&interfaces));
+		return Ok (());
+	}
+	
+	for iface in interfaces {
+		let mut flags = vec! [];
+		if iface.is_up {
+			flags.push ("UP");
+		}
+		if iface.is_multicast {
+			flags.push ("MULTICAST");
+		}
+		if iface.is_loopback {
+			flags.push ("LOOPBACK");
+		}
+		
+		let mac = iface.mac.map (|x| mac_address::MacAddress::new (x).to_string ()).unwrap_or_default ();
+		
+		println! ("{}: {} {} <{}>", iface.index, iface.name, mac, flags.join (","));
+		for addr in &iface.addrs {
+			println! ("\t{}", addr);
+		}
+	}
+	
+	Ok (())
+}
+
+#[cfg(not (target_os = "linux"))]
+fn my_ips <I: Iterator <Item=String>> (args: I) -> Result <(), AppError> {
+	let ips = ip::get_ips ()?;
+	
+	if let Some (format) = parse_my_ips_args (args)? {
+		let records: Vec <_> = ips.iter ().map (|x| output::Record {
+			ip: x.to_string (),
+			..Default::default ()
+		}).collect ();
+		output::print (&format, &records);
+		return Ok (());
+	}
+	
+	for addr in ips
 	{
 		println! ("{:?}", addr);
 	}
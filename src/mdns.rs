@@ -0,0 +1,127 @@
+// Just enough mDNS (RFC 6762) to answer A and AAAA queries for
+// `<nickname>.local`, so tools that don't speak LookAround can still
+// resolve us. We never ask questions, only answer them.
+
+use crate::{
+	dns::{
+		self,
+		Answer,
+		CLASS_IN,
+		CLASS_TOP_BIT,
+		Query,
+		TYPE_A,
+		TYPE_AAAA,
+	},
+	prelude::*,
+};
+
+pub const MDNS_PORT: u16 = 5353;
+pub const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new (224, 0, 0, 251);
+pub const MDNS_ADDR_V6: Ipv6Addr = Ipv6Addr::new (0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+
+/// RFC 6762 section 11 says to multicast with a TTL or hop limit of 255
+pub const MDNS_HOP_LIMIT: u32 = 255;
+
+const TTL: u32 = 120;
+
+// RFC 6762 section 6.7 says legacy resolvers shouldn't cache us for long
+
+const LEGACY_TTL: u32 = 10;
+
+/// Who's asking, which changes how we have to answer
+#[derive (Clone, Copy, Debug, PartialEq)]
+pub enum Asker {
+	/// A real mDNS resolver sending from port 5353
+	Mdns,
+	/// Something like `dig -p 5353 @224.0.0.251`, which only understands
+	/// plain unicast DNS
+	Legacy,
+}
+
+/// Answers the questions about `hostname` (e.g. `laptop.local`) with our
+/// addresses. Returns `None` if there's nothing to say.
+pub fn build_response (
+	query: &Query,
+	asker: Asker,
+	hostname: &str,
+	v4: &[Ipv4Addr],
+	v6: &[Ipv6Addr],
+) -> Option <Vec <u8>>
+{
+	let (id, ttl, class) = match asker {
+		Asker::Mdns => (0, TTL, CLASS_IN | CLASS_TOP_BIT),
+		Asker::Legacy => (query.id, LEGACY_TTL, CLASS_IN),
+	};
+	
+	let mut answers = vec! [];
+	let mut questions = vec! [];
+	
+	for q in &query.questions {
+		if ! q.name.eq_ignore_ascii_case (hostname) {
+			continue;
+		}
+		
+		let before = answers.len ();
+		if q.wants (TYPE_A) {
+			answers.extend (v4.iter ().map (|x| Answer::addr (hostname, (*x).into (), class, ttl)));
+		}
+		if q.wants (TYPE_AAAA) {
+			answers.extend (v6.iter ().map (|x| Answer::addr (hostname, (*x).into (), class, ttl)));
+		}
+		if answers.len () > before {
+			questions.push (q);
+		}
+	}
+	
+	if answers.is_empty () {
+		return None;
+	}
+	
+	// Legacy resolvers expect their question echoed back, mDNS ones don't
+	if asker == Asker::Mdns {
+		questions.clear ();
+	}
+	
+	Some (dns::encode_response (id, dns::FLAG_RESPONSE | dns::FLAG_AUTHORITATIVE, &questions, &answers))
+}
+
+#[cfg (test)]
+mod test {
+	use super::*;
+	use crate::dns::{
+		parse_query,
+		read_u16,
+		test::example_query,
+		write_name,
+	};
+	
+	#[test]
+	fn test_build_response () -> Result <(), dns::DnsError> {
+		let query = parse_query (&example_query ("laptop.local"))?.unwrap ();
+		let v4 = [Ipv4Addr::new (192, 168, 1, 101)];
+		let v6 = ["fe80::1".parse ().unwrap ()];
+		
+		let resp = build_response (&query, Asker::Mdns, "laptop.local", &v4, &v6).unwrap ();
+		let mut expected = vec! [
+			0, 0, 0x84, 0,
+			0, 0, 0, 1,
+			0, 0, 0, 0,
+		];
+		write_name (&mut expected, "laptop.local");
+		expected.extend_from_slice (&[0, 1, 0x80, 1, 0, 0, 0, 120, 0, 4, 192, 168, 1, 101]);
+		assert_eq! (resp, expected);
+		
+		// Legacy askers get their ID and question back
+		let resp = build_response (&query, Asker::Legacy, "laptop.local", &v4, &v6).unwrap ();
+		assert_eq! (read_u16 (&resp, 4)?, 1);
+		
+		// Names are case-insensitive, and nobody else's name gets an answer
+		assert! (build_response (&query, Asker::Mdns, "LAPTOP.local", &v4, &v6).is_some ());
+		assert! (build_response (&query, Asker::Mdns, "desktop.local", &v4, &v6).is_none ());
+		
+		// Nothing to say if we don't have the asked-for address family
+		assert! (build_response (&query, Asker::Mdns, "laptop.local", &[], &v6).is_none ());
+		
+		Ok (())
+	}
+}
@@ -3,9 +3,24 @@ use crate::prelude::*;
 const MAGIC_NUMBER: [u8; 4] = [0x9a, 0x4a, 0x43, 0x81];
 pub const PACKET_SIZE: usize = 1024;
 
+/// The original framing. Each type implies its own length, so a packet
+/// with one unknown type can't be read at all.
+pub const VERSION_1: u8 = 1;
+
+/// Every record is length-prefixed, so unknown types can be skipped
+pub const VERSION_2: u8 = 2;
+
+/// Every version we can write, newest first
+pub const VERSIONS: [u8; 2] = [VERSION_2, VERSION_1];
+
+// From v2 on, the magic number is followed by this and then the version.
+// v1 never used tag 0, so v1 parsers drop the packet instead of misreading it.
+
+const VERSION_TAG: u8 = 0;
+
 type Mac = [u8; 6];
 
-#[derive (Debug, PartialEq)]
+#[derive (Clone, Debug, PartialEq)]
 pub enum Message {
 	// 1
 	Request1 {
@@ -16,28 +31,170 @@ pub enum Message {
 	Response1 (Option <Mac>),
 	// 3
 	Response2 (Response2),
+	// 4
+	Services (Vec <Service>),
+	// 5
+	// Signs every message before it in the packet, see `identity`
+	Signature {
+		public_key: [u8; 32],
+		signature: [u8; 64],
+	},
+	// 6
+	// Starts a packet that a server multicasts without being asked, when
+	// it starts up or its IPs change. The rest looks like a response.
+	Announce {
+		idem_id: [u8; 8],
+	},
+	// 7
+	// Like `Announce`, but the server is shutting down
+	Goodbye {
+		idem_id: [u8; 8],
+	},
+	// 8, v2 only
+	// Which of a server's sockets sent this response, so the client can
+	// list it in `KnownAnswers`
+	AnswerId ([u8; 8]),
+	// 9, v2 only
+	// Follows `Request1` in retransmits. Servers whose `AnswerId` is in
+	// here already got through, so they don't answer again.
+	KnownAnswers (Vec <[u8; 8]>),
+	// 10, v2 only
+	// Authenticates every message before it in the packet with the
+	// fleet's pre-shared key, see `psk`
+	Hmac ([u8; 32]),
+	// 11, v2 only
+	// Follows `Request1`. Asks servers to encrypt their answers to this
+	// X25519 key, see `sealed`.
+	ResponseKey ([u8; 32]),
+	// 12, v2 only
+	// An encrypted packet, answering a request with a `ResponseKey`
+	Sealed {
+		ephemeral_key: [u8; 32],
+		ciphertext: Vec <u8>,
+	},
+	// 13, v2 only
+	// When a private fleet's request was sent, in seconds since the Unix
+	// epoch. Goes before `Hmac`, so servers can tell a fresh request
+	// from a replayed one.
+	Timestamp (u64),
+	// A type from a newer version, skipped. Kept whole so a signature
+	// covering it can still be checked.
+	Unknown {
+		tag: u8,
+		body: Vec <u8>,
+	},
+}
+
+/// A decoded packet, and which version's framing it used, so we can
+/// answer in the same one
+#[derive (Clone, Debug, PartialEq)]
+pub struct Packet {
+	pub version: u8,
+	pub msgs: Vec <Message>,
+}
+
+impl Packet {
+	/// Tags of the records we didn't understand
+	pub fn unknown_tags (&self) -> Vec <u8> {
+		self.msgs.iter ().filter_map (|x| match x {
+			Message::Unknown { tag, .. } => Some (*tag),
+			_ => None,
+		}).collect ()
+	}
 }
 
 impl Message {
 	pub fn new_request1 () -> Message {
+		Self::new_request1_for (None)
+	}
+	
+	/// A request that only the server with `mac` will answer, or every
+	/// server if `mac` is `None`
+	pub fn new_request1_for (mac: Option <[u8; 6]>) -> Message {
 		let mut idem_id = [0u8; 8];
 		rand::thread_rng ().fill_bytes (&mut idem_id);
 		
 		Message::Request1 {
 			idem_id,
-			mac: None,
+			mac,
+		}
+	}
+	
+	pub fn idem_id (&self) -> Option <[u8; 8]> {
+		match self {
+			Self::Request1 { idem_id, .. } => Some (*idem_id),
+			Self::Response2 (x) => Some (x.idem_id),
+			Self::Announce { idem_id } => Some (*idem_id),
+			Self::Goodbye { idem_id } => Some (*idem_id),
+			_ => None,
 		}
 	}
 }
 
-#[derive (Debug, PartialEq)]
+#[derive (Clone, Debug, PartialEq)]
 pub struct Response2 {
 	pub idem_id: [u8; 8],
 	pub nickname: String,
 }
 
+/// A TCP or UDP service that a server advertises, e.g. `ssh = tcp/22`
+/// in server.ini
+#[derive (Clone, Debug, PartialEq)]
+pub struct Service {
+	pub name: String,
+	pub protocol: Protocol,
+	pub port: u16,
+}
+
+// On the wire these are the IP protocol numbers
+
+#[derive (Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+	Tcp,
+	Udp,
+}
+
+impl Service {
+	/// Parses the value half of a `[services]` entry, like `tcp/22`
+	pub fn parse (name: &str, value: &str) -> Result <Self, MessageError> {
+		let (protocol, port) = value.trim ().split_once ('/')
+		.ok_or_else (|| MessageError::BadService (value.to_string ()))?;
+		
+		let protocol = match protocol.to_ascii_lowercase ().as_str () {
+			"tcp" => Protocol::Tcp,
+			"udp" => Protocol::Udp,
+			_ => return Err (MessageError::BadService (value.to_string ())),
+		};
+		let port = u16::from_str (port)
+		.map_err (|_| MessageError::BadService (value.to_string ()))?;
+		
+		Ok (Self {
+			name: name.to_string (),
+			protocol,
+			port,
+		})
+	}
+}
+
+impl std::fmt::Display for Protocol {
+	fn fmt (&self, f: &mut std::fmt::Formatter <'_>) -> std::fmt::Result {
+		match self {
+			Self::Tcp => write! (f, "tcp"),
+			Self::Udp => write! (f, "udp"),
+		}
+	}
+}
+
+impl std::fmt::Display for Service {
+	fn fmt (&self, f: &mut std::fmt::Formatter <'_>) -> std::fmt::Result {
+		write! (f, "{} = {}/{}", self.name, self.protocol, self.port)
+	}
+}
+
 #[derive (Debug, thiserror::Error)]
 pub enum MessageError {
+	#[error ("Can't parse service `{0}`, expected something like `tcp/22`")]
+	BadService (String),
 	#[error (transparent)]
 	Io (#[from] std::io::Error),
 	#[error ("Length prefix too long")]
@@ -69,41 +226,105 @@ impl Write for DummyWriter {
 }
 
 impl Message {
-	pub fn write <T> (&self, w: &mut Cursor <T>) -> Result <(), MessageError> 
-	where Cursor <T>: Write
-	{
+	fn tag (&self) -> u8 {
+		match self {
+			Self::Request1 { .. } => 1,
+			Self::Response1 (_) => 2,
+			Self::Response2 (_) => 3,
+			Self::Services (_) => 4,
+			Self::Signature { .. } => 5,
+			Self::Announce { .. } => 6,
+			Self::Goodbye { .. } => 7,
+			Self::AnswerId (_) => 8,
+			Self::KnownAnswers (_) => 9,
+			Self::Hmac (_) => 10,
+			Self::ResponseKey (_) => 11,
+			Self::Sealed { .. } => 12,
+			Self::Timestamp (_) => 13,
+			Self::Unknown { tag, .. } => *tag,
+		}
+	}
+	
+	// Everything after the tag, or after the length prefix if there is one
+	
+	fn write_fields <W: Write> (&self, w: &mut W) -> Result <(), MessageError> {
 		match self {
 			Self::Request1 {
 				idem_id,
 				mac,
 			}=> {
-				w.write_all (&[1])?;
 				w.write_all (&idem_id[..])?;
 				Self::write_mac_opt (w, *mac)?;
 			},
-			Self::Response1 (mac) => {
-				w.write_all (&[2])?;
-				Self::write_mac_opt (w, *mac)?;
+			Self::Response1 (mac) => Self::write_mac_opt (w, *mac)?,
+			Self::Response2 (x) => Self::write_response_2 (w, x)?,
+			Self::Services (x) => Self::write_services (w, x)?,
+			Self::Signature {
+				public_key,
+				signature,
+			} => {
+				w.write_all (&public_key[..])?;
+				w.write_all (&signature[..])?;
 			},
-			Self::Response2 (x) => {
-				w.write_all (&[3])?;
-				// Measure length with dummy writes
-				// This is dumb, I'm just messing around to see if I can do
-				// this without allocating.
-				let mut dummy_writer = DummyWriter::default ();
-				
-				Self::write_response_2 (&mut dummy_writer, x)?;
-				
-				// Write length and real params to real output
-				let len = u32::try_from (dummy_writer.position)?;
-				w.write_all (&len.to_le_bytes ())?;
-				Self::write_response_2 (w, x)?;
+			Self::Announce { idem_id } => w.write_all (&idem_id[..])?,
+			Self::Goodbye { idem_id } => w.write_all (&idem_id[..])?,
+			Self::AnswerId (x) => w.write_all (&x[..])?,
+			Self::KnownAnswers (x) => for id in x {
+				w.write_all (&id[..])?;
 			},
+			Self::Hmac (x) => w.write_all (&x[..])?,
+			Self::ResponseKey (x) => w.write_all (&x[..])?,
+			Self::Sealed {
+				ephemeral_key,
+				ciphertext,
+			} => {
+				w.write_all (&ephemeral_key[..])?;
+				w.write_all (ciphertext)?;
+			},
+			Self::Timestamp (x) => w.write_all (&x.to_le_bytes ())?,
+			Self::Unknown { body, .. } => w.write_all (body)?,
 		}
 		
 		Ok (())
 	}
 	
+	fn write_length_prefixed <W: Write> (&self, w: &mut W) -> Result <(), MessageError> {
+		// Measure length with dummy writes
+		// This is dumb, I'm just messing around to see if I can do
+		// this without allocating.
+		let mut dummy_writer = DummyWriter::default ();
+		self.write_fields (&mut dummy_writer)?;
+		
+		// Write length and real params to real output
+		let len = u32::try_from (dummy_writer.position)?;
+		w.write_all (&len.to_le_bytes ())?;
+		self.write_fields (w)
+	}
+	
+	// In v1, only the types that came after Request1 and Response1 have
+	// a length prefix
+	
+	fn write_v1 <W: Write> (&self, w: &mut W) -> Result <(), MessageError> {
+		w.write_all (&[self.tag ()])?;
+		match self {
+			Self::AnswerId (_) |
+			Self::KnownAnswers (_) |
+			Self::Hmac (_) |
+			Self::ResponseKey (_) |
+			Self::Sealed { .. } |
+			Self::Timestamp (_) |
+			Self::Unknown { .. } => return Err (MessageError::UnknownType),
+			Self::Response2 (_) | Self::Services (_) => self.write_length_prefixed (w)?,
+			_ => self.write_fields (w)?,
+		}
+		Ok (())
+	}
+	
+	fn write_v2 <W: Write> (&self, w: &mut W) -> Result <(), MessageError> {
+		w.write_all (&[self.tag ()])?;
+		self.write_length_prefixed (w)
+	}
+	
 	fn write_response_2 <W: Write> (w: &mut W, params: &Response2) 
 	-> Result <(), MessageError>
 	{
@@ -113,6 +334,20 @@ impl Message {
 		Ok (())
 	}
 	
+	fn write_services <W: Write> (w: &mut W, services: &[Service]) 
+	-> Result <(), MessageError>
+	{
+		for service in services {
+			tlv::Writer::<_>::lv_bytes (w, service.name.as_bytes ())?;
+			w.write_all (&[match service.protocol {
+				Protocol::Tcp => 6,
+				Protocol::Udp => 17,
+			}])?;
+			w.write_all (&service.port.to_le_bytes ())?;
+		}
+		Ok (())
+	}
+	
 	fn write_mac_opt <W: Write> (w: &mut W, mac: Option <[u8; 6]>) -> Result <(), std::io::Error>
 	{
 		match mac {
@@ -125,26 +360,30 @@ impl Message {
 		Ok (())
 	}
 	
-	pub fn to_vec (&self) -> Result <Vec <u8>, MessageError> {
+	/// Encodes a whole packet in the framing of `version`
+	pub fn encode (msgs: &[Self], version: u8) -> Result <Vec <u8>, MessageError> {
 		let mut cursor = Cursor::new (Vec::with_capacity (PACKET_SIZE));
 		cursor.write_all (&MAGIC_NUMBER)?;
-		self.write (&mut cursor)?;
-		Ok (cursor.into_inner ())
-	}
-	
-	pub fn many_to_vec (msgs: &[Self]) -> Result <Vec <u8>, MessageError> {
-		let mut cursor = Cursor::new (Vec::with_capacity (PACKET_SIZE));
-		cursor.write_all (&MAGIC_NUMBER)?;
-		for msg in msgs {
-			msg.write (&mut cursor)?;
+		
+		if version == VERSION_1 {
+			for msg in msgs {
+				msg.write_v1 (&mut cursor)?;
+			}
+		}
+		else {
+			cursor.write_all (&[VERSION_TAG, version])?;
+			for msg in msgs {
+				msg.write_v2 (&mut cursor)?;
+			}
 		}
+		
 		Ok (cursor.into_inner ())
 	}
 	
-	fn read2 <R: std::io::Read> (r: &mut R) -> Result <Self, MessageError> {
-		let t = tlv::Reader::u8 (r)?;
-		
-		Ok (match t {
+	// `Ok (None)` if we don't know the type
+	
+	fn read_fields <R: std::io::Read> (t: u8, r: &mut R) -> Result <Option <Self>, MessageError> {
+		Ok (Some (match t {
 			1 => {
 				let mut idem_id = [0u8; 8];
 				r.read_exact (&mut idem_id)?;
@@ -160,8 +399,6 @@ impl Message {
 				Self::Response1 (mac)
 			},
 			3 => {
-				tlv::Reader::<_>::length (r)?;
-				
 				let mut idem_id = [0; 8];
 				r.read_exact (&mut idem_id)?;
 				
@@ -173,10 +410,143 @@ impl Message {
 					nickname,
 				})
 			},
-			_ => return Err (MessageError::UnknownType),
+			4 => {
+				// Only ever called with the record's own bytes, so this
+				// stops at the end of the record
+				let mut buf = vec! [];
+				r.read_to_end (&mut buf)?;
+				
+				Self::Services (Self::read_services (&buf)?)
+			},
+			5 => {
+				let mut public_key = [0u8; 32];
+				r.read_exact (&mut public_key)?;
+				let mut signature = [0u8; 64];
+				r.read_exact (&mut signature)?;
+				
+				Self::Signature {
+					public_key,
+					signature,
+				}
+			},
+			6 => {
+				let mut idem_id = [0u8; 8];
+				r.read_exact (&mut idem_id)?;
+				Self::Announce { idem_id }
+			},
+			7 => {
+				let mut idem_id = [0u8; 8];
+				r.read_exact (&mut idem_id)?;
+				Self::Goodbye { idem_id }
+			},
+			8 => {
+				let mut id = [0u8; 8];
+				r.read_exact (&mut id)?;
+				Self::AnswerId (id)
+			},
+			9 => {
+				let mut buf = vec! [];
+				r.read_to_end (&mut buf)?;
+				
+				Self::KnownAnswers (buf.chunks_exact (8)
+				.map (|x| x.try_into ().unwrap ())
+				.collect ())
+			},
+			10 => {
+				let mut x = [0u8; 32];
+				r.read_exact (&mut x)?;
+				Self::Hmac (x)
+			},
+			11 => {
+				let mut x = [0u8; 32];
+				r.read_exact (&mut x)?;
+				Self::ResponseKey (x)
+			},
+			12 => {
+				let mut ephemeral_key = [0u8; 32];
+				r.read_exact (&mut ephemeral_key)?;
+				let mut ciphertext = vec! [];
+				r.read_to_end (&mut ciphertext)?;
+				Self::Sealed {
+					ephemeral_key,
+					ciphertext,
+				}
+			},
+			13 => {
+				let mut x = [0u8; 8];
+				r.read_exact (&mut x)?;
+				Self::Timestamp (u64::from_le_bytes (x))
+			},
+			_ => return Ok (None),
+		}))
+	}
+	
+	fn read_length_prefixed <R: std::io::Read> (r: &mut R) -> Result <Vec <u8>, MessageError> {
+		let len = usize::try_from (tlv::Reader::<_>::length (r)?)?;
+		if len > PACKET_SIZE {
+			return Err (MessageError::LengthPrefixTooLong ((len, PACKET_SIZE)));
+		}
+		let mut buf = vec! [0u8; len];
+		r.read_exact (&mut buf)?;
+		Ok (buf)
+	}
+	
+	// v1 can't skip an unknown type, since it doesn't know how long it is
+	
+	fn read_v1 <R: std::io::Read> (r: &mut R) -> Result <Self, MessageError> {
+		let t = tlv::Reader::u8 (r)?;
+		
+		let msg = match t {
+			3 | 4 => Self::read_fields (t, &mut Cursor::new (Self::read_length_prefixed (r)?))?,
+			1..=7 => Self::read_fields (t, r)?,
+			// Anything newer is v2 only
+			_ => None,
+		};
+		
+		msg.ok_or (MessageError::UnknownType)
+	}
+	
+	// Anything newer than us parses as far as we understand it. Unknown
+	// types, and any fields after the ones we know, are skipped.
+	
+	fn read_v2 <R: std::io::Read> (r: &mut R) -> Result <Self, MessageError> {
+		let tag = tlv::Reader::u8 (r)?;
+		let body = Self::read_length_prefixed (r)?;
+		
+		Ok (match Self::read_fields (tag, &mut Cursor::new (&body))? {
+			Some (x) => x,
+			None => Self::Unknown {
+				tag,
+				body,
+			},
 		})
 	}
 	
+	fn read_services (buf: &[u8]) -> Result <Vec <Service>, MessageError> {
+		let mut r = Cursor::new (buf);
+		let mut services = vec! [];
+		
+		while r.position () < u64::try_from (buf.len ())? {
+			let name = tlv::Reader::<_>::lv_bytes_to_vec (&mut r, 64)?;
+			let name = String::from_utf8 (name)?;
+			let protocol = match tlv::Reader::u8 (&mut r)? {
+				6 => Protocol::Tcp,
+				17 => Protocol::Udp,
+				_ => return Err (MessageError::UnknownType),
+			};
+			let mut port = [0u8; 2];
+			r.read_exact (&mut port)?;
+			
+			services.push (Service {
+				name,
+				protocol,
+				port: u16::from_le_bytes (port),
+			});
+		}
+		
+		Ok (services)
+	}
+	
 	fn read_mac_opt <R: std::io::Read> (r: &mut R) 
 	-> Result <Option <[u8; 6]>, std::io::Error> 
 	{
@@ -190,17 +560,35 @@ impl Message {
 		})
 	}
 	
-	pub fn from_slice2 (buf: &[u8]) -> Result <Vec <Self>, MessageError> {
+	/// Decodes a packet in any version's framing
+	pub fn decode (buf: &[u8]) -> Result <Packet, MessageError> {
 		let mut cursor = Cursor::new (buf);
 		tlv::Reader::expect (&mut cursor, &MAGIC_NUMBER)?;
 		
+		let version = if buf.get (MAGIC_NUMBER.len ()) == Some (&VERSION_TAG) {
+			cursor.set_position (MAGIC_NUMBER.len () as u64 + 1);
+			tlv::Reader::u8 (&mut cursor)?
+		}
+		else {
+			VERSION_1
+		};
+		
 		let mut msgs = Vec::with_capacity (2);
 		
 		while cursor.position () < u64::try_from (buf.len ())? {
-			let msg = Self::read2 (&mut cursor)?;
+			let msg = if version == VERSION_1 {
+				Self::read_v1 (&mut cursor)?
+			}
+			else {
+				Self::read_v2 (&mut cursor)?
+			};
 			msgs.push (msg);
 		}
-		Ok (msgs)
+		
+		Ok (Packet {
+			version,
+			msgs,
+		})
 	}
 }
 
@@ -256,8 +644,33 @@ mod test {
 					58, 86,
 				],
 			),
+			(
+				vec! [
+					Message::Services (vec! [
+						Service {
+							name: "ssh".to_string (),
+							protocol: Protocol::Tcp,
+							port: 22,
+						},
+					]),
+				],
+				vec! [
+					154, 74, 67, 129,
+					// Services tag
+					4,
+					// Length prefix
+					10, 0, 0, 0,
+					// Length-prefixed name
+					3, 0, 0, 0,
+					115, 115, 104,
+					// TCP
+					6,
+					// Port
+					22, 0,
+				],
+			),
 		] { 
-			let actual = Message::many_to_vec (&input)?;
+			let actual = Message::encode (&input, VERSION_1)?;
 			assert_eq! (actual, expected, "{:?}", input);
 		}
 		
@@ -307,7 +720,61 @@ mod test {
 				],
 			),
 		].into_iter () {
-			let actual = input.to_vec ()?;
+			let actual = Message::encode (&[input], VERSION_1)?;
+			assert_eq! (actual, expected);
+		}
+		
+		Ok (())
+	}
+	
+	#[test]
+	fn test_write_v2 () -> Result <(), MessageError> {
+		for (input, expected) in [
+			(
+				vec! [
+					Message::Request1 {
+						idem_id: [1, 2, 3, 4, 5, 6, 7, 8,],
+						mac: None,
+					},
+				],
+				vec! [
+					154, 74, 67, 129,
+					// Version tag and version
+					0, 2,
+					// Request tag
+					1,
+					// Length prefix
+					9, 0, 0, 0,
+					// Idem ID
+					1, 2, 3, 4, 5, 6, 7, 8,
+					// MAC is None
+					0,
+				],
+			),
+			(
+				vec! [
+					Message::Response1 (None),
+					Message::Unknown {
+						tag: 99,
+						body: vec! [1, 2, 3],
+					},
+				],
+				vec! [
+					154, 74, 67, 129,
+					0, 2,
+					// Response1 tag
+					2,
+					1, 0, 0, 0,
+					// MAC is None
+					0,
+					// Some type from the future
+					99,
+					3, 0, 0, 0,
+					1, 2, 3,
+				],
+			),
+		] {
+			let actual = Message::encode (&input, VERSION_2)?;
 			assert_eq! (actual, expected, "{:?}", input);
 		}
 		
@@ -329,19 +796,145 @@ mod test {
 			vec! [
 				Message::Response1 (None),
 			],
+			vec! [
+				Message::Announce {
+					idem_id: [1, 2, 3, 4, 5, 6, 7, 8,],
+				},
+				Message::Response1 (None),
+			],
+			vec! [
+				Message::Goodbye {
+					idem_id: [1, 2, 3, 4, 5, 6, 7, 8,],
+				},
+				Message::Response1 (None),
+			],
 			vec! [
 				Message::Response1 (Some ([0x11, 0x22, 0x33, 0x44, 0x55, 0x66])),
 				Message::Response2 (Response2 {
 					idem_id: [1, 2, 3, 4, 5, 6, 7, 8,],
 					nickname: ":V".to_string (),
 				}),
+				Message::Services (vec! [
+					Service {
+						name: "ssh".to_string (),
+						protocol: Protocol::Tcp,
+						port: 22,
+					},
+					Service {
+						name: "dns".to_string (),
+						protocol: Protocol::Udp,
+						port: 53,
+					},
+				]),
+				Message::Signature {
+					public_key: [1; 32],
+					signature: [2; 64],
+				},
 			],
 		].into_iter () {
-			let encoded = Message::many_to_vec (&input)?;
-			let decoded = Message::from_slice2 (&encoded)?;
-			assert_eq! (input, decoded);
+			for version in VERSIONS {
+				let encoded = Message::encode (&input, version)?;
+				let decoded = Message::decode (&encoded)?;
+				assert_eq! (decoded.version, version);
+				assert_eq! (input, decoded.msgs);
+			}
 		}
 		
+		// These only exist in v2
+		let input = vec! [
+			Message::Request1 {
+				idem_id: [1, 2, 3, 4, 5, 6, 7, 8,],
+				mac: None,
+			},
+			Message::KnownAnswers (vec! [[3; 8], [4; 8]]),
+			Message::AnswerId ([5; 8]),
+			Message::Hmac ([6; 32]),
+			Message::ResponseKey ([7; 32]),
+			Message::Sealed {
+				ephemeral_key: [8; 32],
+				ciphertext: vec! [9, 10, 11],
+			},
+			Message::Timestamp (1_700_000_000),
+		];
+		assert_eq! (Message::decode (&Message::encode (&input, VERSION_2)?)?.msgs, input);
+		assert! (matches! (Message::encode (&input, VERSION_1), Err (MessageError::UnknownType)));
+		
 		Ok (())
 	}
+	
+	#[test]
+	fn test_read_unknown () -> Result <(), MessageError> {
+		// A newer peer sends a type we don't know between two we do. We
+		// skip it, and keep it around so signatures still check out.
+		let encoded = vec! [
+			154, 74, 67, 129,
+			0, 3,
+			2,
+			1, 0, 0, 0,
+			0,
+			99,
+			3, 0, 0, 0,
+			1, 2, 3,
+			6,
+			8, 0, 0, 0,
+			1, 2, 3, 4, 5, 6, 7, 8,
+		];
+		
+		let decoded = Message::decode (&encoded)?;
+		assert_eq! (decoded, Packet {
+			version: 3,
+			msgs: vec! [
+				Message::Response1 (None),
+				Message::Unknown {
+					tag: 99,
+					body: vec! [1, 2, 3],
+				},
+				Message::Announce {
+					idem_id: [1, 2, 3, 4, 5, 6, 7, 8,],
+				},
+			],
+		});
+		assert_eq! (decoded.unknown_tags (), vec! [99]);
+		assert_eq! (Message::encode (&decoded.msgs, 3)?, encoded);
+		
+		// Newer fields on the end of a type we know are skipped too
+		let encoded = vec! [
+			154, 74, 67, 129,
+			0, 3,
+			6,
+			10, 0, 0, 0,
+			1, 2, 3, 4, 5, 6, 7, 8,
+			42, 42,
+		];
+		assert_eq! (Message::decode (&encoded)?.msgs, vec! [
+			Message::Announce {
+				idem_id: [1, 2, 3, 4, 5, 6, 7, 8,],
+			},
+		]);
+		
+		// v1 can't skip anything
+		let encoded = vec! [
+			154, 74, 67, 129,
+			2,
+			0,
+			99,
+		];
+		assert! (matches! (Message::decode (&encoded), Err (MessageError::UnknownType)));
+		
+		Ok (())
+	}
+	
+	#[test]
+	fn test_parse_service () {
+		assert_eq! (Service::parse ("web", "TCP/8080").unwrap (), Service {
+			name: "web".to_string (),
+			protocol: Protocol::Tcp,
+			port: 8080,
+		});
+		assert_eq! (Service::parse ("dns", " udp/53").unwrap ().protocol, Protocol::Udp);
+		
+		for bad in ["tcp", "sctp/22", "tcp/ssh", "tcp/65536"] {
+			assert! (Service::parse ("x", bad).is_err (), "{}", bad);
+		}
+	}
 }
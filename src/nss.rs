@@ -0,0 +1,221 @@
+// The lookups behind the NSS module in `nss/`, which lets glibc resolve
+// nicknames for every program. They live here so they can be tested
+// against a real responder without loading anything into glibc.
+//
+// Every program that resolves a name blocks on us, so everything here is
+// built to give up fast: a short timeout, a cache, and names with dots in
+// them are turned away without touching the network, unless they end in
+// our suffix.
+
+use std::sync::{
+	Mutex,
+	PoisonError,
+};
+
+use crate::{
+	client::discover,
+	prelude::*,
+	resolver::{
+		Cache,
+		nick_for_name,
+	},
+};
+
+/// Much shorter than `client`'s timeout, since somebody is always
+/// waiting on us
+pub const NSS_TIMEOUT: Duration = Duration::from_millis (250);
+
+// Once the peer we want answers, only wait this much longer for its
+// other addresses, instead of the whole timeout
+
+const SETTLE_TIME: Duration = Duration::from_millis (50);
+
+pub struct NssResolver {
+	options: DiscoverOptions,
+	suffix: String,
+	cache: Mutex <Cache>,
+	
+	// Held while discovering, so threads that miss the cache together
+	// share one multicast
+	discovering: Mutex <()>,
+}
+
+impl NssResolver {
+	pub fn new (options: DiscoverOptions, ttl: Duration) -> Self {
+		Self {
+			options,
+			suffix: "lookaround".to_string (),
+			cache: Mutex::new (Cache::new (ttl)),
+			discovering: Mutex::new (()),
+		}
+	}
+	
+	/// client.ini nicknames, `NSS_TIMEOUT`, and answers cached for a
+	/// minute. We're a guest in whatever program is resolving a name, so
+	/// we don't print anything, or pin new keys in known_peers.ini.
+	pub fn from_config () -> Self {
+		Self::new (DiscoverOptions {
+			timeout: NSS_TIMEOUT,
+			..DiscoverOptions::from_config_as_guest ()
+		}, Duration::from_secs (60))
+	}
+	
+	/// Blocks until we know the addresses for `name`, which can be a bare
+	/// nickname like `laptop`, or `laptop.lookaround`. Also returns how
+	/// long the addresses can be cached for. The list is empty if nobody
+	/// answered, or if `name` isn't ours to answer.
+	pub fn lookup (&self, name: &str) -> Result <(Vec <IpAddr>, Duration), AppError> {
+		let nick = match nick_for_query (name, &self.suffix) {
+			None => return Ok ((vec! [], Duration::ZERO)),
+			Some (x) => x.to_ascii_lowercase (),
+		};
+		
+		let asked = Instant::now ();
+		if let Some (x) = self.cache.lock ().unwrap ().get (&nick, asked) {
+			return Ok (x);
+		}
+		
+		// A panic while discovering is passed on to glibc's caller as
+		// "unavailable", and shouldn't lock everyone else out after
+		let _discovering = self.discovering.lock ().unwrap_or_else (PoisonError::into_inner);
+		if let Some (x) = self.cache.lock ().unwrap ().get_since (&nick, asked, Instant::now ()) {
+			return Ok (x);
+		}
+		
+		// On a thread of our own, in case the program calling us is
+		// already inside a Tokio runtime, since those can't be nested
+		let options = self.options.clone ();
+		let wanted = nick.clone ();
+		let peers = std::thread::spawn (move || {
+			let rt = tokio::runtime::Builder::new_current_thread ().enable_all ().build ()?;
+			rt.block_on (collect_peers (options, &wanted))
+		}).join ().unwrap_or_else (|e| std::panic::resume_unwind (e))?;
+		
+		let mut cache = self.cache.lock ().unwrap ();
+		let now = Instant::now ();
+		cache.insert_peers (&peers, now);
+		Ok (cache.get_since (&nick, asked, now).unwrap_or_default ())
+	}
+}
+
+// Every peer that answers, so the cache learns about all of them, but
+// we stop early once `nick` has answered
+
+async fn collect_peers (options: DiscoverOptions, nick: &str) -> Result <Vec <Peer>, AppError> {
+	let mut peers = Box::pin (discover (options).await?);
+	let mut found = vec! [];
+	let mut deadline = None;
+	
+	loop {
+		let next = match deadline {
+			None => peers.next ().await,
+			Some (x) => match tokio::time::timeout_at (x, peers.next ()).await {
+				Ok (x) => x,
+				Err (_) => break,
+			},
+		};
+		let peer = match next {
+			None => break,
+			Some (x) => x,
+		};
+		
+		if deadline.is_none () && peer.nickname.as_deref ().is_some_and (|x| x.eq_ignore_ascii_case (nick)) {
+			deadline = Some (tokio::time::Instant::now () + SETTLE_TIME);
+		}
+		found.push (peer);
+	}
+	
+	Ok (found)
+}
+
+// Bare names are nicknames. Anything with a dot is somebody else's,
+// unless it ends in our suffix, so `example.com` never costs a multicast.
+
+fn nick_for_query <'a> (name: &'a str, suffix: &str) -> Option <&'a str> {
+	let name = name.strip_suffix ('.').unwrap_or (name);
+	
+	if name.contains ('.') {
+		nick_for_name (name, suffix)
+	}
+	else {
+		Some (name).filter (|x| ! x.is_empty ())
+	}
+}
+
+#[cfg (test)]
+mod test {
+	use super::*;
+	use crate::server::Responder;
+	
+	#[test]
+	fn test_nick_for_query () {
+		for (input, expected) in [
+			("laptop", Some ("laptop")),
+			("laptop.", Some ("laptop")),
+			("laptop.lookaround", Some ("laptop")),
+			("laptop.lookaround.", Some ("laptop")),
+			("example.com", None),
+			("laptop.local", None),
+			("", None),
+			(".", None),
+		] {
+			assert_eq! (nick_for_query (input, "lookaround"), expected, "{}", input);
+		}
+	}
+	
+	#[test]
+	fn test_lookup () -> Result <(), AppError> {
+		// Off the usual port and group, so real servers on the LAN stay
+		// out of it
+		let common = app_common::Params {
+			server_port: 19040,
+			multicast_addr: Ipv4Addr::new (225, 100, 99, 97),
+			multicast_addr_v6: Ipv6Addr::new (0xff02, 0, 0, 0, 0, 0, 0xe164, 0x6361),
+			psk: None,
+			encrypt: false,
+		};
+		
+		let responder = Responder::builder ()
+		.common (common.clone ())
+		.nickname ("nss-test")
+		.build ()?;
+		
+		let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()> ();
+		let server = std::thread::spawn (move || {
+			let rt = tokio::runtime::Builder::new_current_thread ().enable_all ().build ()?;
+			rt.block_on (responder.run_until (async {
+				stop_rx.await.ok ();
+			}))
+		});
+		std::thread::sleep (Duration::from_millis (100));
+		
+		let resolver = NssResolver::new (DiscoverOptions {
+			common,
+			timeout: NSS_TIMEOUT,
+			..Default::default ()
+		}, Duration::from_secs (60));
+		
+		let (addrs, ttl) = resolver.lookup ("NSS-Test")?;
+		assert! (! addrs.is_empty ());
+		assert! (ttl > Duration::from_secs (59));
+		
+		// Cached, so this is instant even though the server is gone
+		stop_tx.send (()).ok ();
+		server.join ().unwrap ()?;
+		let started = Instant::now ();
+		assert_eq! (resolver.lookup ("nss-test.lookaround")?.0, addrs);
+		assert! (started.elapsed () < NSS_TIMEOUT);
+		
+		// Misses are cached too, but not for long
+		let (addrs, ttl) = resolver.lookup ("nobody")?;
+		assert! (addrs.is_empty ());
+		assert! (ttl <= Duration::from_secs (5));
+		
+		// Not ours, so no lookup at all
+		let started = Instant::now ();
+		assert! (resolver.lookup ("example.com")?.0.is_empty ());
+		assert! (started.elapsed () < NSS_TIMEOUT);
+		
+		Ok (())
+	}
+}
@@ -0,0 +1,320 @@
+// Machine-readable output for `client`, `find-nick`, and `my-ips`. Every
+// format has the same fields in the same order, so scripts don't need to
+// care which subcommand printed a record.
+
+use std::{
+	net::{
+		IpAddr,
+		SocketAddr,
+	},
+	str::FromStr,
+};
+
+use mac_address::MacAddress;
+
+use lookaround::{
+	Peer,
+	app_common::{
+		CliArgError,
+		format_ip,
+	},
+	ip::{
+		self,
+		Interface,
+	},
+};
+
+/// Field names, for JSON keys, table headers, and `{field}` in templates
+const FIELDS: [&str; 6] = ["mac", "ip", "nick", "nick_source", "iface", "rtt_ms"];
+
+#[derive (Debug, PartialEq)]
+pub enum Format {
+	Json,
+	Jsonl,
+	Tsv,
+	Csv,
+	Template (Vec <Segment>),
+}
+
+#[derive (Debug, PartialEq)]
+pub enum Segment {
+	Literal (String),
+	Field (usize),
+}
+
+impl FromStr for Format {
+	type Err = CliArgError;
+	
+	fn from_str (s: &str) -> Result <Self, Self::Err> {
+		Ok (match s {
+			"json" => Self::Json,
+			"jsonl" => Self::Jsonl,
+			"tsv" => Self::Tsv,
+			"csv" => Self::Csv,
+			_ if s.contains ('{') => Self::Template (parse_template (s)?),
+			_ => return Err (CliArgError::BadFormat (s.to_string ())),
+		})
+	}
+}
+
+fn parse_template (s: &str) -> Result <Vec <Segment>, CliArgError> {
+	let mut segments = vec! [];
+	let mut rest = s;
+	
+	while let Some (start) = rest.find ('{') {
+		if start > 0 {
+			segments.push (Segment::Literal (rest [..start].to_string ()));
+		}
+		let end = rest [start..].find ('}')
+		.ok_or_else (|| CliArgError::BadFormat (s.to_string ()))? + start;
+		let name = &rest [start + 1..end];
+		let index = FIELDS.iter ().position (|x| *x == name)
+		.ok_or_else (|| CliArgError::BadFormat (format! ("{{{}}}", name)))?;
+		segments.push (Segment::Field (index));
+		rest = &rest [end + 1..];
+	}
+	
+	if ! rest.is_empty () {
+		segments.push (Segment::Literal (rest.to_string ()));
+	}
+	
+	Ok (segments)
+}
+
+#[derive (Debug, Default, PartialEq)]
+pub struct Record {
+	pub mac: Option <String>,
+	pub ip: String,
+	pub nick: Option <String>,
+	pub nick_source: Option <&'static str>,
+	pub iface: Option <String>,
+	pub rtt_ms: Option <String>,
+}
+
+impl Record {
+	pub fn from_peer (peer: &Peer, interfaces: &[Interface]) -> Self {
+		Self {
+			mac: peer.mac.map (|x| MacAddress::new (x).to_string ()),
+			ip: format_ip (&peer.addr),
+			nick: peer.nickname.clone (),
+			nick_source: peer.nickname_source.map (|x| x.as_str ()),
+			iface: iface_name (interfaces, &peer.addr),
+			rtt_ms: peer.rtt.map (|x| format! ("{:.1}", x.as_secs_f64 () * 1000.0)),
+		}
+	}
+	
+	fn fields (&self) -> [Option <&str>; 6] {
+		[
+			self.mac.as_deref (),
+			Some (&self.ip),
+			self.nick.as_deref (),
+			self.nick_source,
+			self.iface.as_deref (),
+			self.rtt_ms.as_deref (),
+		]
+	}
+}
+
+// Falls back to the bare scope ID for IPv6 if we can't list interfaces
+
+fn iface_name (interfaces: &[Interface], addr: &SocketAddr) -> Option <String> {
+	if let Some (iface) = ip::iface_for (interfaces, addr) {
+		return Some (iface.name.clone ());
+	}
+	
+	match addr {
+		SocketAddr::V6 (x) if x.scope_id () != 0 => Some (x.scope_id ().to_string ()),
+		_ => None,
+	}
+}
+
+/// One record per address of each interface
+pub fn interface_records (interfaces: &[Interface]) -> Vec <Record> {
+	let mut records = vec! [];
+	
+	for iface in interfaces {
+		for addr in &iface.addrs {
+			let ip = match addr.addr {
+				IpAddr::V6 (x) if x.segments () [0] & 0xffc0 == 0xfe80 => format! ("{}%{}", x, iface.index),
+				x => x.to_string (),
+			};
+			
+			records.push (Record {
+				mac: iface.mac.map (|x| MacAddress::new (x).to_string ()),
+				ip,
+				iface: Some (iface.name.clone ()),
+				..Default::default ()
+			});
+		}
+	}
+	
+	records
+}
+
+pub fn print (format: &Format, records: &[Record]) {
+	print! ("{}", render (format, records));
+}
+
+fn render (format: &Format, records: &[Record]) -> String {
+	let mut s = String::new ();
+	
+	match format {
+		Format::Json => {
+			let objects: Vec <_> = records.iter ().map (json_object).collect ();
+			s.push ('[');
+			if ! objects.is_empty () {
+				s.push ('\n');
+				s.push_str (&objects.join (",\n"));
+				s.push ('\n');
+			}
+			s.push_str ("]\n");
+		},
+		Format::Jsonl => for record in records {
+			s.push_str (&json_object (record));
+			s.push ('\n');
+		},
+		Format::Tsv => {
+			s.push_str (&FIELDS.join ("\t"));
+			s.push ('\n');
+			for record in records {
+				let row: Vec <_> = record.fields ().iter ()
+				.map (|x| x.unwrap_or_default ().replace (['\t', '\n'], " "))
+				.collect ();
+				s.push_str (&row.join ("\t"));
+				s.push ('\n');
+			}
+		},
+		Format::Csv => {
+			s.push_str (&FIELDS.join (","));
+			s.push ('\n');
+			for record in records {
+				let row: Vec <_> = record.fields ().iter ()
+				.map (|x| csv_field (x.unwrap_or_default ()))
+				.collect ();
+				s.push_str (&row.join (","));
+				s.push ('\n');
+			}
+		},
+		Format::Template (segments) => for record in records {
+			let fields = record.fields ();
+			for segment in segments {
+				match segment {
+					Segment::Literal (x) => s.push_str (x),
+					Segment::Field (i) => s.push_str (fields [*i].unwrap_or_default ()),
+				}
+			}
+			s.push ('\n');
+		},
+	}
+	
+	s
+}
+
+fn json_object (record: &Record) -> String {
+	let pairs: Vec <_> = FIELDS.iter ().zip (record.fields ().iter ())
+	.map (|(k, v)| match v {
+		None => format! ("\"{}\":null", k),
+		Some (v) => format! ("\"{}\":{}", k, json_string (v)),
+	})
+	.collect ();
+	
+	format! ("{{{}}}", pairs.join (","))
+}
+
+fn json_string (s: &str) -> String {
+	let mut out = String::from ("\"");
+	for c in s.chars () {
+		match c {
+			'"' => out.push_str ("\\\""),
+			'\\' => out.push_str ("\\\\"),
+			'\n' => out.push_str ("\\n"),
+			'\r' => out.push_str ("\\r"),
+			'\t' => out.push_str ("\\t"),
+			c if (c as u32) < 0x20 => out.push_str (&format! ("\\u{:04x}", c as u32)),
+			c => out.push (c),
+		}
+	}
+	out.push ('"');
+	out
+}
+
+fn csv_field (s: &str) -> String {
+	if s.contains ([',', '"', '\n', '\r']) {
+		format! ("\"{}\"", s.replace ('"', "\"\""))
+	}
+	else {
+		s.to_string ()
+	}
+}
+
+#[cfg (test)]
+mod test {
+	use super::*;
+	
+	fn records () -> Vec <Record> {
+		vec! [
+			Record {
+				mac: Some ("01:02:03:04:05:06".to_string ()),
+				ip: "192.168.1.101".to_string (),
+				nick: Some ("laptop".to_string ()),
+				nick_source: Some ("server"),
+				iface: Some ("eth0".to_string ()),
+				rtt_ms: Some ("1.5".to_string ()),
+			},
+			Record {
+				ip: "fe80::1%2".to_string (),
+				nick: Some ("my \"pc\", again".to_string ()),
+				nick_source: Some ("client.ini"),
+				..Default::default ()
+			},
+		]
+	}
+	
+	#[test]
+	fn test_render () {
+		for (format, expected) in [
+			("json", concat! (
+				"[\n",
+				r#"{"mac":"01:02:03:04:05:06","ip":"192.168.1.101","nick":"laptop","nick_source":"server","iface":"eth0","rtt_ms":"1.5"},"#, "\n",
+				r#"{"mac":null,"ip":"fe80::1%2","nick":"my \"pc\", again","nick_source":"client.ini","iface":null,"rtt_ms":null}"#, "\n",
+				"]\n",
+			)),
+			("jsonl", concat! (
+				r#"{"mac":"01:02:03:04:05:06","ip":"192.168.1.101","nick":"laptop","nick_source":"server","iface":"eth0","rtt_ms":"1.5"}"#, "\n",
+				r#"{"mac":null,"ip":"fe80::1%2","nick":"my \"pc\", again","nick_source":"client.ini","iface":null,"rtt_ms":null}"#, "\n",
+			)),
+			("tsv", concat! (
+				"mac\tip\tnick\tnick_source\tiface\trtt_ms\n",
+				"01:02:03:04:05:06\t192.168.1.101\tlaptop\tserver\teth0\t1.5\n",
+				"\tfe80::1%2\tmy \"pc\", again\tclient.ini\t\t\n",
+			)),
+			("csv", concat! (
+				"mac,ip,nick,nick_source,iface,rtt_ms\n",
+				"01:02:03:04:05:06,192.168.1.101,laptop,server,eth0,1.5\n",
+				",fe80::1%2,\"my \"\"pc\"\", again\",client.ini,,\n",
+			)),
+			("{ip} {nick}", concat! (
+				"192.168.1.101 laptop\n",
+				"fe80::1%2 my \"pc\", again\n",
+			)),
+		] {
+			let format = Format::from_str (format).unwrap ();
+			assert_eq! (render (&format, &records ()), expected, "{:?}", format);
+		}
+		
+		assert_eq! (render (&Format::Json, &[]), "[]\n");
+	}
+	
+	#[test]
+	fn test_parse_format () {
+		assert_eq! (Format::from_str ("ssh {nick}.local").unwrap (), Format::Template (vec! [
+			Segment::Literal ("ssh ".to_string ()),
+			Segment::Field (2),
+			Segment::Literal (".local".to_string ()),
+		]));
+		
+		for input in ["yaml", "{hostname}", "{ip"] {
+			assert! (Format::from_str (input).is_err (), "{}", input);
+		}
+	}
+}
@@ -1,20 +1,30 @@
 pub use std::{
-	collections::HashMap,
+	collections::{
+		HashMap,
+		HashSet,
+	},
 	env,
+	future::Future,
 	io::{
 		Cursor,
+		Read,
 		Write,
 	},
 	net::{
+		IpAddr,
 		Ipv4Addr,
+		Ipv6Addr,
 		SocketAddr,
 		SocketAddrV4,
+		SocketAddrV6,
 	},
+	path::PathBuf,
 	str::FromStr,
 	sync::Arc,
 	time::{
 		Duration,
 		Instant,
+		SystemTime,
 	},
 };
 
@@ -27,26 +37,53 @@ pub use mac_address::{
 pub use rand::RngCore;
 pub use tokio::{
 	net::UdpSocket,
+	sync::mpsc,
 	time::{
 		sleep,
 		timeout,
 	},
 };
+pub use tokio_stream::{
+	Stream,
+	StreamExt,
+	wrappers::UnboundedReceiverStream,
+};
 
 pub use crate::{
 	app_common::{
 		self,
-		LOOKAROUND_VERSION,
 		AppError,
-		CliArgError,
+		bind_udp_v6,
 		find_project_dirs,
-		recv_msg_from,
+		recv_packet_from,
+	},
+	client::{
+		DiscoverOptions,
+		Peer,
+	},
+	identity::{
+		self,
+		Identity,
+		KnownPeers,
+		PublicKey,
+		Trust,
+	},
+	ip::{
+		get_ips,
+		get_ipv6_ifaces,
 	},
-	ip::get_ips,
 	message::{
 		self,
 		PACKET_SIZE,
 		Message,
+		MessageError,
+		Packet,
+		Service,
+	},
+	psk::{
+		self,
+		Psk,
 	},
+	sealed::ResponseKey,
 	tlv,
 };
@@ -0,0 +1,186 @@
+// Private fleets. Every machine in the fleet shares a key, set as `psk`
+// in server.ini and client.ini, and tags every packet it sends with an
+// HMAC-SHA256 over the packet. Servers ignore requests that don't carry a
+// good tag, and clients ignore responses that don't, so nobody outside the
+// fleet can list it, or pose as one of its servers.
+//
+// A tag doesn't cover who sent the packet, so anybody who overhears a
+// request could send it again from their own address. Requests carry a
+// timestamp under the tag, and servers ignore ones that are too old, or
+// that already came from somebody else while they were fresh.
+//
+// Unlike signatures, this doesn't tell fleet members apart. It only keeps
+// outsiders out.
+
+use hmac::{
+	Hmac,
+	Mac,
+};
+use sha2::Sha256;
+
+use crate::prelude::*;
+
+// Keeps a tag from being valid in any other context
+
+const HMAC_CONTEXT: &[u8] = b"lookaround fleet v1";
+
+/// How far a request's timestamp can be from the server's clock. Clocks
+/// on one LAN are rarely further apart, and a request can only be
+/// replayed within this.
+pub const MAX_SKEW: Duration = Duration::from_secs (30);
+
+/// A fleet's pre-shared key
+#[derive (Clone)]
+pub struct Psk {
+	key: Vec <u8>,
+}
+
+impl std::fmt::Debug for Psk {
+	fn fmt (&self, f: &mut std::fmt::Formatter <'_>) -> std::fmt::Result {
+		f.write_str ("Psk (..)")
+	}
+}
+
+impl Psk {
+	pub fn new (key: &[u8]) -> Self {
+		Self {
+			key: key.to_vec (),
+		}
+	}
+	
+	/// Tags `msgs`, as they'll be encoded in `version`, along with the
+	/// `idem_id` they're about. The tag goes last in the packet.
+	pub fn tag (&self, idem_id: [u8; 8], msgs: &[Message], version: u8) -> Result <Message, MessageError> {
+		let mut mac = self.mac ();
+		mac.update (&tagged_bytes (idem_id, msgs, version)?);
+		Ok (Message::Hmac (mac.finalize ().into_bytes ().into ()))
+	}
+	
+	/// True if `packet` ends with a good tag from `tag`. Only v2 can carry
+	/// a tag, so v1 packets never pass.
+	pub fn verify (&self, idem_id: [u8; 8], packet: &Packet) -> bool {
+		let (expected, msgs) = match packet.msgs.split_last () {
+			Some ((Message::Hmac (x), msgs)) => (x, msgs),
+			_ => return false,
+		};
+		let bytes = match tagged_bytes (idem_id, msgs, packet.version) {
+			Ok (x) => x,
+			Err (_) => return false,
+		};
+		
+		// Constant-time, so the time taken doesn't hint at how much of a
+		// forged tag was right
+		let mut mac = self.mac ();
+		mac.update (&bytes);
+		mac.verify_slice (expected).is_ok ()
+	}
+	
+	fn mac (&self) -> Hmac <Sha256> {
+		// HMAC takes keys of any length
+		Hmac::new_from_slice (&self.key).unwrap ()
+	}
+}
+
+/// Now, as a `Message::Timestamp`
+pub fn timestamp (now: SystemTime) -> Message {
+	Message::Timestamp (now.duration_since (SystemTime::UNIX_EPOCH).map (|x| x.as_secs ()).unwrap_or_default ())
+}
+
+/// True if `packet` has a timestamp within `MAX_SKEW` of `now`
+pub fn is_fresh (packet: &Packet, now: SystemTime) -> bool {
+	let sent = packet.msgs.iter ().find_map (|x| match x {
+		Message::Timestamp (x) => SystemTime::UNIX_EPOCH.checked_add (Duration::from_secs (*x)),
+		_ => None,
+	});
+	let sent = match sent {
+		Some (x) => x,
+		None => return false,
+	};
+	
+	let skew = match now.duration_since (sent) {
+		Ok (x) => x,
+		Err (e) => e.duration (),
+	};
+	skew <= MAX_SKEW
+}
+
+fn tagged_bytes (idem_id: [u8; 8], msgs: &[Message], version: u8) -> Result <Vec <u8>, MessageError> {
+	let mut v = HMAC_CONTEXT.to_vec ();
+	v.extend_from_slice (&idem_id);
+	v.extend_from_slice (&Message::encode (msgs, version)?);
+	Ok (v)
+}
+
+#[cfg (test)]
+mod test {
+	use super::*;
+	
+	#[test]
+	fn test_psk () -> Result <(), MessageError> {
+		let psk = Psk::new (b"correct horse battery staple");
+		let idem_id = [1; 8];
+		let msgs = vec! [
+			Message::Request1 {
+				idem_id,
+				mac: None,
+			},
+		];
+		
+		let tagged = |psk: &Psk, version| -> Result <Packet, MessageError> {
+			let mut msgs = msgs.clone ();
+			msgs.push (psk.tag (idem_id, &msgs, version)?);
+			Message::decode (&Message::encode (&msgs, version)?)
+		};
+		
+		let packet = tagged (&psk, message::VERSION_2)?;
+		assert! (psk.verify (idem_id, &packet));
+		
+		// Tagged with a different idem_id, or a different key
+		assert! (! psk.verify ([2; 8], &packet));
+		assert! (! Psk::new (b"hunter2").verify (idem_id, &packet));
+		
+		// Untagged, or tampered with
+		assert! (! psk.verify (idem_id, &Packet {
+			version: message::VERSION_2,
+			msgs: msgs.clone (),
+		}));
+		let mut tampered = packet.clone ();
+		tampered.msgs.insert (1, Message::KnownAnswers (vec! [[3; 8]]));
+		assert! (! psk.verify (idem_id, &tampered));
+		
+		// v1 can't carry a tag at all
+		assert! (tagged (&psk, message::VERSION_1).is_err ());
+		
+		Ok (())
+	}
+	
+	#[test]
+	fn test_is_fresh () {
+		let now = SystemTime::UNIX_EPOCH + Duration::from_secs (1_700_000_000);
+		
+		for (sent, expected) in [
+			(Some (1_700_000_000), true),
+			(Some (1_699_999_970), true),
+			(Some (1_700_000_030), true),
+			// Old enough to be a replay, or our clocks are way off
+			(Some (1_699_999_969), false),
+			(Some (1_700_000_031), false),
+			(Some (0), false),
+			(Some (u64::MAX), false),
+			(None, false),
+		] {
+			let mut msgs = vec! [Message::Request1 {
+				idem_id: [1; 8],
+				mac: None,
+			}];
+			msgs.extend (sent.map (Message::Timestamp));
+			let packet = Packet {
+				version: message::VERSION_2,
+				msgs,
+			};
+			assert_eq! (is_fresh (&packet, now), expected, "{:?}", sent);
+		}
+		
+		assert_eq! (timestamp (now), Message::Timestamp (1_700_000_000));
+	}
+}
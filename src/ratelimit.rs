@@ -0,0 +1,261 @@
+// Limits on how often the server answers. A response is bigger than the
+// request that asked for it, so without limits, anybody who can spoof a
+// source address could aim a flood of requests at us and have us bounce
+// more traffic at the victim.
+//
+// Each source IP gets its own token bucket, and on top of that there's a
+// global budget, since a flood can come from a different spoofed source
+// every time.
+
+use std::collections::BTreeSet;
+
+use crate::prelude::*;
+
+/// Sizes and refill rates for the buckets
+#[derive (Clone, Copy, Debug)]
+pub struct Limits {
+	/// Responses one source can get in a burst
+	pub source_burst: f64,
+	
+	/// Responses per second one source can keep getting
+	pub source_rate: f64,
+	
+	/// Responses everybody together can get in a burst
+	pub global_burst: f64,
+	
+	/// Responses per second everybody together can keep getting
+	pub global_rate: f64,
+	
+	/// How many sources to keep buckets for. Past this, sources we
+	/// haven't heard from lately are forgotten.
+	pub max_sources: usize,
+}
+
+impl Default for Limits {
+	fn default () -> Self {
+		// A client sends one request per query, so even a script looking
+		// up peers in a loop stays well under this
+		Self {
+			source_burst: 20.0,
+			source_rate: 10.0,
+			global_burst: 200.0,
+			global_rate: 100.0,
+			max_sources: 1024,
+		}
+	}
+}
+
+#[derive (Debug, PartialEq)]
+pub enum Verdict {
+	Allow,
+	
+	// `first` is true for the first drop since the last allowed response,
+	// so callers can log once per burst instead of once per packet
+	
+	SourceLimited { first: bool },
+	GlobalLimited { first: bool },
+}
+
+struct TokenBucket {
+	tokens: f64,
+	last: Instant,
+	limited: bool,
+}
+
+impl TokenBucket {
+	fn new (burst: f64, now: Instant) -> Self {
+		Self {
+			tokens: burst,
+			last: now,
+			limited: false,
+		}
+	}
+	
+	fn refill (&mut self, burst: f64, rate: f64, now: Instant) {
+		let elapsed = now.saturating_duration_since (self.last).as_secs_f64 ();
+		self.tokens = (self.tokens + elapsed * rate).min (burst);
+		self.last = now;
+	}
+	
+	fn has_token (&self) -> bool {
+		self.tokens >= 1.0
+	}
+	
+	// Returns true if this is the first drop in a row
+	
+	fn drop_one (&mut self) -> bool {
+		! std::mem::replace (&mut self.limited, true)
+	}
+	
+	fn take (&mut self) {
+		self.tokens -= 1.0;
+		self.limited = false;
+	}
+	
+	// When this bucket will be back up to `burst`, if it ever will. Only
+	// changes when the bucket is used.
+	
+	fn full_at (&self, burst: f64, rate: f64) -> Option <Instant> {
+		let missing = (burst - self.tokens).max (0.0);
+		let wait = Duration::try_from_secs_f64 (missing / rate).ok ()?;
+		self.last.checked_add (wait)
+	}
+}
+
+pub struct RateLimiter {
+	limits: Limits,
+	sources: HashMap <IpAddr, TokenBucket>,
+	
+	// Every source by when its bucket will be full, so the next one to
+	// forget is always first
+	refills: BTreeSet <(Instant, IpAddr)>,
+	global: TokenBucket,
+}
+
+impl RateLimiter {
+	pub fn new (limits: Limits) -> Self {
+		Self {
+			limits,
+			sources: Default::default (),
+			refills: Default::default (),
+			global: TokenBucket::new (limits.global_burst, Instant::now ()),
+		}
+	}
+	
+	/// Decides whether we can answer `source` at `now`, and if so, charges
+	/// it and the global budget for one response
+	pub fn check (&mut self, source: IpAddr, now: Instant) -> Verdict {
+		let l = self.limits;
+		
+		if ! self.sources.contains_key (&source) && ! self.make_room (now) {
+			// Too many sources at once to track them all. Only a flood
+			// looks like that.
+			return Verdict::GlobalLimited { first: self.global.drop_one () };
+		}
+		
+		let bucket = self.sources.entry (source).or_insert_with (|| TokenBucket::new (l.source_burst, now));
+		if let Some (x) = bucket.full_at (l.source_burst, l.source_rate) {
+			self.refills.remove (&(x, source));
+		}
+		
+		let verdict = charge (bucket, &mut self.global, l, now);
+		
+		if let Some (x) = bucket.full_at (l.source_burst, l.source_rate) {
+			self.refills.insert ((x, source));
+		}
+		verdict
+	}
+	
+	// Forgets the source whose bucket filled back up first, since a new
+	// bucket for it would be the same. Returns false if nobody's bucket
+	// is full yet.
+	
+	fn make_room (&mut self, now: Instant) -> bool {
+		if self.sources.len () < self.limits.max_sources {
+			return true;
+		}
+		
+		match self.refills.first () {
+			Some (&(full_at, source)) if full_at <= now => {
+				self.refills.pop_first ();
+				self.sources.remove (&source);
+				true
+			},
+			_ => false,
+		}
+	}
+}
+
+fn charge (bucket: &mut TokenBucket, global: &mut TokenBucket, l: Limits, now: Instant) -> Verdict {
+	bucket.refill (l.source_burst, l.source_rate, now);
+	if ! bucket.has_token () {
+		return Verdict::SourceLimited { first: bucket.drop_one () };
+	}
+	
+	global.refill (l.global_burst, l.global_rate, now);
+	if ! global.has_token () {
+		return Verdict::GlobalLimited { first: global.drop_one () };
+	}
+	
+	bucket.take ();
+	global.take ();
+	Verdict::Allow
+}
+
+#[cfg (test)]
+mod test {
+	use super::*;
+	
+	fn limits () -> Limits {
+		Limits {
+			source_burst: 2.0,
+			source_rate: 1.0,
+			global_burst: 3.0,
+			global_rate: 2.0,
+			max_sources: 2,
+		}
+	}
+	
+	#[test]
+	fn test_rate_limiter () {
+		let a = IpAddr::from ([192, 168, 1, 101]);
+		let b = IpAddr::from ([192, 168, 1, 102]);
+		let c = IpAddr::from ([192, 168, 1, 103]);
+		let start = Instant::now ();
+		let mut limiter = RateLimiter::new (limits ());
+		
+		for (ms, source, expected) in [
+			// `a` gets its burst, then has to wait
+			(0, a, Verdict::Allow),
+			(0, a, Verdict::Allow),
+			(0, a, Verdict::SourceLimited { first: true }),
+			(0, a, Verdict::SourceLimited { first: false }),
+			// `b` has its own bucket, but only one token is left globally
+			(0, b, Verdict::Allow),
+			(0, b, Verdict::GlobalLimited { first: true }),
+			// Too many sources, and nobody's bucket is full yet
+			(0, c, Verdict::GlobalLimited { first: false }),
+			// Both buckets refill over time
+			(1000, a, Verdict::Allow),
+			(1000, b, Verdict::Allow),
+			(1000, a, Verdict::SourceLimited { first: true }),
+			// Once the other buckets are full again, they make room for `c`
+			(3000, c, Verdict::Allow),
+		] {
+			let actual = limiter.check (source, start + Duration::from_millis (ms));
+			assert_eq! (actual, expected, "{} {}", ms, source);
+		}
+	}
+	
+	#[test]
+	fn test_eviction () {
+		let a = IpAddr::from ([192, 168, 1, 101]);
+		let b = IpAddr::from ([192, 168, 1, 102]);
+		let c = IpAddr::from ([192, 168, 1, 103]);
+		let start = Instant::now ();
+		let mut limiter = RateLimiter::new (limits ());
+		
+		for (ms, source, expected) in [
+			// `a` will be full again at 1000 ms, `b` at 2000 ms
+			(0, a, Verdict::Allow),
+			(0, b, Verdict::Allow),
+			(0, b, Verdict::Allow),
+			// Only `a` is full, so only `a` is forgotten
+			(1500, c, Verdict::Allow),
+			// `b` still has its own bucket, with 1.5 tokens
+			(1500, b, Verdict::Allow),
+			(1500, b, Verdict::SourceLimited { first: true }),
+			// Now nobody's full, so `a` can't come back yet
+			(1500, a, Verdict::GlobalLimited { first: true }),
+			// `c` fills up first, at 2500 ms
+			(2600, a, Verdict::Allow),
+			(2600, b, Verdict::Allow),
+		] {
+			let actual = limiter.check (source, start + Duration::from_millis (ms));
+			assert_eq! (actual, expected, "{} {}", ms, source);
+		}
+		
+		assert_eq! (limiter.refills.len (), limiter.sources.len ());
+		assert! (! limiter.sources.contains_key (&c));
+	}
+}
@@ -0,0 +1,403 @@
+// A stub DNS server, so anything that can use a DNS server can resolve
+// `<nick>.lookaround`. Names under our suffix are looked up with the same
+// multicast query as `client`, and everything else is forwarded upstream
+// or refused with NXDOMAIN.
+
+use std::sync::Mutex;
+
+use crate::{
+	client::discover,
+	dns::{
+		self,
+		Answer,
+		CLASS_IN,
+		Query,
+	},
+	prelude::*,
+};
+
+// Nobody answered for this nickname. Short, so a peer that just
+// started shows up soon.
+
+const NEGATIVE_TTL: Duration = Duration::from_secs (5);
+
+// Anybody who can send us queries can make up names, and each miss is
+// cached, so past this many only the peers that answered get cached
+
+const MAX_ENTRIES: usize = 4096;
+
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs (2);
+
+#[derive (Clone)]
+pub struct ResolverOptions {
+	pub discover: DiscoverOptions,
+	pub listen: SocketAddr,
+	
+	/// Names end in `.<suffix>`, e.g. `laptop.lookaround`
+	pub suffix: String,
+	
+	/// How long to trust a lookup before asking again
+	pub ttl: Duration,
+	
+	/// Where to send names that aren't ours. If `None`, they get NXDOMAIN.
+	pub upstream: Option <SocketAddr>,
+}
+
+impl Default for ResolverOptions {
+	fn default () -> Self {
+		Self {
+			discover: Default::default (),
+			listen: (Ipv4Addr::LOCALHOST, 5353).into (),
+			suffix: "lookaround".to_string (),
+			ttl: Duration::from_secs (60),
+			upstream: None,
+		}
+	}
+}
+
+/// Serves DNS over UDP on `options.listen` forever
+pub async fn serve_dns (options: ResolverOptions) -> Result <(), AppError> {
+	let socket = Arc::new (UdpSocket::bind (options.listen).await?);
+	let cache = Arc::new (Mutex::new (Cache::new (options.ttl)));
+	let discovering = Arc::new (tokio::sync::Mutex::new (()));
+	let options = Arc::new (options);
+	
+	loop {
+		let mut buf = vec! [0u8; PACKET_SIZE];
+		// Like an ICMP error from an earlier answer, which shouldn't stop
+		// us answering everyone else
+		let (len, remote_addr) = match socket.recv_from (&mut buf).await {
+			Ok (x) => x,
+			Err (e) => {
+				println! ("Error while receiving query: {:?}", e);
+				continue;
+			},
+		};
+		buf.truncate (len);
+		
+		// Lookups take a while, so don't make everyone else wait
+		let socket = Arc::clone (&socket);
+		let cache = Arc::clone (&cache);
+		let discovering = Arc::clone (&discovering);
+		let options = Arc::clone (&options);
+		tokio::spawn (async move {
+			let resp = match dns::parse_query (&buf) {
+				Ok (Some (query)) => answer (&options, &cache, &discovering, &query, &buf).await,
+				_ => return,
+			};
+			
+			if let Err (e) = socket.send_to (&resp, remote_addr).await {
+				println! ("Error sending DNS response to {}: {:?}", remote_addr, e);
+			}
+		});
+	}
+}
+
+async fn answer (
+	options: &ResolverOptions,
+	cache: &Mutex <Cache>,
+	discovering: &tokio::sync::Mutex <()>,
+	query: &Query,
+	raw: &[u8],
+) -> Vec <u8>
+{
+	let question = query.questions.first ();
+	let nick = question.and_then (|q| nick_for_name (&q.name, &options.suffix));
+	
+	let (question, nick) = match (question, nick) {
+		(Some (q), Some (nick)) => (q, nick.to_ascii_lowercase ()),
+		_ => return match options.upstream {
+			Some (upstream) => forward (upstream, query, raw).await,
+			None => refuse (query, dns::RCODE_NXDOMAIN),
+		},
+	};
+	
+	let (addrs, ttl) = match lookup (options, cache, discovering, &nick).await {
+		Ok (x) => x,
+		Err (e) => {
+			println! ("Error looking up `{}`: {:?}", nick, e);
+			return refuse (query, dns::RCODE_SERVFAIL);
+		},
+	};
+	
+	if addrs.is_empty () {
+		return refuse (query, dns::RCODE_NXDOMAIN);
+	}
+	
+	let answers: Vec <_> = addrs.into_iter ()
+	.map (|x| Answer::addr (&question.name, x, CLASS_IN, ttl.as_secs () as u32))
+	.filter (|x| question.wants (x.rtype))
+	.collect ();
+	
+	let flags = dns::FLAG_RESPONSE | dns::FLAG_AUTHORITATIVE | recursion_flags (query, options.upstream.is_some ());
+	dns::encode_response (query.id, flags, &[question], &answers)
+}
+
+// Only one discovery runs at a time, since each one finds every peer.
+// Queries that miss the cache wait for it, instead of each multicasting.
+
+async fn lookup (
+	options: &ResolverOptions,
+	cache: &Mutex <Cache>,
+	discovering: &tokio::sync::Mutex <()>,
+	nick: &str,
+) -> Result <(Vec <IpAddr>, Duration), AppError>
+{
+	let asked = Instant::now ();
+	if let Some (x) = cache.lock ().unwrap ().get (nick, asked) {
+		return Ok (x);
+	}
+	
+	let _discovering = discovering.lock ().await;
+	if let Some (x) = cache.lock ().unwrap ().get_since (nick, asked, Instant::now ()) {
+		return Ok (x);
+	}
+	
+	let peers: Vec <Peer> = discover (options.discover.clone ()).await?.collect ().await;
+	let mut cache = cache.lock ().unwrap ();
+	let now = Instant::now ();
+	cache.insert_peers (&peers, now);
+	Ok (cache.get_since (nick, asked, now).unwrap_or_default ())
+}
+
+fn recursion_flags (query: &Query, upstream: bool) -> u16 {
+	let mut flags = query.flags & dns::FLAG_RECURSION_DESIRED;
+	if upstream {
+		flags |= dns::FLAG_RECURSION_AVAILABLE;
+	}
+	flags
+}
+
+fn refuse (query: &Query, rcode: u16) -> Vec <u8> {
+	let questions: Vec <_> = query.questions.iter ().collect ();
+	let flags = dns::FLAG_RESPONSE | (query.flags & dns::FLAG_RECURSION_DESIRED) | rcode;
+	dns::encode_response (query.id, flags, &questions, &[])
+}
+
+async fn forward (upstream: SocketAddr, query: &Query, raw: &[u8]) -> Vec <u8> {
+	let forwarded = async {
+		let bind_addr: SocketAddr = match upstream {
+			SocketAddr::V4 (_) => (Ipv4Addr::UNSPECIFIED, 0).into (),
+			SocketAddr::V6 (_) => (Ipv6Addr::UNSPECIFIED, 0).into (),
+		};
+		let socket = UdpSocket::bind (bind_addr).await?;
+		socket.send_to (raw, upstream).await?;
+		
+		let mut buf = vec! [0u8; PACKET_SIZE];
+		loop {
+			let (len, remote_addr) = socket.recv_from (&mut buf).await?;
+			if remote_addr == upstream {
+				buf.truncate (len);
+				return Ok::<_, std::io::Error> (buf);
+			}
+		}
+	};
+	
+	match timeout (UPSTREAM_TIMEOUT, forwarded).await {
+		Ok (Ok (x)) => x,
+		Ok (Err (e)) => {
+			println! ("Error forwarding to {}: {:?}", upstream, e);
+			refuse (query, dns::RCODE_SERVFAIL)
+		},
+		Err (_) => refuse (query, dns::RCODE_SERVFAIL),
+	}
+}
+
+// `laptop.lookaround` -> `laptop`
+
+pub(crate) fn nick_for_name <'a> (name: &'a str, suffix: &str) -> Option <&'a str> {
+	let name = name.strip_suffix ('.').unwrap_or (name);
+	let split = name.len ().checked_sub (suffix.len () + 1)?;
+	
+	if ! name.is_char_boundary (split) || name.as_bytes () [split] != b'.' {
+		return None;
+	}
+	if ! name [split + 1..].eq_ignore_ascii_case (suffix) {
+		return None;
+	}
+	
+	Some (&name [..split]).filter (|x| ! x.is_empty ())
+}
+
+// Lookup results by lowercase nickname. An empty list means we asked
+// and nobody answered. Expired entries are dropped whenever something
+// new goes in.
+
+pub(crate) struct Cache {
+	ttl: Duration,
+	entries: HashMap <String, (Vec <IpAddr>, Instant)>,
+	
+	// When the last discovery finished
+	discovered: Option <Instant>,
+}
+
+impl Cache {
+	pub(crate) fn new (ttl: Duration) -> Self {
+		Self {
+			ttl,
+			entries: Default::default (),
+			discovered: None,
+		}
+	}
+	
+	/// The addresses, and how much longer they're good for
+	pub(crate) fn get (&self, nick: &str, now: Instant) -> Option <(Vec <IpAddr>, Duration)> {
+		let (addrs, expires) = self.entries.get (nick)?;
+		let left = expires.checked_duration_since (now).filter (|x| ! x.is_zero ())?;
+		Some ((addrs.clone (), left))
+	}
+	
+	/// Like `get`, but if a discovery finished since we were `asked`
+	/// about `nick`, and it isn't here, nobody has it, so that's cached
+	/// as a miss
+	pub(crate) fn get_since (&mut self, nick: &str, asked: Instant, now: Instant) -> Option <(Vec <IpAddr>, Duration)> {
+		if let Some (x) = self.get (nick, now) {
+			return Some (x);
+		}
+		if self.discovered.is_some_and (|x| x >= asked) {
+			self.insert_missing (nick, now);
+			return Some ((vec! [], self.negative_ttl ()));
+		}
+		None
+	}
+	
+	/// Every peer that answered, not just the one we asked about, since
+	/// the lookup was a multicast anyway
+	pub(crate) fn insert_peers (&mut self, peers: &[Peer], now: Instant) {
+		let mut found: HashMap <String, Vec <IpAddr>> = Default::default ();
+		
+		for peer in peers {
+			let nick = match &peer.nickname {
+				None => continue,
+				Some (x) => x.to_ascii_lowercase (),
+			};
+			
+			// DNS has nowhere to put a scope ID, so link-local IPv6 is useless
+			if let SocketAddr::V6 (x) = peer.addr {
+				if x.ip ().segments () [0] & 0xffc0 == 0xfe80 {
+					continue;
+				}
+			}
+			
+			let addrs = found.entry (nick).or_default ();
+			if ! addrs.contains (&peer.addr.ip ()) {
+				addrs.push (peer.addr.ip ());
+			}
+		}
+		
+		self.prune (now);
+		self.discovered = Some (now);
+		for (nick, addrs) in found {
+			if self.entries.len () < MAX_ENTRIES || self.entries.contains_key (&nick) {
+				self.entries.insert (nick, (addrs, now + self.ttl));
+			}
+		}
+	}
+	
+	pub(crate) fn insert_missing (&mut self, nick: &str, now: Instant) {
+		self.prune (now);
+		if self.entries.len () < MAX_ENTRIES || self.entries.contains_key (nick) {
+			self.entries.insert (nick.to_string (), (vec! [], now + self.negative_ttl ()));
+		}
+	}
+	
+	fn negative_ttl (&self) -> Duration {
+		NEGATIVE_TTL.min (self.ttl)
+	}
+	
+	fn prune (&mut self, now: Instant) {
+		self.entries.retain (|_, (_, expires)| *expires > now);
+	}
+}
+
+#[cfg (test)]
+mod test {
+	use super::*;
+	
+	#[test]
+	fn test_nick_for_name () {
+		for (input, expected) in [
+			("laptop.lookaround", Some ("laptop")),
+			("laptop.lookaround.", Some ("laptop")),
+			("Laptop.LookAround", Some ("Laptop")),
+			("my.laptop.lookaround", Some ("my.laptop")),
+			("lookaround", None),
+			(".lookaround", None),
+			("laptoplookaround", None),
+			("laptop.local", None),
+			("example.com", None),
+		] {
+			assert_eq! (nick_for_name (input, "lookaround"), expected, "{}", input);
+		}
+	}
+	
+	#[test]
+	fn test_cache () {
+		let start = Instant::now ();
+		let sec = |x| start + Duration::from_secs (x);
+		let peer = |nick: &str, addr: &str| Peer {
+			addr: addr.parse ().unwrap (),
+			mac: None,
+			nickname: Some (nick.to_string ()),
+			nickname_source: None,
+			services: vec! [],
+			public_key: None,
+			rtt: None,
+		};
+		let ip = |x: &str| x.parse::<IpAddr> ().unwrap ();
+		
+		let mut cache = Cache::new (Duration::from_secs (60));
+		assert_eq! (cache.get ("laptop", sec (0)), None);
+		
+		cache.insert_peers (&[
+			peer ("Laptop", "192.168.1.101:9040"),
+			peer ("Laptop", "[fe80::1%2]:9040"),
+			peer ("Laptop", "[fd00::1]:9040"),
+			peer ("desktop", "192.168.1.102:9040"),
+		], sec (0));
+		
+		assert_eq! (cache.get ("laptop", sec (10)), Some ((
+			vec! [ip ("192.168.1.101"), ip ("fd00::1")],
+			Duration::from_secs (50),
+		)));
+		assert_eq! (cache.get ("desktop", sec (10)).unwrap ().0, vec! [ip ("192.168.1.102")]);
+		assert_eq! (cache.get ("laptop", sec (60)), None);
+		
+		cache.insert_missing ("phone", sec (0));
+		assert_eq! (cache.get ("phone", sec (1)), Some ((vec! [], Duration::from_secs (4))));
+		assert_eq! (cache.get ("phone", sec (5)), None);
+		
+		// Expired entries are dropped once something new goes in
+		assert_eq! (cache.entries.len (), 3);
+		cache.insert_missing ("tablet", sec (60));
+		assert_eq! (cache.entries.keys ().collect::<Vec <_>> (), vec! ["tablet"]);
+		
+		// Made-up names can't grow it forever
+		for i in 0..MAX_ENTRIES * 2 {
+			cache.insert_missing (&format! ("nobody-{}", i), sec (61));
+		}
+		assert_eq! (cache.entries.len (), MAX_ENTRIES);
+		cache.insert_peers (&[peer ("laptop", "192.168.1.101:9040")], sec (61));
+		assert_eq! (cache.get ("laptop", sec (61)), None);
+		cache.insert_missing ("nobody-0", sec (70));
+		assert_eq! (cache.entries.len (), 1);
+	}
+	
+	#[test]
+	fn test_get_since () {
+		let start = Instant::now ();
+		let sec = |x| start + Duration::from_secs (x);
+		let mut cache = Cache::new (Duration::from_secs (60));
+		
+		// Nobody's looked yet
+		assert_eq! (cache.get_since ("laptop", sec (0), sec (0)), None);
+		
+		// A discovery that finished before we asked doesn't count...
+		cache.insert_peers (&[], sec (1));
+		assert_eq! (cache.get_since ("laptop", sec (2), sec (2)), None);
+		
+		// ...but one that finished while we waited does
+		assert_eq! (cache.get_since ("laptop", sec (1), sec (2)), Some ((vec! [], Duration::from_secs (5))));
+		assert! (cache.get ("laptop", sec (2)).is_some ());
+	}
+}
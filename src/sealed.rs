@@ -0,0 +1,190 @@
+// Encrypted responses. A client that doesn't want its peers' MACs and
+// nicknames going over the air in the clear makes a new X25519 key for
+// each request, and sends the public half along. Servers make a new key
+// of their own for each answer, and encrypt the answer with
+// ChaCha20-Poly1305 under a key derived from both halves. Sniffers only
+// learn that somebody answered.
+//
+// Anybody can still ask, so this doesn't keep outsiders out. That's what
+// `psk` is for.
+
+use chacha20poly1305::{
+	ChaCha20Poly1305,
+	KeyInit,
+	Nonce,
+	aead::{
+		Aead,
+		Payload,
+	},
+};
+use sha2::{
+	Digest,
+	Sha256,
+};
+use x25519_dalek::{
+	EphemeralSecret,
+	ReusableSecret,
+};
+
+use crate::prelude::*;
+
+// Keeps a key from being valid in any other context
+
+const KEY_CONTEXT: &[u8] = b"lookaround sealed v1";
+
+#[derive (Debug, thiserror::Error)]
+pub enum SealError {
+	#[error ("Encryption failed")]
+	Encrypt,
+	#[error (transparent)]
+	Message (#[from] MessageError),
+	#[error ("Can't encrypt to a weak key")]
+	WeakKey,
+}
+
+/// A client's key for one request. Only its holder can read the answers
+/// sealed to it.
+pub struct ResponseKey {
+	secret: ReusableSecret,
+	public_key: [u8; 32],
+}
+
+impl Default for ResponseKey {
+	fn default () -> Self {
+		let secret = ReusableSecret::random_from_rng (rand::thread_rng ());
+		let public_key = x25519_dalek::PublicKey::from (&secret).to_bytes ();
+		Self {
+			secret,
+			public_key,
+		}
+	}
+}
+
+impl ResponseKey {
+	/// The message asking servers to seal their answers to us
+	pub fn message (&self) -> Message {
+		Message::ResponseKey (self.public_key)
+	}
+	
+	pub fn public_key (&self) -> [u8; 32] {
+		self.public_key
+	}
+	
+	/// Decrypts the `Sealed` message in `packet`. `None` if there isn't
+	/// one, or it wasn't sealed to us as an answer to `idem_id`.
+	pub fn open (&self, idem_id: [u8; 8], packet: &Packet) -> Option <Packet> {
+		let (ephemeral_key, ciphertext) = packet.msgs.iter ().find_map (|x| match x {
+			Message::Sealed { ephemeral_key, ciphertext } => Some ((ephemeral_key, ciphertext)),
+			_ => None,
+		})?;
+		
+		let shared = self.secret.diffie_hellman (&x25519_dalek::PublicKey::from (*ephemeral_key));
+		let plaintext = cipher (shared.as_bytes (), &self.public_key, ephemeral_key)
+		.decrypt (&Nonce::default (), Payload {
+			msg: ciphertext,
+			aad: &idem_id,
+		})
+		.ok ()?;
+		
+		Message::decode (&plaintext).ok ()
+	}
+}
+
+/// Encrypts `msgs`, as they'll be encoded in `version`, so only the holder
+/// of `recipient` can read them, and only as an answer to `idem_id`
+pub fn seal (recipient: &[u8; 32], idem_id: [u8; 8], msgs: &[Message], version: u8)
+-> Result <Message, SealError>
+{
+	let secret = EphemeralSecret::random_from_rng (rand::thread_rng ());
+	let ephemeral_key = x25519_dalek::PublicKey::from (&secret).to_bytes ();
+	let shared = secret.diffie_hellman (&x25519_dalek::PublicKey::from (*recipient));
+	
+	// A low-order key would make the shared secret something anybody can
+	// work out
+	if ! shared.was_contributory () {
+		return Err (SealError::WeakKey);
+	}
+	
+	// Our half of the key is new every time, so each key only ever
+	// seals one message, and the nonce can stay fixed
+	let ciphertext = cipher (shared.as_bytes (), recipient, &ephemeral_key)
+	.encrypt (&Nonce::default (), Payload {
+		msg: &Message::encode (msgs, version)?,
+		aad: &idem_id,
+	})
+	.map_err (|_| SealError::Encrypt)?;
+	
+	Ok (Message::Sealed {
+		ephemeral_key,
+		ciphertext,
+	})
+}
+
+fn cipher (shared: &[u8; 32], recipient: &[u8; 32], ephemeral_key: &[u8; 32]) -> ChaCha20Poly1305 {
+	let key = Sha256::new ()
+	.chain_update (KEY_CONTEXT)
+	.chain_update (shared)
+	.chain_update (recipient)
+	.chain_update (ephemeral_key)
+	.finalize ();
+	ChaCha20Poly1305::new (&key)
+}
+
+#[cfg (test)]
+mod test {
+	use super::*;
+	
+	#[test]
+	fn test_seal () -> Result <(), SealError> {
+		let key = ResponseKey::default ();
+		let recipient = match key.message () {
+			Message::ResponseKey (x) => x,
+			_ => unreachable! (),
+		};
+		let idem_id = [1; 8];
+		let msgs = vec! [
+			Message::Response1 (Some ([1, 2, 3, 4, 5, 6])),
+			Message::Response2 (message::Response2 {
+				idem_id,
+				nickname: "laptop".to_string (),
+			}),
+		];
+		
+		let sealed = Packet {
+			version: message::VERSION_2,
+			msgs: vec! [
+				Message::AnswerId ([2; 8]),
+				seal (&recipient, idem_id, &msgs, message::VERSION_2)?,
+			],
+		};
+		
+		// Nothing readable goes over the air
+		let encoded = Message::encode (&sealed.msgs, message::VERSION_2)?;
+		assert! (! encoded.windows (6).any (|x| x == b"laptop"));
+		assert! (! encoded.windows (6).any (|x| x == [1, 2, 3, 4, 5, 6]));
+		
+		let opened = key.open (idem_id, &sealed).unwrap ();
+		assert_eq! (opened.msgs, msgs);
+		
+		// Only for the request it answers, and only for us
+		assert_eq! (key.open ([2; 8], &sealed), None);
+		assert_eq! (ResponseKey::default ().open (idem_id, &sealed), None);
+		
+		// Tampered with
+		let mut tampered = sealed.clone ();
+		if let Message::Sealed { ciphertext, .. } = &mut tampered.msgs [1] {
+			ciphertext [0] ^= 1;
+		}
+		assert_eq! (key.open (idem_id, &tampered), None);
+		
+		// Not sealed at all
+		assert_eq! (key.open (idem_id, &Packet {
+			version: message::VERSION_2,
+			msgs: msgs.clone (),
+		}), None);
+		
+		assert! (matches! (seal (&[0; 32], idem_id, &msgs, message::VERSION_2), Err (SealError::WeakKey)));
+		
+		Ok (())
+	}
+}
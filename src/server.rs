@@ -1,108 +1,908 @@
-use crate::prelude::*;
+use std::sync::Mutex;
 
+use socket2::{
+	Domain,
+	Protocol,
+	Socket,
+	Type,
+};
+
+use crate::{
+	dedup::{
+		FirstSources,
+		IdemCache,
+	},
+	ip::{
+		self,
+		Interface,
+	},
+	mdns::{
+		self,
+		MDNS_ADDR,
+		MDNS_ADDR_V6,
+		MDNS_HOP_LIMIT,
+		MDNS_PORT,
+	},
+	prelude::*,
+	ratelimit::{
+		Limits,
+		RateLimiter,
+		Verdict,
+	},
+	sealed,
+};
+
+/// Answers discovery requests on every interface. Make one with
+/// `Responder::builder`.
 #[derive (Clone)]
-struct Params {
+pub struct Responder {
 	common: app_common::Params,
 	bind_addrs: Vec <Ipv4Addr>,
+	auto_bind_addrs: bool,
+	v6_ifaces: Vec <u32>,
+	auto_v6_ifaces: bool,
 	nickname: String,
 	our_mac: Option <[u8; 6]>,
+	
+	// Every MAC we answer MAC-specific requests for, one per interface
+	macs: Vec <[u8; 6]>,
+	services: Vec <Service>,
+	identity: Option <Arc <Identity>>,
+	mdns: bool,
+	
+	// Shared by every interface, so the global budget really is global
+	limiter: Arc <Mutex <RateLimiter>>,
 }
 
-pub async fn server <I: Iterator <Item=String>> (args: I) -> Result <(), AppError> 
-{
-	match get_mac_address() {
-		Ok(Some(ma)) => {
-			println!("Our MAC addr = {}", ma);
+#[derive (Clone, Default)]
+pub struct ResponderBuilder {
+	common: app_common::Params,
+	bind_addrs: Vec <Ipv4Addr>,
+	v6_ifaces: Vec <u32>,
+	nickname: String,
+	mac: Option <[u8; 6]>,
+	services: Vec <Service>,
+	identity: Option <Arc <Identity>>,
+	mdns: bool,
+	limits: Limits,
+}
+
+impl Responder {
+	pub fn builder () -> ResponderBuilder {
+		Default::default ()
+	}
+	
+	/// Serves forever
+	pub async fn run (self) -> Result <(), AppError> {
+		self.run_until (std::future::pending ()).await
+	}
+	
+	/// Serves until `shutdown` completes, then says goodbye. If the local
+	/// addresses were auto-detected, they're re-checked periodically, and
+	/// when they change we rebind and announce ourselves again.
+	pub async fn run_until <F: Future <Output = ()>> (mut self, shutdown: F) 
+	-> Result <(), AppError>
+	{
+		tokio::pin! (shutdown);
+		
+		loop {
+			let ifaces = self.bind_all ();
+			if ifaces.is_empty () {
+				return Err (AppError::NoInterfaces);
+			}
+			
+			self.announce (&ifaces, |idem_id| Message::Announce { idem_id }).await?;
+			
+			let shutting_down = loop {
+				tokio::select! {
+					_ = &mut shutdown => break true,
+					_ = sleep (ADDR_CHECK_INTERVAL) => if self.redetect_addrs () {
+						break false;
+					},
+				}
+			};
+			
+			for iface in &ifaces {
+				iface.task.abort ();
+			}
+			
+			if shutting_down {
+				self.announce (&ifaces, |idem_id| Message::Goodbye { idem_id }).await?;
+				return Ok (());
+			}
+			
+			println! ("Local addresses changed, rebinding");
+		}
+	}
+	
+	fn bind_all (&self) -> Vec <ServedInterface> {
+		let mut ifaces = vec! [];
+		
+		// For telling local requests from spoofed ones. Addresses only
+		// change along with a rebind, so this stays current.
+		let interfaces = Arc::new (ip::list_interfaces ());
+		if interfaces.is_empty () {
+			println! ("Can't list interfaces, so requests from outside our subnets won't be refused");
+		}
+		
+		for bind_addr in &one_per_interface (&self.bind_addrs, &interfaces) {
+			let group = SocketAddrV4::new (self.common.multicast_addr, self.common.server_port);
+			match bind_interface_v4 (group, *bind_addr, None) {
+				Ok (socket) => {
+					println! ("Serving IPv4 on iface {}", bind_addr);
+					ifaces.push (self.serve (socket, group.into (), &interfaces));
+				},
+				Err (e) => println! ("Error binding socket for iface {}: {:?}", bind_addr, e),
+			}
+		}
+		
+		for iface in &self.v6_ifaces {
+			let group = SocketAddrV6::new (self.common.multicast_addr_v6, self.common.server_port, 0, *iface);
+			match bind_interface_v6 (group, None) {
+				Ok (socket) => {
+					println! ("Serving IPv6 on iface {}", iface);
+					ifaces.push (self.serve (socket, group.into (), &interfaces));
+				},
+				Err (e) => println! ("Error binding IPv6 socket for iface {}: {:?}", iface, e),
+			}
+		}
+		
+		if self.mdns {
+			self.bind_mdns (&mut ifaces);
+		}
+		
+		ifaces
+	}
+	
+	fn serve (&self, socket: UdpSocket, group: SocketAddr, interfaces: &Arc <Vec <Interface>>) -> ServedInterface {
+		let socket = Arc::new (socket);
+		let params = self.clone ();
+		let task_socket = Arc::clone (&socket);
+		let interfaces = Arc::clone (interfaces);
+		
+		let task = tokio::spawn (async move {
+			if let Err (e) = serve_interface (params, task_socket, interfaces).await {
+				println! ("Stopped serving iface: {:?}", e);
+			}
+		});
+		
+		ServedInterface {
+			socket,
+			group: Some (group),
+			task,
+		}
+	}
+	
+	// mDNS gets its own sockets on the same interfaces, answering for
+	// `<nickname>.local` with each interface's own addresses
+	
+	fn bind_mdns (&self, ifaces: &mut Vec <ServedInterface>) {
+		if self.nickname.is_empty () {
+			println! ("Can't answer mDNS queries without a nickname");
+			return;
+		}
+		
+		let hostname = format! ("{}.local", self.nickname);
+		let interfaces = ip::list_interfaces ();
+		
+		for bind_addr in &one_per_interface (&self.bind_addrs, &interfaces) {
+			let group = SocketAddrV4::new (MDNS_ADDR, MDNS_PORT);
+			let (mut v4, v6) = mdns_addrs (&interfaces, |x| x.addrs.iter ().any (|a| a.addr == *bind_addr));
+			if v4.is_empty () {
+				v4.push (*bind_addr);
+			}
+			
+			match bind_interface_v4 (group, *bind_addr, Some (MDNS_HOP_LIMIT)) {
+				Ok (socket) => {
+					println! ("Answering mDNS for {} on iface {}", hostname, bind_addr);
+					ifaces.push (serve_mdns (hostname.clone (), socket, group.into (), v4, v6));
+				},
+				Err (e) => println! ("Error binding mDNS socket for iface {}: {:?}", bind_addr, e),
+			}
+		}
+		
+		for iface in &self.v6_ifaces {
+			let group = SocketAddrV6::new (MDNS_ADDR_V6, MDNS_PORT, 0, *iface);
+			let (v4, v6) = mdns_addrs (&interfaces, |x| x.index == *iface);
+			
+			match bind_interface_v6 (group, Some (MDNS_HOP_LIMIT)) {
+				Ok (socket) => {
+					println! ("Answering mDNS for {} on IPv6 iface {}", hostname, iface);
+					ifaces.push (serve_mdns (hostname.clone (), socket, group.into (), v4, v6));
+				},
+				Err (e) => println! ("Error binding IPv6 mDNS socket for iface {}: {:?}", iface, e),
+			}
 		}
-		Ok(None) => println!("No MAC address found."),
-		Err(e) => println!("{:?}", e),
 	}
 	
-	let params = configure (args)?;
+	// Multicasts an unsolicited response on every interface, prefixed
+	// with `marker`. A few times, since nobody will ask us to retransmit,
+	// and in every version, since we don't know who's listening. Except
+	// in a private fleet, where everybody can read v2.
+	
+	async fn announce <M: Fn ([u8; 8]) -> Message> (&self, ifaces: &[ServedInterface], marker: M) 
+	-> Result <(), AppError>
+	{
+		// Nobody gave us a key to seal an announcement to
+		if self.common.encrypt {
+			return Ok (());
+		}
+		
+		let mut idem_id = [0u8; 8];
+		rand::thread_rng ().fill_bytes (&mut idem_id);
+		
+		let packets = message::VERSIONS.iter ()
+		.filter (|v| self.common.psk.is_none () || **v >= message::VERSION_2)
+		.map (|v| Ok (Message::encode (&self.response (idem_id, self.our_mac, Some (marker (idem_id)), None, *v)?, *v)?))
+		.collect::<Result <Vec <_>, AppError>> ()?;
+		
+		for _ in 0..3 {
+			for iface in ifaces {
+				let group = match iface.group {
+					None => continue,
+					Some (x) => x,
+				};
+				for packet in &packets {
+					if let Err (e) = iface.socket.send_to (packet, group).await {
+						println! ("Error announcing to {}: {:?}", group, e);
+					}
+				}
+			}
+			sleep (Duration::from_millis (100)).await;
+		}
+		
+		Ok (())
+	}
 	
-	let socket = UdpSocket::bind (SocketAddrV4::new (Ipv4Addr::UNSPECIFIED, params.common.server_port)).await?;
+	// Starts with `marker`, if there is one. The signature and the PSK tag
+	// cover the encoding, so they depend on `version`. If the client sent
+	// `response_key`, everything but the marker and the PSK tag is sealed
+	// to it, including the signature, since our public key would give us
+	// away as surely as our MAC.
 	
-	for bind_addr in &params.bind_addrs {
-		if let Err (e) = socket.join_multicast_v4 (params.common.multicast_addr, *bind_addr) {
-			println! ("Error joining multicast group with iface {}: {:?}", bind_addr, e);
+	fn response (
+		&self,
+		idem_id: [u8; 8],
+		mac: Option <[u8; 6]>,
+		marker: Option <Message>,
+		response_key: Option <&[u8; 32]>,
+		version: u8,
+	) 
+	-> Result <Vec <Message>, AppError>
+	{
+		let mut resp: Vec <_> = marker.into_iter ().collect ();
+		match response_key {
+			None => self.push_payload (&mut resp, idem_id, mac, version)?,
+			Some (key) => {
+				let mut payload = vec! [];
+				self.push_payload (&mut payload, idem_id, mac, version)?;
+				resp.push (sealed::seal (key, idem_id, &payload, version)?);
+			},
+		}
+		if let Some (psk) = &self.common.psk {
+			resp.push (psk.tag (idem_id, &resp, version)?);
 		}
+		
+		Ok (resp)
 	}
 	
-	serve_interface (params, socket).await?;
+	fn push_payload (&self, resp: &mut Vec <Message>, idem_id: [u8; 8], mac: Option <[u8; 6]>, version: u8) 
+	-> Result <(), MessageError>
+	{
+		resp.push (Message::Response1 (mac));
+		resp.push (Message::Response2 (message::Response2 {
+			idem_id,
+			nickname: self.nickname.clone (),
+		}));
+		
+		// v1 clients drop the whole packet at any type they don't know,
+		// so they only get what they've always gotten
+		if version < message::VERSION_2 {
+			return Ok (());
+		}
+		
+		if ! self.services.is_empty () {
+			resp.push (Message::Services (self.services.clone ()));
+		}
+		if let Some (identity) = &self.identity {
+			resp.push (identity.sign (idem_id, resp, version)?);
+		}
+		
+		Ok (())
+	}
 	
-	Ok (())
+	// Charges a response to `remote_addr`, and logs the first of each run
+	// of drops
+	
+	fn allow_response (&self, remote_addr: SocketAddr) -> bool {
+		let verdict = self.limiter.lock ().unwrap ().check (remote_addr.ip (), Instant::now ());
+		match verdict {
+			Verdict::Allow => return true,
+			Verdict::SourceLimited { first: true } => println! ("Too many requests from {}, dropping some", remote_addr.ip ()),
+			Verdict::GlobalLimited { first: true } => println! ("Too many requests overall, dropping some"),
+			_ => (),
+		}
+		false
+	}
+	
+	// Returns true if the auto-detected addresses changed
+	
+	fn redetect_addrs (&mut self) -> bool {
+		let mut changed = false;
+		
+		if self.auto_bind_addrs {
+			if let Ok (mut x) = get_ips () {
+				x.sort ();
+				changed |= x != self.bind_addrs;
+				self.bind_addrs = x;
+			}
+		}
+		
+		if self.auto_v6_ifaces {
+			if let Ok (mut x) = get_ipv6_ifaces () {
+				x.sort ();
+				changed |= x != self.v6_ifaces;
+				self.v6_ifaces = x;
+			}
+		}
+		
+		changed
+	}
 }
 
-fn configure <I: Iterator <Item=String>> (mut args: I) -> Result <Params, AppError>
-{
-	let common = app_common::Params::default ();
-	let mut bind_addrs = vec![];
-	let mut nickname = String::new ();
+struct ServedInterface {
+	socket: Arc <UdpSocket>,
+	
+	// Where to announce ourselves. `None` for mDNS sockets.
+	group: Option <SocketAddr>,
+	task: tokio::task::JoinHandle <()>,
+}
+
+const ADDR_CHECK_INTERVAL: Duration = Duration::from_secs (5);
+
+// Clients retransmit every 100 ms, so this re-answers about every third
+// copy until they tell us they heard us
+
+const REANSWER_HOLDOFF: Duration = Duration::from_millis (250);
+
+// Which copies of each request one socket answers. Clients send each
+// request several times, in every version. We answer the first copy that
+// arrives, normally the newest version we can read, and then v2 copies
+// that come after the holdoff, in case our answer was lost, until the
+// client says it heard us.
+
+struct Replies {
+	// Random, so clients can tell this socket's answers apart from every
+	// other server's, and from our own on other interfaces
+	answer_id: [u8; 8],
+	
+	// When we last answered each request
+	answered: IdemCache,
+	
+	// Requests whose client listed `answer_id` as known. Only v2 copies
+	// can say so, and this keeps the v1 copies quiet too.
+	acknowledged: IdemCache,
+	
+	// In a private fleet, where each request first came from
+	claimed: FirstSources,
+}
+
+impl Default for Replies {
+	fn default () -> Self {
+		let mut answer_id = [0u8; 8];
+		rand::thread_rng ().fill_bytes (&mut answer_id);
+		
+		Self {
+			answer_id,
+			answered: Default::default (),
+			acknowledged: Default::default (),
+			claimed: Default::default (),
+		}
+	}
+}
+
+impl Replies {
+	// Doesn't count as answering, since the request might still be
+	// refused. Call `answer` for that.
 	
-	if let Some (proj_dirs) = find_project_dirs () {
-		let mut ini = Ini::new_cs ();
-		let path = proj_dirs.config_local_dir ().join ("server.ini");
-		if ini.load (&path).is_ok () {
-			if let Some (x) = ini.get ("server", "nickname") {
-				nickname = x;
-				eprintln! ("Loaded nickname {:?}", nickname);
+	fn should_answer (
+		&mut self,
+		idem_id: [u8; 8],
+		source: SocketAddr,
+		version: u8,
+		known_answers: &[[u8; 8]],
+		now: Instant,
+	) -> bool
+	{
+		if known_answers.contains (&self.answer_id) {
+			self.acknowledged.insert (idem_id, source, now);
+			return false;
+		}
+		if self.acknowledged.since (idem_id, source, now).is_some () {
+			return false;
+		}
+		
+		match self.answered.since (idem_id, source, now) {
+			None => true,
+			// v1 clients never expected a second answer, and one that
+			// sends both versions would rather have it signed
+			Some (x) => version >= message::VERSION_2 && x >= REANSWER_HOLDOFF,
+		}
+	}
+	
+	fn answer (&mut self, idem_id: [u8; 8], source: SocketAddr, now: Instant) {
+		self.answered.insert (idem_id, source, now);
+	}
+	
+	// A tag only proves that somebody in the fleet sent the request once.
+	// Copies that are stale, or that come from anywhere but the first
+	// sender, were overheard and replayed.
+	
+	fn is_replay (&mut self, idem_id: [u8; 8], req: &Packet, source: SocketAddr, now: Instant, clock: SystemTime) -> bool {
+		if ! psk::is_fresh (req, clock) {
+			println! ("Ignoring stale request from {}, or our clocks are more than {:?} apart", source, psk::MAX_SKEW);
+			return true;
+		}
+		if ! self.claimed.claim (idem_id, source, now) {
+			println! ("Ignoring replayed request from {}", source);
+			return true;
+		}
+		false
+	}
+}
+
+impl ResponderBuilder {
+	/// Applies settings from server.ini, if there is one
+	pub fn load_config (mut self) -> Self {
+		if let Some (proj_dirs) = find_project_dirs () {
+			match load_server_ini () {
+				Some (ini) => self.load_ini (&ini, "network", "server", "services"),
+				None => eprintln! ("Can't load server.ini, didn't load default configs"),
+			}
+			
+			let key_path = proj_dirs.config_local_dir ().join ("server.key");
+			match Identity::load_or_create (&key_path) {
+				Ok (x) => {
+					eprintln! ("Signing responses with key {} from {:?}", identity::to_hex (&x.public_key ()), key_path);
+					self.identity = Some (Arc::new (x));
+				},
+				Err (e) => eprintln! ("Can't load key, responses won't be signed: {}", e),
 			}
 		}
 		else {
-			eprintln! ("Can't load ini from {:?}, didn't load default configs", path);
+			eprintln! ("Can't find config dir, didn't load default configs");
 		}
+		
+		self
 	}
-	else {
-		eprintln! ("Can't find config dir, didn't load default configs");
+	
+	/// One builder per `[fleet.<name>]` section in server.ini, sorted by
+	/// name. Each starts as a copy of this one, so anything a fleet doesn't
+	/// set, like its nickname or its services, is the same as ours. mDNS
+	/// is the exception, since it answers for the whole machine, so a
+	/// fleet only has it if its own section sets it.
+	pub fn fleets (&self) -> Vec <(String, Self)> {
+		let ini = match load_server_ini () {
+			None => return vec! [],
+			Some (x) => x,
+		};
+		
+		app_common::fleet_sections (&ini).into_iter ()
+		.map (|(name, section)| {
+			let mut x = self.clone ();
+			x.mdns = false;
+			x.load_ini (&ini, &section, &section, &format! ("{}.services", section));
+			eprintln! ("Fleet `{}` is on port {}, group {}", name, x.common.server_port, x.common.multicast_addr);
+			(name, x)
+		})
+		.collect ()
 	}
 	
-	while let Some (arg) = args.next () {
-		match arg.as_str () {
-			"--bind-addr" => {
-				bind_addrs.push (match args.next () {
-					None => return Err (CliArgError::MissingArgumentValue (arg).into ()),
-					Some (x) => Ipv4Addr::from_str (&x)?,
-				});
-			},
-			"--nickname" => {
-				nickname = match args.next () {
-					None => return Err (CliArgError::MissingArgumentValue (arg).into ()),
-					Some (x) => x
+	// A fleet section holds both network and server settings, so the
+	// section names are separate
+	
+	fn load_ini (&mut self, ini: &Ini, network: &str, server: &str, services: &str) {
+		for x in self.common.load_ini (ini, network) {
+			eprintln! ("{}", x);
+		}
+		
+		if let Some (x) = ini.get (server, "nickname") {
+			self.nickname = x;
+			eprintln! ("Loaded nickname {:?}", self.nickname);
+		}
+		
+		match ini.getbool (server, "mdns") {
+			Ok (Some (x)) => self.mdns = x,
+			Ok (None) => (),
+			Err (e) => eprintln! ("Ignoring bad `mdns` setting: {}", e),
+		}
+		
+		if let Some (x) = ini.get_map_ref ().get (services) {
+			self.services.clear ();
+			for (name, value) in x {
+				let value = match value {
+					None => continue,
+					Some (x) => x,
 				};
-			},
-			_ => return Err (CliArgError::UnrecognizedArgument (arg).into ()),
+				match Service::parse (name, value) {
+					Ok (x) => self.services.push (x),
+					Err (e) => eprintln! ("Ignoring service {:?}: {}", name, e),
+				}
+			}
+			self.services.sort_by (|a, b| a.name.cmp (&b.name));
 		}
 	}
 	
-	let our_mac = get_mac_address ()?.map (|x| x.bytes ());
-	if our_mac.is_none () {
-		println! ("Warning: Can't find our own MAC address. We won't be able to respond to MAC-specific lookaround requests");
+	pub fn common (mut self, x: app_common::Params) -> Self {
+		self.common = x;
+		self
+	}
+	
+	/// Listen on this UDP port, like `port` in `[network]` in server.ini
+	pub fn port (mut self, x: u16) -> Self {
+		self.common.server_port = x;
+		self
+	}
+	
+	/// Join this IPv4 multicast group, like `group` in `[network]`
+	pub fn group (mut self, x: Ipv4Addr) -> Self {
+		self.common.multicast_addr = x;
+		self
+	}
+	
+	/// Join this IPv6 multicast group, like `group_v6` in `[network]`
+	pub fn group_v6 (mut self, x: Ipv6Addr) -> Self {
+		self.common.multicast_addr_v6 = x;
+		self
+	}
+	
+	/// Only answer requests that ask for a sealed answer, and don't
+	/// announce ourselves
+	pub fn encrypt (mut self, x: bool) -> Self {
+		self.common.encrypt = x;
+		self
 	}
 	
-	if bind_addrs.is_empty () {
-		println! ("No bind addresses given, auto-detecting all local IPs");
-		bind_addrs = get_ips ()?;
+	/// Serve on the interface with this IPv4 address. If none are given,
+	/// all interfaces are used.
+	pub fn bind_addr (mut self, x: Ipv4Addr) -> Self {
+		self.bind_addrs.push (x);
+		self
 	}
 	
-	Ok (Params {
-		common,
-		bind_addrs,
-		nickname,
-		our_mac,
+	/// Serve IPv6 on the interface with this index. If none are given,
+	/// all interfaces are used.
+	pub fn v6_iface (mut self, x: u32) -> Self {
+		self.v6_ifaces.push (x);
+		self
+	}
+	
+	pub fn nickname <S: Into <String>> (mut self, x: S) -> Self {
+		self.nickname = x.into ();
+		self
+	}
+	
+	/// Advertise a service, like `[services]` in server.ini
+	pub fn service (mut self, x: Service) -> Self {
+		self.services.push (x);
+		self
+	}
+	
+	/// Sign responses with this key, so clients can pin it
+	pub fn identity (mut self, x: Identity) -> Self {
+		self.identity = Some (Arc::new (x));
+		self
+	}
+	
+	/// Also answer mDNS queries for `<nickname>.local`, so machines without
+	/// LookAround can find us
+	pub fn mdns (mut self, x: bool) -> Self {
+		self.mdns = x;
+		self
+	}
+	
+	pub fn has_mdns (&self) -> bool {
+		self.mdns
+	}
+	
+	/// How often we'll answer each source, and everybody together.
+	/// Requests past that are dropped.
+	pub fn limits (mut self, x: Limits) -> Self {
+		self.limits = x;
+		self
+	}
+	
+	/// Overrides the MAC we report. By default it's auto-detected.
+	pub fn mac (mut self, x: [u8; 6]) -> Self {
+		self.mac = Some (x);
+		self
+	}
+	
+	pub fn build (self) -> Result <Responder, AppError> {
+		let our_mac = match self.mac {
+			Some (x) => Some (x),
+			None => get_mac_address ()?.map (|x| x.bytes ()),
+		};
+		
+		let mut macs = detect_macs ();
+		macs.extend (our_mac);
+		macs.sort ();
+		macs.dedup ();
+		if macs.is_empty () {
+			println! ("Warning: Can't find our own MAC address. We won't be able to respond to MAC-specific lookaround requests");
+		}
+		
+		let mut bind_addrs = self.bind_addrs;
+		let auto_bind_addrs = bind_addrs.is_empty ();
+		if auto_bind_addrs {
+			println! ("No bind addresses given, auto-detecting all local IPs");
+			bind_addrs = get_ips ()?;
+			bind_addrs.sort ();
+		}
+		
+		let mut v6_ifaces = self.v6_ifaces;
+		let auto_v6_ifaces = v6_ifaces.is_empty ();
+		if auto_v6_ifaces {
+			v6_ifaces = get_ipv6_ifaces ().unwrap_or_else (|e| {
+				println! ("Can't detect IPv6 interfaces: {:?}", e);
+				vec! []
+			});
+			v6_ifaces.sort ();
+		}
+		
+		let responder = Responder {
+			common: self.common,
+			bind_addrs,
+			auto_bind_addrs,
+			v6_ifaces,
+			auto_v6_ifaces,
+			nickname: self.nickname,
+			our_mac,
+			macs,
+			services: self.services,
+			identity: self.identity,
+			mdns: self.mdns,
+			limiter: Arc::new (Mutex::new (RateLimiter::new (self.limits))),
+		};
+		
+		// Clients would only get the start of an answer that doesn't fit
+		// in a packet. A sealed v2 one with a MAC is the biggest we send.
+		let key = ResponseKey::default ().public_key ();
+		let biggest = responder.response ([0; 8], Some ([0; 6]), Some (Message::AnswerId ([0; 8])), Some (&key), message::VERSION_2)?;
+		let len = Message::encode (&biggest, message::VERSION_2)?.len ();
+		if len > PACKET_SIZE {
+			return Err (AppError::ResponseTooBig (len));
+		}
+		
+		Ok (responder)
+	}
+}
+
+/// True if server.ini has settings outside of any fleet. A server with
+/// fleets only serves the top-level settings if they're set here or by
+/// flags, so a fleet-only machine doesn't answer on the default group.
+pub fn has_top_level_config () -> bool {
+	load_server_ini ().is_some_and (|ini| {
+		let sections = ini.get_map_ref ();
+		["network", "server", "services"].iter ().any (|x| sections.contains_key (*x))
 	})
 }
 
-async fn serve_interface (
-	params: Params, 
+fn load_server_ini () -> Option <Ini> {
+	let path = find_project_dirs ()?.config_local_dir ().join ("server.ini");
+	let mut ini = Ini::new_cs ();
+	ini.load (&path).ok ()?;
+	Some (ini)
+}
+
+// All the addresses of the interface that `is_it` picks
+
+fn mdns_addrs <F: Fn (&Interface) -> bool> (interfaces: &[Interface], is_it: F) 
+-> (Vec <Ipv4Addr>, Vec <Ipv6Addr>)
+{
+	let mut v4 = vec! [];
+	let mut v6 = vec! [];
+	
+	for addr in interfaces.iter ().filter (|x| is_it (x)).flat_map (|x| &x.addrs) {
+		match addr.addr {
+			IpAddr::V4 (x) => v4.push (x),
+			IpAddr::V6 (x) => v6.push (x),
+		}
+	}
+	
+	(v4, v6)
+}
+
+fn serve_mdns (
+	hostname: String,
 	socket: UdpSocket,
+	group: SocketAddr,
+	v4: Vec <Ipv4Addr>,
+	v6: Vec <Ipv6Addr>,
+) -> ServedInterface
+{
+	let socket = Arc::new (socket);
+	let task_socket = Arc::clone (&socket);
+	
+	let task = tokio::spawn (async move {
+		let socket = task_socket;
+		let mut buf = vec! [0u8; 9000];
+		
+		loop {
+			let (len, remote_addr) = match socket.recv_from (&mut buf).await {
+				Ok (x) => x,
+				Err (e) => {
+					println! ("Stopped answering mDNS: {:?}", e);
+					return;
+				},
+			};
+			
+			let query = match crate::dns::parse_query (&buf [..len]) {
+				Ok (Some (x)) => x,
+				_ => continue,
+			};
+			
+			// Only real mDNS resolvers send from 5353
+			let asker = if remote_addr.port () == MDNS_PORT {
+				mdns::Asker::Mdns
+			}
+			else {
+				mdns::Asker::Legacy
+			};
+			
+			let resp = match mdns::build_response (&query, asker, &hostname, &v4, &v6) {
+				None => continue,
+				Some (x) => x,
+			};
+			
+			let unicast = asker == mdns::Asker::Legacy || query.questions.iter ().all (|q| q.unicast_response);
+			let dest = if unicast {
+				remote_addr
+			}
+			else {
+				group
+			};
+			
+			if let Err (e) = socket.send_to (&resp, dest).await {
+				println! ("Error sending mDNS response to {}: {:?}", dest, e);
+			}
+		}
+	});
+	
+	ServedInterface {
+		socket,
+		group: None,
+		task,
+	}
+}
+
+// Anything we send goes back to the request's source, so we only answer
+// sources on one of our own subnets. Spoofing one of those takes being on
+// the LAN already, and a request from further away is either misrouted or
+// trying to use us as a reflector. If we couldn't list our interfaces,
+// we can't tell, so everybody gets an answer.
+
+fn is_local_source (interfaces: &[Interface], addr: &SocketAddr) -> bool {
+	interfaces.is_empty () ||
+	addr.ip ().is_loopback () ||
+	ip::iface_for (interfaces, addr).is_some ()
+}
+
+// Group memberships are per socket, so two sockets joined on the same
+// interface would both hear every request on it, and both answer. An
+// interface with more than one address only gets a socket for the first.
+// Addresses we can't find on any interface keep their own sockets.
+
+fn one_per_interface (bind_addrs: &[Ipv4Addr], interfaces: &[Interface]) -> Vec <Ipv4Addr> {
+	let mut seen = HashSet::new ();
+	bind_addrs.iter ().copied ()
+	.filter (|addr| {
+		let iface = interfaces.iter ().find (|x| x.addrs.iter ().any (|a| a.addr == IpAddr::V4 (*addr)));
+		match iface {
+			None => true,
+			Some (x) => seen.insert (x.index),
+		}
+	})
+	.collect ()
+}
+
+fn detect_macs () -> Vec <[u8; 6]> {
+	ip::list_interfaces ().into_iter ()
+	.filter (|x| ! x.is_loopback)
+	.filter_map (|x| x.mac)
+	.collect ()
+}
+
+// Each interface gets its own socket, all sharing the server port. That way
+// each socket only hears requests that arrived on its own interface, and
+// anything we multicast goes back out that same interface, instead of
+// whichever one the kernel likes best. Unicast replies aren't pinned to the
+// interface, they go wherever the routing table says. We only answer peers
+// on our own subnets, so that's normally the interface they asked on.
+// `hop_limit` is only for mDNS, since LookAround has always stayed on the
+// OS default.
+
+fn bind_interface_v4 (group: SocketAddrV4, bind_addr: Ipv4Addr, hop_limit: Option <u32>) 
+-> Result <UdpSocket, AppError>
+{
+	let socket = Socket::new (Domain::IPV4, Type::DGRAM, Some (Protocol::UDP))?;
+	socket.set_reuse_address (true)?;
+	socket.bind (&SocketAddrV4::new (Ipv4Addr::UNSPECIFIED, group.port ()).into ())?;
+	socket.join_multicast_v4 (group.ip (), &bind_addr)?;
+	socket.set_multicast_if_v4 (&bind_addr)?;
+	
+	if let Some (x) = hop_limit {
+		socket.set_multicast_ttl_v4 (x)?;
+	}
+	
+	#[cfg(target_os = "linux")]
+	disable_multicast_all (&socket, libc::IPPROTO_IP, libc::IP_MULTICAST_ALL)?;
+	
+	socket.set_nonblocking (true)?;
+	Ok (UdpSocket::from_std (socket.into ())?)
+}
+
+fn bind_interface_v6 (group: SocketAddrV6, hop_limit: Option <u32>) 
+-> Result <UdpSocket, AppError>
+{
+	let iface = group.scope_id ();
+	let socket = Socket::new (Domain::IPV6, Type::DGRAM, Some (Protocol::UDP))?;
+	socket.set_only_v6 (true)?;
+	socket.set_reuse_address (true)?;
+	socket.bind (&SocketAddrV6::new (Ipv6Addr::UNSPECIFIED, group.port (), 0, 0).into ())?;
+	socket.join_multicast_v6 (group.ip (), iface)?;
+	socket.set_multicast_if_v6 (iface)?;
+	if let Some (x) = hop_limit {
+		socket.set_multicast_hops_v6 (x)?;
+	}
+	
+	#[cfg(target_os = "linux")]
+	disable_multicast_all (&socket, libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_ALL)?;
+	
+	socket.set_nonblocking (true)?;
+	Ok (UdpSocket::from_std (socket.into ())?)
+}
+
+// By default Linux delivers a multicast packet to every socket bound to the
+// port, no matter which socket joined the group on which interface. Turning
+// this off is what makes the sockets actually per-interface.
+
+#[cfg(target_os = "linux")]
+fn disable_multicast_all (socket: &Socket, level: libc::c_int, name: libc::c_int) 
+-> Result <(), std::io::Error>
+{
+	use std::os::unix::io::AsRawFd;
+	
+	let value: libc::c_int = 0;
+	let rc = unsafe {
+		libc::setsockopt (
+			socket.as_raw_fd (),
+			level,
+			name,
+			&value as *const libc::c_int as *const libc::c_void,
+			std::mem::size_of_val (&value) as libc::socklen_t,
+		)
+	};
+	
+	if rc != 0 {
+		return Err (std::io::Error::last_os_error ());
+	}
+	
+	Ok (())
+}
+
+async fn serve_interface (
+	params: Responder, 
+	socket: Arc <UdpSocket>,
+	interfaces: Arc <Vec <Interface>>,
 ) 
 -> Result <(), AppError>
 {
-	let mut recent_idem_ids = Vec::with_capacity (32);
+	let mut replies = Replies::default ();
 	
 	loop {
 		println! ("Listening...");
-		let (req_msgs, remote_addr) = match recv_msg_from (&socket).await {
+		let (req, remote_addr) = match recv_packet_from (&socket).await {
 			Ok (x) => x,
 			Err (e) => {
 				println! ("Error while receiving message: {:?}", e);
@@ -110,39 +910,331 @@ async fn serve_interface (
 			},
 		};
 		
-		let req = match req_msgs.into_iter ().next () {
+		// In a private fleet, anybody without the key gets silence
+		if let Some (psk) = &params.common.psk {
+			let idem_id = match req.msgs.first () {
+				Some (Message::Request1 { idem_id, .. }) => *idem_id,
+				_ => continue,
+			};
+			if ! psk.verify (idem_id, &req) {
+				continue;
+			}
+			if replies.is_replay (idem_id, &req, remote_addr, Instant::now (), SystemTime::now ()) {
+				continue;
+			}
+		}
+		
+		// Clients newer than us might ask for things we don't know about
+		let unknown_tags = req.unknown_tags ();
+		if ! unknown_tags.is_empty () {
+			println! ("Skipping unknown message types {:?} from {}", unknown_tags, remote_addr);
+		}
+		
+		// Answer in whatever version they asked in, since that's one they
+		// can read, or in our newest if theirs is newer still. Newer
+		// clients still read v2.
+		let version = req.version.min (message::VERSION_2);
+		let mut msgs = req.msgs.into_iter ();
+		let req = match msgs.next () {
 			Some (x) => x,
 			_ => {
 				println! ("Don't know how to handle this message, ignoring");
 				continue;
 			},
 		};
+		let mut known_answers = vec! [];
+		let mut response_key = None;
+		for msg in msgs {
+			match msg {
+				Message::KnownAnswers (x) => known_answers.extend (x),
+				Message::ResponseKey (x) => response_key = Some (x),
+				_ => (),
+			}
+		}
 		
 		let resp = match req {
 			Message::Request1 {
-				mac: None,
+				mac,
 				idem_id,
 			} => {
-				if recent_idem_ids.contains (&idem_id) {
+				// MAC-specific requests are only for whoever has that MAC
+				if let Some (mac) = &mac {
+					if ! params.macs.contains (mac) {
+						continue;
+					}
+				}
+				
+				// Anybody listening would hear our answer
+				if params.common.encrypt && response_key.is_none () {
+					continue;
+				}
+				
+				let now = Instant::now ();
+				if ! replies.should_answer (idem_id, remote_addr, version, &known_answers, now) {
+					None
+				}
+				else if ! is_local_source (&interfaces, &remote_addr) {
+					println! ("Refusing request from {}, which isn't on any of our subnets", remote_addr);
+					None
+				}
+				else if ! params.allow_response (remote_addr) {
 					None
 				}
 				else {
-					recent_idem_ids.insert (0, idem_id);
-					recent_idem_ids.truncate (30);
-					Some (vec! [
-						Message::Response1 (params.our_mac),
-						Message::Response2 (message::Response2 {
-							idem_id,
-							nickname: params.nickname.clone (),
-						}),
-					])
+					replies.answer (idem_id, remote_addr, now);
+					let marker = (version >= message::VERSION_2).then_some (Message::AnswerId (replies.answer_id));
+					match params.response (idem_id, mac.or (params.our_mac), marker, response_key.as_ref (), version) {
+						Ok (x) => Some (x),
+						Err (e) => {
+							println! ("Can't answer {}: {}", remote_addr, e);
+							None
+						},
+					}
 				}
 			},
 			_ => continue,
 		};
 		
 		if let Some (resp) = resp {
-			socket.send_to (&Message::many_to_vec (&resp)?, remote_addr).await?;
+			let packet = match Message::encode (&resp, version) {
+				Ok (x) => x,
+				Err (e) => {
+					println! ("Can't encode response to {}: {:?}", remote_addr, e);
+					continue;
+				},
+			};
+			if let Err (e) = socket.send_to (&packet, remote_addr).await {
+				println! ("Error sending response to {}: {:?}", remote_addr, e);
+			}
 		}
 	}
 }
+
+#[cfg (test)]
+mod test {
+	use super::*;
+	
+	// The decoder from before v2. It reads the tag stream right after the
+	// magic number, and gives up on the whole packet at any type after
+	// `Response2`.
+	
+	fn decode_v1_baseline (buf: &[u8]) -> Result <Vec <u8>, MessageError> {
+		let mut r = Cursor::new (buf);
+		tlv::Reader::expect (&mut r, &[0x9a, 0x4a, 0x43, 0x81])?;
+		
+		let read_mac_opt = |r: &mut Cursor <&[u8]>| -> Result <(), MessageError> {
+			if tlv::Reader::u8 (r)? == 1 {
+				r.read_exact (&mut [0u8; 6])?;
+			}
+			Ok (())
+		};
+		
+		let mut tags = vec! [];
+		while r.position () < u64::try_from (buf.len ())? {
+			let t = tlv::Reader::u8 (&mut r)?;
+			match t {
+				1 => {
+					r.read_exact (&mut [0u8; 8])?;
+					read_mac_opt (&mut r)?;
+				},
+				2 => read_mac_opt (&mut r)?,
+				3 => {
+					let len = tlv::Reader::<_>::length (&mut r)?;
+					r.read_exact (&mut vec! [0u8; usize::try_from (len)?])?;
+				},
+				_ => return Err (MessageError::UnknownType),
+			}
+			tags.push (t);
+		}
+		Ok (tags)
+	}
+	
+	#[test]
+	fn test_v1_response () -> Result <(), AppError> {
+		let responder = Responder::builder ()
+		.nickname ("laptop")
+		.identity (Identity::from_seed ([1; 32]))
+		.service (Service::parse ("ssh", "tcp/22")?)
+		.build ()?;
+		let mac = Some ([1, 2, 3, 4, 5, 6]);
+		
+		// Old clients can still read everything we send them
+		let resp = responder.response ([1; 8], mac, None, None, message::VERSION_1)?;
+		let encoded = Message::encode (&resp, message::VERSION_1)?;
+		assert_eq! (decode_v1_baseline (&encoded)?, vec! [2, 3]);
+		
+		// Newer ones get the rest
+		let resp = responder.response ([1; 8], mac, None, None, message::VERSION_2)?;
+		assert! (resp.iter ().any (|x| matches! (x, Message::Services (_))));
+		assert! (resp.iter ().any (|x| matches! (x, Message::Signature { .. })));
+		
+		Ok (())
+	}
+	
+	#[test]
+	fn test_one_per_interface () {
+		let iface = |index, addrs: &[&str]| Interface {
+			name: format! ("eth{}", index),
+			index,
+			mac: None,
+			addrs: addrs.iter ().map (|x| ip::InterfaceAddr {
+				addr: x.parse ().unwrap (),
+				prefix_len: 24,
+			}).collect (),
+			is_up: true,
+			is_multicast: true,
+			is_loopback: false,
+		};
+		let interfaces = [
+			iface (2, &["192.168.1.5", "192.168.1.6", "fe80::1"]),
+			iface (3, &["10.0.0.7"]),
+		];
+		let ip = |x: &str| x.parse::<Ipv4Addr> ().unwrap ();
+		
+		for (input, expected) in [
+			(&["192.168.1.5", "10.0.0.7"][..], &["192.168.1.5", "10.0.0.7"][..]),
+			// Two addresses on eth2 would answer everything twice
+			(&["192.168.1.5", "192.168.1.6", "10.0.0.7"], &["192.168.1.5", "10.0.0.7"]),
+			(&["192.168.1.6", "192.168.1.5"], &["192.168.1.6"]),
+			// Not on any interface we know of
+			(&["172.16.0.1", "172.16.0.2"], &["172.16.0.1", "172.16.0.2"]),
+		] {
+			let input: Vec <_> = input.iter ().map (|x| ip (x)).collect ();
+			let expected: Vec <_> = expected.iter ().map (|x| ip (x)).collect ();
+			assert_eq! (one_per_interface (&input, &interfaces), expected, "{:?}", input);
+		}
+	}
+	
+	#[test]
+	fn test_replies () {
+		let a: SocketAddr = "192.168.1.101:40000".parse ().unwrap ();
+		let b: SocketAddr = "192.168.1.102:40000".parse ().unwrap ();
+		let start = Instant::now ();
+		let mut replies = Replies::default ();
+		let ours = [replies.answer_id];
+		let v1 = message::VERSION_1;
+		let v2 = message::VERSION_2;
+		
+		// Each row is one copy of the same request
+		for (ms, source, version, known, expected) in [
+			// `a` sends both versions, and we answer the first
+			(0, a, v2, &[][..], true),
+			(0, a, v1, &[], false),
+			// Inside the holdoff, neither gets answered again
+			(100, a, v2, &[], false),
+			(100, a, v1, &[], false),
+			// After it, the v2 copy does, in case our answer was lost
+			(300, a, v2, &[], true),
+			(300, a, v1, &[], false),
+			// Once `a` says it heard us, neither version gets answered
+			(600, a, v2, &ours, false),
+			(600, a, v1, &[], false),
+			(1000, a, v1, &[], false),
+			(1000, a, v2, &[], false),
+			// `b` only speaks v1, and gets one answer, like it always did
+			(1100, b, v1, &[], true),
+			(1400, b, v1, &[], false),
+		] {
+			let now = start + Duration::from_millis (ms);
+			let actual = replies.should_answer ([1; 8], source, version, known, now);
+			assert_eq! (actual, expected, "{} {} v{}", ms, source, version);
+			if actual {
+				replies.answer ([1; 8], source, now);
+			}
+		}
+	}	
+	#[test]
+	fn test_response_size () {
+		for (count, fits) in [
+			(0, true),
+			(40, true),
+			// About 17 bytes each, so these don't fit with everything else
+			(60, false),
+		] {
+			let mut builder = Responder::builder ().nickname ("laptop");
+			for i in 0..count {
+				builder = builder.service (Service {
+					name: format! ("service-{:02}", i),
+					protocol: message::Protocol::Tcp,
+					port: 1000 + i,
+				});
+			}
+			
+			match builder.build () {
+				Ok (_) => assert! (fits, "{}", count),
+				Err (AppError::ResponseTooBig (len)) => {
+					assert! (! fits, "{}", count);
+					assert! (len > PACKET_SIZE);
+				},
+				Err (e) => panic! ("{}", e),
+			}
+		}
+	}
+	
+	#[test]
+	fn test_replay () -> Result <(), AppError> {
+		// Off the usual port and group, so real servers on the LAN stay
+		// out of it
+		let psk = Psk::new (b"correct horse battery staple");
+		let common = app_common::Params {
+			server_port: 19041,
+			multicast_addr: Ipv4Addr::new (225, 100, 99, 96),
+			multicast_addr_v6: Ipv6Addr::new (0xff02, 0, 0, 0, 0, 0, 0xe164, 0x6360),
+			psk: Some (psk.clone ()),
+			encrypt: false,
+		};
+		let group = SocketAddr::from ((common.multicast_addr, common.server_port));
+		
+		let responder = Responder::builder ()
+		.common (common)
+		.nickname ("replay-test")
+		.build ()?;
+		
+		let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()> ();
+		let server = std::thread::spawn (move || {
+			let rt = tokio::runtime::Builder::new_current_thread ().enable_all ().build ()?;
+			rt.block_on (responder.run_until (async {
+				stop_rx.await.ok ();
+			}))
+		});
+		std::thread::sleep (Duration::from_millis (100));
+		
+		// Sends `request` from a new socket, and says if anybody answered
+		let ask = |request: Vec <u8>| async move {
+			let socket = UdpSocket::bind ((Ipv4Addr::UNSPECIFIED, 0)).await?;
+			socket.send_to (&request, group).await?;
+			Ok::<_, AppError> (timeout (Duration::from_millis (500), recv_packet_from (&socket)).await.is_ok ())
+		};
+		
+		let rt = tokio::runtime::Builder::new_current_thread ().enable_all ().build ()?;
+		let result = rt.block_on (async {
+			let request = |idem_id, sent: SystemTime| -> Result <Vec <u8>, AppError> {
+				let mut msgs = vec! [
+					Message::Request1 {
+						idem_id,
+						mac: None,
+					},
+					psk::timestamp (sent),
+				];
+				msgs.push (psk.tag (idem_id, &msgs, message::VERSION_2)?);
+				Ok (Message::encode (&msgs, message::VERSION_2)?)
+			};
+			
+			// The first sender gets an answer, and somebody who
+			// overheard the same packet and sent it again doesn't
+			let fresh = request ([1; 8], SystemTime::now ())?;
+			assert! (ask (fresh.clone ()).await?);
+			assert! (! ask (fresh).await?);
+			
+			// Neither does an old packet, even from a new source
+			let stale = request ([2; 8], SystemTime::now () - psk::MAX_SKEW * 2)?;
+			assert! (! ask (stale).await?);
+			
+			Ok::<_, AppError> (())
+		});
+		
+		stop_tx.send (()).ok ();
+		server.join ().unwrap ()?;
+		result
+	}
+}
@@ -0,0 +1,463 @@
+// Sending a file to a peer by nickname, instead of lining up `nc` on both
+// ends. The receiver advertises a `transfer` service, and the sender finds
+// it with an ordinary discovery.
+//
+// On the wire, over TCP, with every number big-endian:
+//
+// Sender:   MAGIC, name length (u16), name, size (u64), SHA-256 (32 bytes)
+// Receiver: status (u8), how many bytes it already has (u64)
+// Sender:   the rest of the file
+// Receiver: status (u8), once it's checked the hash
+//
+// The receiver keeps partial files under a name that includes the hash, so
+// if the connection drops, the next try with the same file picks up where
+// the last one left off.
+
+use std::{
+	io::SeekFrom,
+	path::Path,
+};
+
+use sha2::{
+	Digest,
+	Sha256,
+};
+use tokio::{
+	fs::{
+		self,
+		File,
+		OpenOptions,
+	},
+	io::{
+		AsyncRead,
+		AsyncReadExt,
+		AsyncSeekExt,
+		AsyncWrite,
+		AsyncWriteExt,
+	},
+	net::TcpStream,
+};
+
+use crate::prelude::*;
+
+/// Receivers advertise themselves under this service name
+pub const SERVICE_NAME: &str = "transfer";
+
+const MAGIC: &[u8; 8] = b"LKARSEND";
+
+const STATUS_OK: u8 = 0;
+const STATUS_BAD_NAME: u8 = 1;
+const STATUS_BAD_CHECKSUM: u8 = 2;
+const STATUS_TOO_BIG: u8 = 3;
+const STATUS_NO_SPACE: u8 = 4;
+const STATUS_BUSY: u8 = 5;
+
+// Most filesystems stop at 255 bytes, and the `.part` file's name is this
+// much longer than the real one
+
+const PART_NAME_OVERHEAD: usize = ".".len () + 16 + ".".len () + ".part".len ();
+
+#[derive (Debug, thiserror::Error)]
+pub enum TransferError {
+	#[error ("Can't send or save a file named {0:?}")]
+	BadFilename (String),
+	#[error ("Not a LookAround file transfer")]
+	BadMagic,
+	#[error ("Receiver is already getting this file from somebody else, try again later")]
+	Busy,
+	#[error ("File was corrupted in transit, try sending it again")]
+	ChecksumMismatch,
+	#[error (transparent)]
+	Io (#[from] std::io::Error),
+	#[error ("Not enough free space for the file")]
+	NoSpace,
+	#[error ("Receiver refused the file with status {0}")]
+	Refused (u8),
+	#[error ("File is bigger than the receiver allows")]
+	TooBig,
+}
+
+/// Everything the receiver learns before the file itself
+#[derive (Clone, Debug, PartialEq)]
+pub struct Header {
+	pub name: String,
+	pub size: u64,
+	pub checksum: [u8; 32],
+}
+
+impl Header {
+	/// Hashes the whole file, so this takes a while for big ones
+	pub async fn for_file (path: &Path) -> Result <Self, TransferError> {
+		let name = path.file_name ().and_then (|x| x.to_str ())
+		.filter (|x| is_safe_filename (x))
+		.ok_or_else (|| TransferError::BadFilename (path.display ().to_string ()))?
+		.to_string ();
+		let size = fs::metadata (path).await?.len ();
+		let checksum = hash_file (File::open (path).await?, size).await?;
+		
+		Ok (Self {
+			name,
+			size,
+			checksum,
+		})
+	}
+	
+	fn to_vec (&self) -> Vec <u8> {
+		let mut buf = MAGIC.to_vec ();
+		buf.extend_from_slice (&(self.name.len () as u16).to_be_bytes ());
+		buf.extend_from_slice (self.name.as_bytes ());
+		buf.extend_from_slice (&self.size.to_be_bytes ());
+		buf.extend_from_slice (&self.checksum);
+		buf
+	}
+	
+	async fn read <R: AsyncRead + Unpin> (r: &mut R) -> Result <Self, TransferError> {
+		let mut magic = [0u8; 8];
+		r.read_exact (&mut magic).await?;
+		if &magic != MAGIC {
+			return Err (TransferError::BadMagic);
+		}
+		
+		let mut name = vec! [0u8; r.read_u16 ().await? as usize];
+		r.read_exact (&mut name).await?;
+		let name = String::from_utf8 (name)
+		.map_err (|e| TransferError::BadFilename (String::from_utf8_lossy (e.as_bytes ()).into_owned ()))?;
+		
+		let size = r.read_u64 ().await?;
+		let mut checksum = [0u8; 32];
+		r.read_exact (&mut checksum).await?;
+		
+		Ok (Self {
+			name,
+			size,
+			checksum,
+		})
+	}
+}
+
+/// Sends the file at `path`, described by `header`, to the receiver at
+/// `addr`. Returns how many bytes were skipped because the receiver
+/// already had them from an earlier try.
+pub async fn send_file (addr: SocketAddr, path: &Path, header: &Header) -> Result <u64, TransferError> {
+	let mut stream = TcpStream::connect (addr).await?;
+	stream.write_all (&header.to_vec ()).await?;
+	
+	check_status (stream.read_u8 ().await?, &header.name)?;
+	let offset = stream.read_u64 ().await?.min (header.size);
+	
+	let mut file = File::open (path).await?;
+	file.seek (SeekFrom::Start (offset)).await?;
+	tokio::io::copy (&mut file.take (header.size - offset), &mut stream).await?;
+	stream.flush ().await?;
+	
+	check_status (stream.read_u8 ().await?, &header.name)?;
+	Ok (offset)
+}
+
+fn check_status (status: u8, name: &str) -> Result <(), TransferError> {
+	match status {
+		STATUS_OK => Ok (()),
+		STATUS_BAD_NAME => Err (TransferError::BadFilename (name.to_string ())),
+		STATUS_BAD_CHECKSUM => Err (TransferError::ChecksumMismatch),
+		STATUS_TOO_BIG => Err (TransferError::TooBig),
+		STATUS_NO_SPACE => Err (TransferError::NoSpace),
+		STATUS_BUSY => Err (TransferError::Busy),
+		x => Err (TransferError::Refused (x)),
+	}
+}
+
+/// Receives one file from `stream` into `dir`, if it's no bigger than
+/// `max_size` and there's room for it. Returns where it was saved, which is
+/// never over an existing file.
+pub async fn receive_file <S: AsyncRead + AsyncWrite + Unpin> (stream: &mut S, dir: &Path, max_size: u64)
+-> Result <PathBuf, TransferError>
+{
+	let header = Header::read (stream).await?;
+	if ! is_safe_filename (&header.name) {
+		stream.write_u8 (STATUS_BAD_NAME).await?;
+		return Err (TransferError::BadFilename (header.name));
+	}
+	if header.size > max_size {
+		stream.write_u8 (STATUS_TOO_BIG).await?;
+		return Err (TransferError::TooBig);
+	}
+	
+	let part_path = part_path (dir, &header);
+	let have = fs::metadata (&part_path).await.map (|x| x.len ()).unwrap_or (0);
+	if free_space (dir).is_some_and (|x| x < header.size.saturating_sub (have)) {
+		stream.write_u8 (STATUS_NO_SPACE).await?;
+		return Err (TransferError::NoSpace);
+	}
+	
+	// Two senders of the same file at once would write over each other, so
+	// the second is turned away until the first is done. The lock goes
+	// with the file handle, so it's let go even if we crash.
+	let part = OpenOptions::new ().create (true).truncate (false).read (true).write (true).open (&part_path).await?;
+	let part = part.into_std ().await;
+	match part.try_lock () {
+		Ok (()) => (),
+		Err (std::fs::TryLockError::WouldBlock) => {
+			stream.write_u8 (STATUS_BUSY).await?;
+			return Err (TransferError::Busy);
+		},
+		Err (std::fs::TryLockError::Error (e)) => return Err (e.into ()),
+	}
+	let mut part = File::from_std (part);
+	
+	let mut offset = part.metadata ().await?.len ();
+	if offset > header.size {
+		part.set_len (0).await?;
+		offset = 0;
+	}
+	part.seek (SeekFrom::Start (offset)).await?;
+	
+	stream.write_u8 (STATUS_OK).await?;
+	stream.write_u64 (offset).await?;
+	
+	// Keep whatever arrived even if the connection drops, that's what
+	// makes resuming work
+	let copied = tokio::io::copy (&mut (&mut *stream).take (header.size - offset), &mut part).await;
+	part.flush ().await?;
+	copied?;
+	
+	if part.metadata ().await?.len () != header.size {
+		return Err (std::io::Error::from (std::io::ErrorKind::UnexpectedEof).into ());
+	}
+	
+	// Through the same handle, since on some platforms the lock keeps
+	// any other one from reading
+	part.seek (SeekFrom::Start (0)).await?;
+	if hash_file (&mut part, header.size).await? != header.checksum {
+		fs::remove_file (&part_path).await?;
+		stream.write_u8 (STATUS_BAD_CHECKSUM).await?;
+		return Err (TransferError::ChecksumMismatch);
+	}
+	
+	let path = save_as_free_name (&part_path, dir, &header.name).await?;
+	
+	// Only now, so nobody can pick up the part file before it's saved
+	drop (part);
+	
+	stream.write_u8 (STATUS_OK).await?;
+	Ok (path)
+}
+
+// Hidden, and named after the hash, so a different file with the same
+// name never resumes from this one
+
+fn part_path (dir: &Path, header: &Header) -> PathBuf {
+	dir.join (format! (".{}.{}.part", header.name, identity::to_hex (&header.checksum [..8])))
+}
+
+// Only a bare file name, so a sender can't write outside the directory,
+// and short enough that the `.part` file's name fits too
+
+fn is_safe_filename (name: &str) -> bool {
+	! name.is_empty () &&
+	name.len () <= 255 - PART_NAME_OVERHEAD &&
+	name != "." &&
+	name != ".." &&
+	! name.contains (['/', '\\', '\0'])
+}
+
+// Moves the finished file to `report.pdf`, or `report (1).pdf` if that's
+// taken, and so on. Linking fails if the name is taken, so unlike checking
+// first and then renaming, a file that shows up in between is never
+// overwritten.
+
+async fn save_as_free_name (part_path: &Path, dir: &Path, name: &str) -> Result <PathBuf, std::io::Error> {
+	for i in 0.. {
+		let path = numbered_path (dir, name, i);
+		match fs::hard_link (part_path, &path).await {
+			Ok (()) => {
+				fs::remove_file (part_path).await?;
+				return Ok (path);
+			},
+			Err (e) if e.kind () == std::io::ErrorKind::AlreadyExists => (),
+			Err (e) => return Err (e),
+		}
+	}
+	
+	Err (std::io::ErrorKind::AlreadyExists.into ())
+}
+
+// `report.pdf` for 0, `report (1).pdf` for 1, and so on
+
+fn numbered_path (dir: &Path, name: &str, i: u32) -> PathBuf {
+	if i == 0 {
+		return dir.join (name);
+	}
+	
+	let (stem, ext) = match name.rsplit_once ('.') {
+		Some ((stem, ext)) if ! stem.is_empty () => (stem, format! (".{}", ext)),
+		_ => (name, String::new ()),
+	};
+	dir.join (format! ("{} ({}){}", stem, i, ext))
+}
+
+// How many more bytes we can write in `dir`, if we can tell
+
+#[cfg(target_os = "linux")]
+fn free_space (dir: &Path) -> Option <u64> {
+	use std::os::unix::ffi::OsStrExt;
+	
+	let path = std::ffi::CString::new (dir.as_os_str ().as_bytes ()).ok ()?;
+	let mut stat: libc::statvfs = unsafe { std::mem::zeroed () };
+	if unsafe { libc::statvfs (path.as_ptr (), &mut stat) } != 0 {
+		return None;
+	}
+	// Both are narrower than u64 on some platforms
+	(stat.f_bavail as u64).checked_mul (stat.f_frsize as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn free_space (_dir: &Path) -> Option <u64> {
+	None
+}
+
+async fn hash_file <R: AsyncRead + Unpin> (file: R, len: u64) -> Result <[u8; 32], std::io::Error> {
+	let mut file = file.take (len);
+	let mut hasher = Sha256::new ();
+	let mut buf = vec! [0u8; 64 * 1024];
+	
+	loop {
+		let n = file.read (&mut buf).await?;
+		if n == 0 {
+			break;
+		}
+		hasher.update (&buf [..n]);
+	}
+	
+	Ok (hasher.finalize ().into ())
+}
+
+#[cfg (test)]
+mod test {
+	use tokio::net::TcpListener;
+	
+	use super::*;
+	
+	#[test]
+	fn test_is_safe_filename () {
+		for (input, expected) in [
+			("report.pdf", true),
+			(".bashrc", true),
+			("my report.pdf", true),
+			("", false),
+			(".", false),
+			("..", false),
+			("../report.pdf", false),
+			("/etc/passwd", false),
+			("..\\report.pdf", false),
+		] {
+			assert_eq! (is_safe_filename (input), expected, "{:?}", input);
+		}
+		
+		// The longest name we take still fits as a `.part` file
+		let longest = "a".repeat (255 - PART_NAME_OVERHEAD);
+		assert! (is_safe_filename (&longest));
+		assert! (! is_safe_filename (&format! ("{}a", longest)));
+		let header = Header {
+			name: longest,
+			size: 0,
+			checksum: [0; 32],
+		};
+		assert_eq! (part_path (Path::new ("."), &header).file_name ().unwrap ().len (), 255);
+	}
+	
+	#[tokio::test]
+	async fn test_header () -> Result <(), TransferError> {
+		let header = Header {
+			name: "report.pdf".to_string (),
+			size: 1234,
+			checksum: [7; 32],
+		};
+		assert_eq! (Header::read (&mut &header.to_vec () [..]).await?, header);
+		
+		let mut bad = header.to_vec ();
+		bad [0] = b'X';
+		assert! (matches! (Header::read (&mut &bad [..]).await, Err (TransferError::BadMagic)));
+		
+		Ok (())
+	}
+	
+	#[tokio::test]
+	async fn test_transfer () -> Result <(), TransferError> {
+		let root = env::temp_dir ().join (format! ("lookaround-test-{}", rand::thread_rng ().next_u64 ()));
+		let (src_dir, dst_dir) = (root.join ("src"), root.join ("dst"));
+		fs::create_dir_all (&src_dir).await?;
+		fs::create_dir_all (&dst_dir).await?;
+		
+		let data: Vec <u8> = (0..200_000u32).map (|x| (x % 251) as u8).collect ();
+		let src = src_dir.join ("report.pdf");
+		fs::write (&src, &data).await?;
+		let header = Header::for_file (&src).await?;
+		assert_eq! (header.size, 200_000);
+		
+		let listener = TcpListener::bind ((Ipv4Addr::LOCALHOST, 0)).await?;
+		let addr = listener.local_addr ()?;
+		let receiver_dir = dst_dir.clone ();
+		tokio::spawn (async move {
+			loop {
+				let (mut stream, _) = listener.accept ().await.unwrap ();
+				let receiver_dir = receiver_dir.clone ();
+				tokio::spawn (async move {
+					receive_file (&mut stream, &receiver_dir, 300_000).await.ok ();
+				});
+			}
+		});
+		
+		// Half of it made it last time, so only the rest is sent
+		fs::write (part_path (&dst_dir, &header), &data [..100_000]).await?;
+		assert_eq! (send_file (addr, &src, &header).await?, 100_000);
+		assert_eq! (fs::read (dst_dir.join ("report.pdf")).await?, data);
+		assert! (! part_path (&dst_dir, &header).exists ());
+		
+		// Never overwrites
+		assert_eq! (send_file (addr, &src, &header).await?, 0);
+		assert_eq! (fs::read (dst_dir.join ("report (1).pdf")).await?, data);
+		
+		// Taken names are skipped, never overwritten
+		fs::write (dst_dir.join ("report (2).pdf"), b"mine").await?;
+		fs::write (part_path (&dst_dir, &header), &data).await?;
+		let path = save_as_free_name (&part_path (&dst_dir, &header), &dst_dir, "report.pdf").await?;
+		assert_eq! (path, dst_dir.join ("report (3).pdf"));
+		assert_eq! (fs::read (dst_dir.join ("report (2).pdf")).await?, b"mine");
+		
+		// While one sender is partway through, a second one with the same
+		// file is turned away
+		let mut first = TcpStream::connect (addr).await?;
+		first.write_all (&header.to_vec ()).await?;
+		assert_eq! (first.read_u8 ().await?, STATUS_OK);
+		assert_eq! (first.read_u64 ().await?, 0);
+		assert! (matches! (send_file (addr, &src, &header).await, Err (TransferError::Busy)));
+		first.write_all (&data).await?;
+		assert_eq! (first.read_u8 ().await?, STATUS_OK);
+		assert_eq! (fs::read (dst_dir.join ("report (4).pdf")).await?, data);
+		
+		// Too big for the limit, or for the disk
+		let mut big = header.clone ();
+		big.size = 300_001;
+		assert! (matches! (send_file (addr, &src, &big).await, Err (TransferError::TooBig)));
+		if let Some (free) = free_space (&dst_dir) {
+			assert! (free >= header.size);
+			let listener = TcpListener::bind ((Ipv4Addr::LOCALHOST, 0)).await?;
+			let addr = listener.local_addr ()?;
+			let receiver_dir = dst_dir.clone ();
+			tokio::spawn (async move {
+				let (mut stream, _) = listener.accept ().await.unwrap ();
+				receive_file (&mut stream, &receiver_dir, u64::MAX).await.ok ();
+			});
+			big.size = u64::MAX;
+			assert! (matches! (send_file (addr, &src, &big).await, Err (TransferError::NoSpace)));
+		}
+		
+		// A partial file that doesn't match the hash is thrown out
+		fs::write (part_path (&dst_dir, &header), &[0u8; 100_000]).await?;
+		assert! (matches! (send_file (addr, &src, &header).await, Err (TransferError::ChecksumMismatch)));
+		assert! (! part_path (&dst_dir, &header).exists ());
+		
+		fs::remove_dir_all (&root).await?;
+		Ok (())
+	}
+}
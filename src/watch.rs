@@ -0,0 +1,278 @@
+// Keeps track of which peers are around over time, and reports when they
+// come, go, or change IPs
+
+use std::collections::BTreeMap;
+
+use crate::{
+	client::{
+		Outstanding,
+		listen_for_responses,
+		parse_peer,
+		send_requests,
+	},
+	dedup::IdemCache,
+	prelude::*,
+};
+
+#[derive (Clone, Debug, PartialEq)]
+pub enum PeerEvent {
+	Joined (Peer),
+	Left (Peer),
+	/// The peer's preferred address changed, e.g. it moved from WiFi to
+	/// Ethernet, or DHCP gave it a new IP
+	AddressChanged {
+		peer: Peer,
+		old: SocketAddr,
+	},
+}
+
+/// Peers we've heard from recently. Feed it responses with `observe`,
+/// then call `changes` to expire stale addresses and find out what's new.
+pub struct PeerTable {
+	max_age: Duration,
+	peers: BTreeMap <String, TableEntry>,
+}
+
+#[derive (Default)]
+struct TableEntry {
+	// Every address we've heard this peer on, and when
+	addrs: HashMap <SocketAddr, (Peer, Instant)>,
+	
+	// What we last told the caller about this peer
+	reported: Option <Peer>,
+}
+
+impl PeerTable {
+	/// Addresses we haven't heard from in `max_age` are forgotten
+	pub fn new (max_age: Duration) -> Self {
+		Self {
+			max_age,
+			peers: Default::default (),
+		}
+	}
+	
+	pub fn observe (&mut self, peer: Peer, now: Instant) {
+		self.peers.entry (peer_key (&peer)).or_default ()
+		.addrs.insert (peer.addr, (peer, now));
+	}
+	
+	/// Drops all of a peer's addresses at once, e.g. when it says goodbye
+	pub fn forget (&mut self, peer: &Peer) {
+		if let Some (entry) = self.peers.get_mut (&peer_key (peer)) {
+			entry.addrs.clear ();
+		}
+	}
+	
+	pub fn changes (&mut self, now: Instant) -> Vec <PeerEvent> {
+		let mut events = vec! [];
+		let max_age = self.max_age;
+		
+		for entry in self.peers.values_mut () {
+			entry.addrs.retain (|_, (_, t)| now.saturating_duration_since (*t) <= max_age);
+			
+			// Prefer IPv4, since that's what most people want to see
+			let best = entry.addrs.values ()
+			.map (|(peer, _)| peer)
+			.min_by_key (|peer| (peer.addr.is_ipv6 (), peer.addr))
+			.cloned ();
+			
+			match (entry.reported.take (), &best) {
+				(None, None) => (),
+				(None, Some (new)) => events.push (PeerEvent::Joined (new.clone ())),
+				(Some (old), None) => events.push (PeerEvent::Left (old)),
+				(Some (old), Some (new)) => if old.addr != new.addr {
+					events.push (PeerEvent::AddressChanged {
+						peer: new.clone (),
+						old: old.addr,
+					});
+				},
+			}
+			
+			entry.reported = best;
+		}
+		
+		self.peers.retain (|_, entry| entry.reported.is_some ());
+		
+		events
+	}
+	
+	/// Each peer at its preferred address
+	pub fn peers (&self) -> impl Iterator <Item = &Peer> {
+		self.peers.values ().filter_map (|x| x.reported.as_ref ())
+	}
+}
+
+// Peers are the same peer if they have the same MAC, or failing that, the
+// same nickname. So a peer that changes IPs is still the same peer.
+
+fn peer_key (peer: &Peer) -> String {
+	match (&peer.mac, &peer.nickname) {
+		(Some (mac), _) => MacAddress::new (*mac).to_string (),
+		(None, Some (nick)) => format! ("nick {}", nick),
+		(None, None) => format! ("addr {}", peer.addr),
+	}
+}
+
+/// Queries every `interval` forever, and reports peers joining, leaving,
+/// and changing IPs. A peer leaves once it misses two queries in a row.
+pub async fn watch (options: DiscoverOptions, interval: Duration)
+-> Result <impl Stream <Item = PeerEvent>, AppError>
+{
+	let sockets = options.make_sockets ().await?;
+	let mut known_peers = options.load_known_peers ();
+	let (tx, rx) = mpsc::unbounded_channel ();
+	
+	tokio::spawn (async move {
+		let mut table = PeerTable::new (interval * 2 + options.timeout);
+		
+		loop {
+			let outstanding = Outstanding::new (Message::new_request1 (), &options);
+			tokio::spawn (send_requests (sockets.clone (), options.common.clone (), Arc::clone (&outstanding)));
+			
+			let mut round = vec! [];
+			let listen = listen_for_responses (&sockets, &outstanding, &options, &mut known_peers, |peer| {
+				round.push (peer);
+				true
+			});
+			timeout (options.timeout, listen).await.ok ();
+			
+			let now = Instant::now ();
+			for peer in round {
+				table.observe (peer, now);
+			}
+			
+			for event in table.changes (now) {
+				if tx.send (event).is_err () {
+					// Nobody's listening anymore
+					return;
+				}
+			}
+			
+			sleep (interval.saturating_sub (options.timeout)).await;
+		}
+	});
+	
+	Ok (UnboundedReceiverStream::new (rx))
+}
+
+/// Reports peers as they announce themselves starting up, and say goodbye
+/// shutting down, without sending any queries. Peers that vanish without
+/// saying goodbye, e.g. by losing power, are never reported as leaving.
+pub async fn listen (options: DiscoverOptions)
+-> Result <impl Stream <Item = PeerEvent>, AppError>
+{
+	let sockets = options.make_group_sockets ()?;
+	let mut known_peers = options.load_known_peers ();
+	let (tx, rx) = mpsc::unbounded_channel ();
+	
+	tokio::spawn (async move {
+		let mut table = PeerTable::new (Duration::MAX);
+		let mut recent_idem_ids = IdemCache::default ();
+		
+		loop {
+			let (packet, remote_addr) = match sockets.recv_packet_from ().await {
+				Err (_) => continue,
+				Ok (x) => x,
+			};
+			
+			// Queries and responses meant for other clients show up here
+			// too, since we share the server port
+			let (idem_id, is_goodbye) = match packet.msgs.first () {
+				Some (Message::Announce { idem_id }) => (*idem_id, false),
+				Some (Message::Goodbye { idem_id }) => (*idem_id, true),
+				_ => continue,
+			};
+			
+			// Servers send each announcement a few times, in every version
+			let now = Instant::now ();
+			if recent_idem_ids.since (idem_id, remote_addr, now).is_some () {
+				continue;
+			}
+			
+			let peer = match parse_peer (packet, remote_addr, idem_id, None, &options, &mut known_peers) {
+				None => continue,
+				Some (x) => x,
+			};
+			
+			// Only once it checks out, so a forged copy can't get the
+			// real one ignored
+			recent_idem_ids.insert (idem_id, remote_addr, now);
+			
+			if is_goodbye {
+				table.forget (&peer);
+			}
+			else {
+				table.observe (peer, now);
+			}
+			
+			for event in table.changes (now) {
+				if tx.send (event).is_err () {
+					return;
+				}
+			}
+		}
+	});
+	
+	Ok (UnboundedReceiverStream::new (rx))
+}
+
+#[cfg (test)]
+mod test {
+	use super::*;
+	
+	fn peer (mac: u8, addr: &str) -> Peer {
+		Peer {
+			addr: addr.parse ().unwrap (),
+			mac: Some ([mac; 6]),
+			nickname: None,
+			nickname_source: None,
+			services: vec! [],
+			public_key: None,
+			rtt: None,
+		}
+	}
+	
+	#[test]
+	fn test_peer_table () {
+		let start = Instant::now ();
+		let sec = |x| start + Duration::from_secs (x);
+		let mut table = PeerTable::new (Duration::from_secs (10));
+		
+		// Nothing yet
+		assert_eq! (table.changes (sec (0)), vec! []);
+		
+		// IPv6 and IPv4 in the same round only make one join, with IPv4
+		table.observe (peer (1, "[fe80::1%2]:9040"), sec (0));
+		table.observe (peer (1, "192.168.1.101:9040"), sec (0));
+		table.observe (peer (2, "192.168.1.102:9040"), sec (0));
+		assert_eq! (table.changes (sec (0)), vec! [
+			PeerEvent::Joined (peer (1, "192.168.1.101:9040")),
+			PeerEvent::Joined (peer (2, "192.168.1.102:9040")),
+		]);
+		
+		// Hearing the same thing again changes nothing
+		table.observe (peer (1, "192.168.1.101:9040"), sec (5));
+		table.observe (peer (1, "[fe80::1%2]:9040"), sec (5));
+		assert_eq! (table.changes (sec (5)), vec! []);
+		
+		// Peer 1 gets a new IP, and the old one expires.
+		// Peer 2 hasn't been heard from in too long.
+		table.observe (peer (1, "192.168.1.150:9040"), sec (12));
+		table.observe (peer (1, "[fe80::1%2]:9040"), sec (12));
+		assert_eq! (table.changes (sec (16)), vec! [
+			PeerEvent::AddressChanged {
+				peer: peer (1, "192.168.1.150:9040"),
+				old: "192.168.1.101:9040".parse ().unwrap (),
+			},
+			PeerEvent::Left (peer (2, "192.168.1.102:9040")),
+		]);
+		assert_eq! (table.peers ().count (), 1);
+		
+		// Saying goodbye leaves immediately
+		table.forget (&peer (1, "192.168.1.150:9040"));
+		assert_eq! (table.changes (sec (17)), vec! [
+			PeerEvent::Left (peer (1, "192.168.1.150:9040")),
+		]);
+		assert_eq! (table.peers ().count (), 0);
+	}
+}